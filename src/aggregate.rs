@@ -2,6 +2,8 @@ use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::Debug;
 use uuid::Uuid;
 
+use crate::IdempotencyKey;
+
 pub trait Aggregate: Debug + Default + Serialize + DeserializeOwned + Sync + Send {
     fn aggregate_type() -> &'static str;
 }
@@ -10,6 +12,12 @@ pub struct AggregateContext<A: Aggregate> {
     pub id: String,
     pub version: usize,
     pub aggregate: A,
+    /// Correlation id stamped on the events appended with this context
+    pub correlation_id: Option<String>,
+    /// Causation id stamped on the events appended with this context
+    pub causation_id: Option<String>,
+    /// Idempotency key remembered with the events appended with this context
+    pub idempotency: Option<IdempotencyKey>,
 }
 
 impl<A: Aggregate> AggregateContext<A> {
@@ -31,6 +39,9 @@ impl<A: Aggregate> Default for AggregateContext<A> {
             id: String::default(),
             version: 0_usize,
             aggregate: A::default(),
+            correlation_id: None,
+            causation_id: None,
+            idempotency: None,
         }
     }
 }
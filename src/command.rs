@@ -1,22 +1,53 @@
 use async_trait::async_trait;
 
-use crate::{Aggregate, AggregateContext, DomainEvent, Error, Store};
+use crate::{Aggregate, AggregateContext, DomainEvent, Error, Meta, MetaData, Store};
 
 /// Command handler
+///
+/// `M` is the metadata of the store the command runs against
 #[async_trait]
-pub trait Command<A, E>: Clone + Sync + Send
+pub trait Command<A, E, M = MetaData>: Clone + Sync + Send
 where
     A: Aggregate,
     E: DomainEvent<A>,
+    M: Meta,
 {
     fn id(&self) -> Option<String>;
 
     async fn handle(self, aggregate_context: &AggregateContext<A>) -> Result<Vec<E>, Error>;
 
-    async fn before<S: Store<A, E>>(command: Self, _store: &S) -> Result<Self, Error>
+    async fn before<S: Store<A, E, M>>(command: Self, _store: &S) -> Result<Self, Error>
     where
         Self: Sized,
     {
         Ok(command)
     }
+
+    /// Whether `CQRS::execute` may handle the command again after a version conflict,
+    /// return `false` for commands that are not idempotent
+    fn retry_on_conflict(&self) -> bool {
+        true
+    }
+
+    /// Correlation id of the chain the command belongs to, `None` starts a new chain
+    ///
+    /// Commands issued in reaction to an event pass on its `correlation_id`.
+    fn correlation_id(&self) -> Option<String> {
+        None
+    }
+
+    /// Id of the event that caused the command, `None` for commands issued from outside
+    fn causation_id(&self) -> Option<String> {
+        None
+    }
+
+    /// Key given by the client to deduplicate retries of the same request
+    ///
+    /// `CQRS::execute` returns the remembered outcome of an earlier command with
+    /// the same key, without handling it again. Keys are remembered by the store
+    /// with the events, stores that don't remember them handle every command,
+    /// see `Store::append`.
+    fn idempotency_key(&self) -> Option<String> {
+        None
+    }
 }
@@ -0,0 +1,351 @@
+use async_trait::async_trait;
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::{
+    Aggregate, AggregateContext, Command, CommandInfo, DomainEvent, Error, ExecutionResult,
+    FormatedEvents, Meta, MetaData, Middleware, Store, CQRS,
+};
+
+/// Step filling in metadata before a command is routed
+type Enricher<M> = Arc<dyn Fn(&mut M) + Send + Sync>;
+
+/// CommandBus
+///
+/// Routes commands to the `CQRS` instance registered for their type, so one
+/// `dispatch` entry point serves every aggregate. Metadata enrichers, bus
+/// middlewares and dispatch handlers registered on the bus apply to all of them.
+///
+/// Commands run concurrently, including those routed to the same `CQRS`
+/// instance, see `CQRS::execute`.
+pub struct CommandBus<M: Meta = MetaData> {
+    routes: HashMap<TypeId, Arc<dyn Route<M>>>,
+    enrichers: Vec<Enricher<M>>,
+    middlewares: Vec<Arc<dyn BusMiddleware<M>>>,
+    handlers: Vec<Arc<dyn DispatchHandler>>,
+}
+
+impl<M: Meta + 'static> CommandBus<M> {
+    pub fn new() -> CommandBus<M> {
+        CommandBus {
+            routes: HashMap::new(),
+            enrichers: Vec::new(),
+            middlewares: Vec::new(),
+            handlers: Vec::new(),
+        }
+    }
+
+    /// Route the commands of `routes` to its `CQRS` instance
+    ///
+    /// A command type routed twice goes to the last registration.
+    pub fn register<A, E, ES>(mut self, routes: CommandRoutes<A, E, ES, M>) -> Self
+    where
+        A: Aggregate + 'static,
+        E: DomainEvent<A> + 'static,
+        ES: Store<A, E, M> + 'static,
+    {
+        self.routes.extend(routes.routes);
+        self
+    }
+
+    /// Add a step filling in the metadata of every dispatched command, steps run in
+    /// the order they were added
+    pub fn with_enricher<F>(mut self, enricher: F) -> Self
+    where
+        F: Fn(&mut M) + Send + Sync + 'static,
+    {
+        self.enrichers.push(Arc::new(enricher));
+        self
+    }
+
+    /// Add a middleware wrapping every routed command
+    ///
+    /// Bus middlewares run before the middlewares of the `CQRS` instance, in the
+    /// order they were added, see `Middleware` for the order hooks run in.
+    pub fn with_middleware<W: BusMiddleware<M> + 'static>(mut self, middleware: W) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Add a handler run after every successful dispatch
+    pub fn with_handler<H: DispatchHandler + 'static>(mut self, handler: H) -> Self {
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    /// Checks if commands of type `C` are routed
+    pub fn is_routed<C: 'static>(&self) -> bool {
+        self.routes.contains_key(&TypeId::of::<C>())
+    }
+
+    /// Execute `command` with the `CQRS` instance registered for its type
+    pub async fn dispatch<C>(&self, command: C, mut meta: M) -> Result<DispatchResult, Error>
+    where
+        C: Send + 'static,
+    {
+        let route = match self.routes.get(&TypeId::of::<C>()) {
+            Some(route) => route,
+            None => return Err(unrouted(type_name::<C>())),
+        };
+
+        for enrich in &self.enrichers {
+            enrich(&mut meta);
+        }
+
+        let result = route
+            .dispatch(Box::new(command), meta, &self.middlewares)
+            .await?;
+
+        for handler in &self.handlers {
+            handler.handle(&result).await;
+        }
+
+        Ok(result)
+    }
+}
+
+impl<M: Meta + 'static> Default for CommandBus<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CommandRoutes
+///
+/// A `CQRS` instance and the command types the bus routes to it
+pub struct CommandRoutes<A, E, ES, M = MetaData>
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    ES: Store<A, E, M>,
+    M: Meta,
+{
+    cqrs: Arc<CQRS<A, E, ES, M>>,
+    routes: Vec<(TypeId, Arc<dyn Route<M>>)>,
+}
+
+impl<A, E, ES, M> CommandRoutes<A, E, ES, M>
+where
+    A: Aggregate + 'static,
+    E: DomainEvent<A> + 'static,
+    ES: Store<A, E, M> + 'static,
+    M: Meta + 'static,
+{
+    pub fn new(cqrs: CQRS<A, E, ES, M>) -> CommandRoutes<A, E, ES, M> {
+        CommandRoutes {
+            cqrs: Arc::new(cqrs),
+            routes: Vec::new(),
+        }
+    }
+
+    /// Route commands of type `C`
+    pub fn command<C: Command<A, E, M> + 'static>(mut self) -> Self {
+        let route = CommandRoute::<A, E, ES, M, C> {
+            cqrs: Arc::clone(&self.cqrs),
+            _c: PhantomData,
+        };
+        self.routes.push((TypeId::of::<C>(), Arc::new(route)));
+        self
+    }
+}
+
+/// DispatchResult
+///
+/// `ExecutionResult` of a dispatched command without its aggregate and event
+/// types, `into_execution` gives the typed result back.
+pub struct DispatchResult {
+    pub aggregate_type: &'static str,
+    pub aggregate_id: String,
+    pub version: usize,
+    /// Whether the command created the aggregate
+    pub created: bool,
+    /// Ids of the committed events
+    pub event_ids: Vec<String>,
+    /// Whether the outcome was remembered from an earlier command with the same idempotency key
+    pub replayed: bool,
+    execution: Box<dyn Any + Send + Sync>,
+}
+
+impl DispatchResult {
+    fn new<A, E, M>(execution: ExecutionResult<A, E, M>) -> DispatchResult
+    where
+        A: Aggregate + 'static,
+        E: DomainEvent<A> + 'static,
+        M: Meta + 'static,
+    {
+        DispatchResult {
+            aggregate_type: A::aggregate_type(),
+            aggregate_id: execution.aggregate_id.clone(),
+            version: execution.version,
+            created: execution.is_created(),
+            event_ids: execution
+                .events
+                .iter()
+                .map(|e| e.event_id.clone())
+                .collect(),
+            replayed: execution.replayed,
+            execution: Box::new(execution),
+        }
+    }
+
+    /// The typed result, the dispatch result itself if the types don't match
+    pub fn into_execution<A, E, M>(self) -> Result<ExecutionResult<A, E, M>, DispatchResult>
+    where
+        A: Aggregate + 'static,
+        E: DomainEvent<A> + 'static,
+        M: Meta + 'static,
+    {
+        match self.execution.downcast::<ExecutionResult<A, E, M>>() {
+            Ok(execution) => Ok(*execution),
+            Err(execution) => Err(DispatchResult { execution, ..self }),
+        }
+    }
+}
+
+impl std::fmt::Debug for DispatchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DispatchResult")
+            .field("aggregate_type", &self.aggregate_type)
+            .field("aggregate_id", &self.aggregate_id)
+            .field("version", &self.version)
+            .field("created", &self.created)
+            .field("event_ids", &self.event_ids)
+            .field("replayed", &self.replayed)
+            .finish()
+    }
+}
+
+/// Handler run by `CommandBus` after each successful dispatch, whatever the aggregate
+#[async_trait]
+pub trait DispatchHandler: Send + Sync {
+    async fn handle(&self, result: &DispatchResult);
+}
+
+/// Middleware added to `CommandBus`, whatever the aggregate
+///
+/// The hooks of `Middleware` without the aggregate and event types, every hook
+/// does nothing by default. `CommandInfo::aggregate_type` tells the routes apart.
+#[async_trait]
+pub trait BusMiddleware<M: Meta = MetaData>: Send + Sync {
+    /// Runs before `Command::before`
+    async fn before_command(&self, _command: &CommandInfo, _meta: &mut M) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Runs before `Command::handle` with the id and version of the assembled aggregate
+    async fn before_handle(
+        &self,
+        _command: &CommandInfo,
+        _aggregate_id: &str,
+        _version: usize,
+        _meta: &mut M,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Runs once the events are appended with their ids
+    async fn after_append(&self, _command: &CommandInfo, _event_ids: &[String]) {}
+
+    /// Runs when the command fails, whichever step failed
+    async fn on_error(&self, _command: &CommandInfo, _error: &Error) {}
+}
+
+/// `BusMiddleware` run as the `Middleware` of a route
+struct Bus<M: Meta>(Arc<dyn BusMiddleware<M>>);
+
+#[async_trait]
+impl<A, E, M> Middleware<A, E, M> for Bus<M>
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    M: Meta,
+{
+    async fn before_command(&self, command: &CommandInfo, meta: &mut M) -> Result<(), Error> {
+        self.0.before_command(command, meta).await
+    }
+
+    async fn before_handle(
+        &self,
+        command: &CommandInfo,
+        context: &AggregateContext<A>,
+        meta: &mut M,
+    ) -> Result<(), Error> {
+        self.0
+            .before_handle(command, &context.id, context.version, meta)
+            .await
+    }
+
+    async fn after_append(&self, command: &CommandInfo, events: &FormatedEvents<A, E, M>) {
+        let event_ids: Vec<String> = events.iter().map(|e| e.event_id.clone()).collect();
+        self.0.after_append(command, &event_ids).await
+    }
+
+    async fn on_error(&self, command: &CommandInfo, error: &Error) {
+        self.0.on_error(command, error).await
+    }
+}
+
+/// Executes commands of one type
+#[async_trait]
+trait Route<M: Meta>: Send + Sync {
+    async fn dispatch(
+        &self,
+        command: Box<dyn Any + Send>,
+        meta: M,
+        middlewares: &[Arc<dyn BusMiddleware<M>>],
+    ) -> Result<DispatchResult, Error>;
+}
+
+struct CommandRoute<A, E, ES, M, C>
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    ES: Store<A, E, M>,
+    M: Meta,
+{
+    cqrs: Arc<CQRS<A, E, ES, M>>,
+    _c: PhantomData<fn() -> C>,
+}
+
+#[async_trait]
+impl<A, E, ES, M, C> Route<M> for CommandRoute<A, E, ES, M, C>
+where
+    A: Aggregate + 'static,
+    E: DomainEvent<A> + 'static,
+    ES: Store<A, E, M> + 'static,
+    M: Meta + 'static,
+    C: Command<A, E, M> + 'static,
+{
+    async fn dispatch(
+        &self,
+        command: Box<dyn Any + Send>,
+        meta: M,
+        middlewares: &[Arc<dyn BusMiddleware<M>>],
+    ) -> Result<DispatchResult, Error> {
+        let command = match command.downcast::<C>() {
+            Ok(command) => *command,
+            Err(_) => return Err(unrouted(type_name::<C>())),
+        };
+
+        let outer: Vec<Arc<dyn Middleware<A, E, M>>> = middlewares
+            .iter()
+            .map(|middleware| Arc::new(Bus(Arc::clone(middleware))) as Arc<dyn Middleware<A, E, M>>)
+            .collect();
+        let execution = self.cqrs.execute_within(command, meta, &outer).await?;
+
+        Ok(DispatchResult::new(execution))
+    }
+}
+
+fn unrouted(command: &str) -> Error {
+    let mut extension = HashMap::new();
+    extension.insert("command".to_string(), command.to_string());
+
+    Error::new(
+        "CommandBus: no route for command",
+        Some("INTERNAL"),
+        Some(extension),
+    )
+}
@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::Error;
+
+/// Codec compressing large event payloads
+///
+/// Each codec needs its cargo feature: `zstd` and `gzip`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    /// Name recorded with each compressed record
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    /// Checks if the codec was compiled in
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Gzip => cfg!(feature = "gzip"),
+        }
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        self.pack(bytes)
+            .map_err(|reason| self.error("Compression: cannot compress", &reason))
+    }
+
+    pub fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        self.unpack(bytes)
+            .map_err(|reason| self.error("Compression: cannot decompress", &reason))
+    }
+
+    /// Compress `bytes`, failures give the reason
+    pub(crate) fn pack(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::encode_all(bytes, 0).map_err(|e| e.to_string()),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes).map_err(|e| e.to_string())?;
+                encoder.finish().map_err(|e| e.to_string())
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = bytes;
+                Err(self.disabled())
+            }
+        }
+    }
+
+    /// Decompress `bytes`, failures give the reason
+    pub(crate) fn unpack(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::decode_all(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Read;
+
+                let mut decoded = Vec::new();
+                flate2::read::GzDecoder::new(bytes)
+                    .read_to_end(&mut decoded)
+                    .map_err(|e| e.to_string())?;
+                Ok(decoded)
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = bytes;
+                Err(self.disabled())
+            }
+        }
+    }
+
+    fn disabled(&self) -> String {
+        format!(
+            "{} compression needs the `{}` feature",
+            self.name(),
+            self.name()
+        )
+    }
+
+    fn error(&self, message: &'static str, reason: &str) -> Error {
+        let mut extension = HashMap::new();
+        extension.insert("compression".to_string(), self.name().to_string());
+        extension.insert("reason".to_string(), reason.to_string());
+
+        Error::new(message, Some("INTERNAL"), Some(extension))
+    }
+}
+
+/// Which payloads a store compresses
+///
+/// Payloads of `threshold` bytes or more once serialized are compressed, smaller
+/// ones are stored as they are. Records say whether they are compressed, so a
+/// store reads them back whatever its own policy is.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CompressionPolicy {
+    compression: Option<Compression>,
+    threshold: usize,
+}
+
+impl CompressionPolicy {
+    /// Never compress
+    pub fn none() -> CompressionPolicy {
+        CompressionPolicy::default()
+    }
+
+    /// Compress payloads of `threshold` bytes or more with `compression`
+    pub fn above(compression: Compression, threshold: usize) -> CompressionPolicy {
+        CompressionPolicy {
+            compression: Some(compression),
+            threshold,
+        }
+    }
+
+    /// Codec for a payload of `len` bytes, `None` if it stays uncompressed
+    pub fn compression_for(&self, len: usize) -> Option<Compression> {
+        self.compression.filter(|_| len >= self.threshold)
+    }
+}
+
+/// Compressed payload of a SQL store row
+///
+/// The codec is recorded in the `compression` column and the payload column
+/// holds a JSON string of the base64 compressed JSON. Uncompressed payloads are
+/// stored as they are, so they stay queryable.
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+#[derive(Debug)]
+pub(crate) struct CompressedPayload {
+    pub compression: Compression,
+    /// Base64 of the compressed JSON
+    pub data: String,
+}
+
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+impl CompressedPayload {
+    /// Compress `json` if the policy says so
+    pub fn pack(
+        policy: CompressionPolicy,
+        json: &[u8],
+    ) -> Result<Option<CompressedPayload>, Error> {
+        match policy.compression_for(json.len()) {
+            Some(compression) => Ok(Some(CompressedPayload {
+                compression,
+                data: base64::encode(compression.compress(json)?),
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Payload `data` of a row whose `compression` column names `codec`
+    pub fn stored(codec: &str, data: String) -> Result<CompressedPayload, Error> {
+        let compression = serde_json::from_value(serde_json::Value::String(codec.to_string()))
+            .map_err(|_| {
+                let mut extension = HashMap::new();
+                extension.insert("compression".to_string(), codec.to_string());
+
+                Error::new(
+                    "Compression: unknown codec",
+                    Some("INTERNAL"),
+                    Some(extension),
+                )
+            })?;
+
+        Ok(CompressedPayload { compression, data })
+    }
+
+    /// The JSON that was compressed
+    pub fn unpack(self) -> Result<Vec<u8>, Error> {
+        let bytes = base64::decode(&self.data).map_err(|e| {
+            self.compression
+                .error("Compression: cannot decompress", &e.to_string())
+        })?;
+
+        self.compression.decompress(&bytes)
+    }
+}
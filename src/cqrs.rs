@@ -1,61 +1,280 @@
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::delay_for;
+use uuid::Uuid;
 
-use crate::{Aggregate, Command, DomainEvent, Error, Handlers, MetaData, Store};
+use crate::{
+    Aggregate, Command, CommandInfo, DomainEvent, Error, ExecutionResult, Handler, Handlers,
+    IdempotencyKey, IdempotencyRecord, Meta, MetaData, Middleware, RetryPolicy, Store,
+    DEFAULT_IDEMPOTENCY_WINDOW,
+};
+
+/// Handler behind its own lock, so `execute` can borrow the `CQRS` shared
+type LockedHandler<A, E, M> = Mutex<Box<dyn Handler<A, E, M> + Send>>;
 
 // #[derive()]
-pub struct CQRS<A, E, ES>
+pub struct CQRS<A, E, ES, M = MetaData>
 where
     A: Aggregate,
     E: DomainEvent<A>,
-    ES: Store<A, E>,
+    ES: Store<A, E, M>,
+    M: Meta,
 {
-    handlers: Handlers<A, E>,
+    handlers: Vec<LockedHandler<A, E, M>>,
+    middlewares: Vec<Arc<dyn Middleware<A, E, M>>>,
     store: ES,
+    retry_policy: RetryPolicy,
+    aggregate_state: bool,
+    idempotency_window: Duration,
     _a: PhantomData<A>,
     _e: PhantomData<E>,
+    _m: PhantomData<M>,
 }
 
-impl<A, E, ES> CQRS<A, E, ES>
+impl<A, E, ES, M> CQRS<A, E, ES, M>
 where
     A: Aggregate,
     E: DomainEvent<A>,
-    ES: Store<A, E>,
+    ES: Store<A, E, M>,
+    M: Meta,
 {
-    pub fn new(store: ES, handlers: Handlers<A, E>) -> CQRS<A, E, ES> {
+    pub fn new(store: ES, handlers: Handlers<A, E, M>) -> CQRS<A, E, ES, M> {
         Self {
             store,
-            handlers,
+            handlers: handlers.into_iter().map(Mutex::new).collect(),
+            middlewares: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            aggregate_state: false,
+            idempotency_window: DEFAULT_IDEMPOTENCY_WINDOW,
             _a: PhantomData,
             _e: PhantomData,
+            _m: PhantomData,
+        }
+    }
+
+    /// Set the policy used to retry commands on version conflicts
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> CQRS<A, E, ES, M> {
+        CQRS {
+            retry_policy,
+            ..self
+        }
+    }
+
+    /// Set whether `execute` returns the state of the aggregate after the command
+    ///
+    /// The committed events are applied to the aggregate the command was handled on.
+    pub fn with_aggregate_state(self, aggregate_state: bool) -> CQRS<A, E, ES, M> {
+        CQRS {
+            aggregate_state,
+            ..self
+        }
+    }
+
+    /// Set how long the outcome of a command with an idempotency key is remembered
+    pub fn with_idempotency_window(self, idempotency_window: Duration) -> CQRS<A, E, ES, M> {
+        CQRS {
+            idempotency_window,
+            ..self
+        }
+    }
+
+    /// Add a middleware wrapping `execute`, see `Middleware` for the order hooks run in
+    pub fn with_middleware<W: Middleware<A, E, M> + 'static>(
+        mut self,
+        middleware: W,
+    ) -> CQRS<A, E, ES, M> {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Handle `command` and append the events it produces
+    ///
+    /// The events share the command's correlation id and are caused by the event
+    /// it names. A command without them gets a new id used for both, so the
+    /// events it produces start a chain.
+    ///
+    /// Returns the aggregate id, which is generated for new aggregates, its new
+    /// version and the committed events.
+    ///
+    /// A command whose idempotency key was remembered isn't handled again, the
+    /// earlier outcome is read back from the store and no handler runs. The key
+    /// is stored with the events, so of retries running at once only the first
+    /// to append keeps its events, the others get its outcome.
+    ///
+    /// Commands run concurrently, only a handler is run by one at a time.
+    pub async fn execute<C: Command<A, E, M>>(
+        &self,
+        command: C,
+        meta: M,
+    ) -> Result<ExecutionResult<A, E, M>, Error> {
+        self.execute_within(command, meta, &[]).await
+    }
+
+    /// `execute` wrapped by `outer` middlewares, which run before those of the instance
+    pub(crate) async fn execute_within<C: Command<A, E, M>>(
+        &self,
+        command: C,
+        meta: M,
+        outer: &[Arc<dyn Middleware<A, E, M>>],
+    ) -> Result<ExecutionResult<A, E, M>, Error> {
+        let middlewares: Vec<_> = outer.iter().chain(self.middlewares.iter()).collect();
+        let info = CommandInfo::new::<A, C>(command.id());
+        let result = self.run(command, meta, &info, &middlewares).await;
+
+        if let Err(e) = &result {
+            for middleware in middlewares.iter().rev() {
+                middleware.on_error(&info, e).await;
+            }
         }
+
+        result
     }
 
-    pub async fn execute<C: Command<A, E>>(
-        &mut self,
+    async fn run<C: Command<A, E, M>>(
+        &self,
         command: C,
-        meta: MetaData,
-    ) -> Result<(), Error> {
+        mut meta: M,
+        info: &CommandInfo,
+        middlewares: &[&Arc<dyn Middleware<A, E, M>>],
+    ) -> Result<ExecutionResult<A, E, M>, Error> {
+        for middleware in middlewares.iter() {
+            middleware.before_command(info, &mut meta).await?;
+        }
+
+        // A repeated key gets the remembered outcome
+        let key = command
+            .idempotency_key()
+            .map(|key| IdempotencyKey::new(&key, self.idempotency_window));
+        if let Some(key) = &key {
+            if let Some(record) = self.store.remembered(&key.key).await? {
+                return Self::replay(&self.store, self.aggregate_state, record).await;
+            }
+        }
+
         // Call command's before
         let cmd = C::before(command, &self.store).await?;
 
-        // Assemble Aggragate
         let id = &cmd.id();
-        let aggregate_context = self.store.assemble_aggregate(id.clone()).await?;
+        let max_retries = match cmd.retry_on_conflict() {
+            true => self.retry_policy.max_retries,
+            false => 0,
+        };
+        let command_id = Uuid::new_v4().to_string();
+        let correlation_id = cmd.correlation_id().unwrap_or_else(|| command_id.clone());
+        let causation_id = cmd.causation_id().unwrap_or(command_id);
+
+        let mut attempt = 0;
+        let (mut aggregate_context, commited_events) = loop {
+            // Assemble Aggragate
+            let mut aggregate_context = self.store.assemble_aggregate(id.clone()).await?;
+            aggregate_context.correlation_id = Some(correlation_id.clone());
+            aggregate_context.causation_id = Some(causation_id.clone());
+            aggregate_context.idempotency = key.clone();
 
-        // Handle Command
-        let generated_events = cmd.handle(&aggregate_context).await?;
+            // Handle Command, wrapped by the middlewares
+            let mut meta = meta.clone();
+            for middleware in middlewares.iter() {
+                middleware
+                    .before_handle(info, &aggregate_context, &mut meta)
+                    .await?;
+            }
+            let mut generated_events = cmd.clone().handle(&aggregate_context).await?;
+            for middleware in middlewares.iter().rev() {
+                middleware
+                    .after_handle(info, &aggregate_context, &mut generated_events, &mut meta)
+                    .await?;
+            }
 
-        // Store New Events
-        let commited_events = &self
-            .store
-            .append(generated_events, aggregate_context, meta)
-            .await?;
+            // Store New Events
+            match self
+                .store
+                .append(generated_events, &aggregate_context, meta)
+                .await
+            {
+                Ok(events) => break (aggregate_context, events),
+                Err(e) if e.is_conflict() && attempt < max_retries => {
+                    delay_for(self.retry_policy.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                // A concurrent retry stored its events first
+                Err(e) if e.is_duplicate() => {
+                    if let Some(key) = &key {
+                        if let Some(record) = self.store.remembered(&key.key).await? {
+                            return Self::replay(&self.store, self.aggregate_state, record).await;
+                        }
+                    }
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let aggregate_id = aggregate_context.id.clone();
+        let version = aggregate_context.version;
+        let new_version = commited_events.last().map_or(version, |e| e.version);
+
+        for middleware in middlewares.iter().rev() {
+            middleware.after_append(info, &commited_events).await;
+        }
 
         // Run Handlers
-        for handler in &self.handlers {
-            handler.handle(commited_events).await;
+        for handler in self.handlers.iter() {
+            handler.lock().await.handle(&commited_events).await;
         }
 
-        Ok(())
+        let aggregate = match self.aggregate_state {
+            true => {
+                for fmt_event in commited_events.iter() {
+                    fmt_event
+                        .payload
+                        .clone()
+                        .apply(&mut aggregate_context.aggregate);
+                }
+                Some(aggregate_context.aggregate)
+            }
+            false => None,
+        };
+
+        Ok(ExecutionResult {
+            aggregate_id,
+            version: new_version,
+            events: commited_events,
+            aggregate,
+            replayed: false,
+        })
+    }
+
+    /// Outcome remembered in `record`, rebuilt from the stored events
+    ///
+    /// Borrows the store only, so the future stays `Send`
+    async fn replay(
+        store: &ES,
+        aggregate_state: bool,
+        record: IdempotencyRecord,
+    ) -> Result<ExecutionResult<A, E, M>, Error> {
+        let mut events = store.retrieve(&record.aggregate_id).await?;
+        events.retain(|e| e.version <= record.version);
+
+        let aggregate = match aggregate_state {
+            true => {
+                let mut aggregate = A::default();
+                for fmt_event in events.iter() {
+                    fmt_event.payload.clone().apply(&mut aggregate);
+                }
+                Some(aggregate)
+            }
+            false => None,
+        };
+        events.retain(|e| e.version > record.from_version);
+
+        Ok(ExecutionResult {
+            aggregate_id: record.aggregate_id,
+            version: record.version,
+            events,
+            aggregate,
+            replayed: true,
+        })
     }
 }
@@ -8,4 +8,12 @@ pub trait DomainEvent<A: Aggregate>:
 {
     fn apply(self, aggregate: &mut A);
     fn name() -> &'static str;
+
+    /// Version of the serialized shape, stored with each record
+    ///
+    /// Bump it when the shape changes and register an upcaster from the old
+    /// version, see `Upcasters`
+    fn schema_version() -> usize {
+        1
+    }
 }
@@ -3,13 +3,13 @@ use std::{collections::HashMap, error, fmt};
 /** App Error */
 #[derive(Debug, PartialEq)]
 pub struct Error {
-    code: Option<STR>,
-    message: STR,
+    code: Option<Str>,
+    message: Str,
     extension: Extension,
 }
 
 impl Error {
-    pub fn new(message: STR, code: Option<&'static str>, extension: Extension) -> Error {
+    pub fn new(message: Str, code: Option<&'static str>, extension: Extension) -> Error {
         Error {
             message,
             code,
@@ -17,16 +17,122 @@ impl Error {
         }
     }
 
-    pub fn code(&self) -> STR {
-        match self.code {
-            Some(code) => code,
-            None => return "",
-        }
+    /// Version conflict raised by `Store::append` when the expected version
+    /// does not match the version currently persisted for the aggregate
+    pub fn conflict(aggregate_id: &str, expected: usize, current: usize) -> Error {
+        let mut extension = HashMap::new();
+        extension.insert("aggregate_id".to_string(), aggregate_id.to_string());
+        extension.insert("expected_version".to_string(), expected.to_string());
+        extension.insert("current_version".to_string(), current.to_string());
+
+        Error::new(
+            "Aggregate version conflict",
+            Some(CONFLICT),
+            Some(extension),
+        )
+    }
+
+    /// Failure reading or writing a store file, `line` is the 1-based line
+    /// number when the failure is tied to one
+    pub fn file(message: Str, path: &str, line: Option<usize>, reason: &str) -> Error {
+        Error::new(
+            message,
+            Some("INTERNAL"),
+            Some(file_extension(path, line, reason)),
+        )
+    }
+
+    /// Line of a store file that can't be decoded into an event
+    pub fn corrupt(path: &str, line: usize, reason: &str) -> Error {
+        Error::new(
+            "Corrupt store record",
+            Some(CORRUPT),
+            Some(file_extension(path, Some(line), reason)),
+        )
+    }
+
+    /// Read that would replay events a compaction dropped from store file `path`
+    pub fn compacted(path: &str, reason: &str) -> Error {
+        Error::new(
+            "Events dropped by compaction",
+            Some(COMPACTED),
+            Some(file_extension(path, None, reason)),
+        )
     }
 
-    pub fn message(&self) -> STR {
+    /// Append carrying an idempotency key already remembered for `aggregate_type`
+    pub fn duplicate(aggregate_type: &str, key: &str) -> Error {
+        let mut extension = HashMap::new();
+        extension.insert("aggregate_type".to_string(), aggregate_type.to_string());
+        extension.insert("idempotency_key".to_string(), key.to_string());
+
+        Error::new(
+            "Idempotency key already used",
+            Some(DUPLICATE),
+            Some(extension),
+        )
+    }
+
+    /// Store file locked by another writer for longer than `timeout_ms`
+    pub fn locked(path: &str, timeout_ms: u128) -> Error {
+        let mut extension = HashMap::new();
+        extension.insert("path".to_string(), path.to_string());
+        extension.insert("timeout_ms".to_string(), timeout_ms.to_string());
+
+        Error::new(
+            "Store is locked by another writer",
+            Some(LOCKED),
+            Some(extension),
+        )
+    }
+
+    pub fn code(&self) -> Str {
+        self.code.unwrap_or_default()
+    }
+
+    pub fn message(&self) -> Str {
         self.message
     }
+
+    pub fn extension(&self) -> Option<&HashMap<String, String>> {
+        self.extension.as_ref()
+    }
+
+    /// Checks if error is a version conflict
+    pub fn is_conflict(&self) -> bool {
+        self.code == Some(CONFLICT)
+    }
+
+    /// Checks if error is a store locked by another writer
+    pub fn is_locked(&self) -> bool {
+        self.code == Some(LOCKED)
+    }
+
+    /// Checks if error is a corrupt store record
+    pub fn is_corrupt(&self) -> bool {
+        self.code == Some(CORRUPT)
+    }
+
+    /// Checks if error is a read of events dropped by compaction
+    pub fn is_compacted(&self) -> bool {
+        self.code == Some(COMPACTED)
+    }
+
+    /// Checks if error is an append with an idempotency key already used
+    pub fn is_duplicate(&self) -> bool {
+        self.code == Some(DUPLICATE)
+    }
+}
+
+fn file_extension(path: &str, line: Option<usize>, reason: &str) -> HashMap<String, String> {
+    let mut extension = HashMap::new();
+    extension.insert("path".to_string(), path.to_string());
+    if let Some(line) = line {
+        extension.insert("line".to_string(), line.to_string());
+    }
+    extension.insert("reason".to_string(), reason.to_string());
+
+    extension
 }
 
 impl error::Error for Error {}
@@ -55,12 +161,36 @@ impl From<serde_json::error::Error> for Error {
 }
 
 impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Self {
-        match err {
-            _ => Error::new("IO Isssues", Some("INTERNAL"), None),
-        }
+    fn from(_err: std::io::Error) -> Self {
+        Error::new("IO Isssues", Some("INTERNAL"), None)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        let mut extension = HashMap::new();
+        extension.insert("reason".to_string(), err.to_string());
+
+        Error::new("Sqlite: query failed", Some("INTERNAL"), Some(extension))
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<postgres::Error> for Error {
+    fn from(err: postgres::Error) -> Self {
+        let mut extension = HashMap::new();
+        extension.insert("reason".to_string(), err.to_string());
+
+        Error::new("Postgres: query failed", Some("INTERNAL"), Some(extension))
     }
 }
 
+const CONFLICT: Str = "CONFLICT";
+const CORRUPT: Str = "CORRUPT";
+const LOCKED: Str = "LOCKED";
+const COMPACTED: Str = "COMPACTED";
+const DUPLICATE: Str = "DUPLICATE";
+
 type Extension = Option<HashMap<String, String>>;
-type STR = &'static str;
+type Str = &'static str;
@@ -0,0 +1,162 @@
+use chrono::prelude::*;
+
+use crate::{Aggregate, DomainEvent, FormatedEvent, Meta};
+
+/// Selects events across aggregates
+///
+/// Every criterion that is set must match, an empty list matches anything.
+///
+/// ```
+/// use cqrs_eventsourcing::EventFilter;
+///
+/// let filter = EventFilter::new()
+///     .aggregate_type("dispatch")
+///     .event_name("Accepted")
+///     .meta("tenant", "acme")
+///     .from_position(10);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventFilter {
+    pub aggregate_ids: Vec<String>,
+    pub aggregate_types: Vec<String>,
+    /// Matched against `FormatedEvent::event_name`
+    pub event_names: Vec<String>,
+    /// Events created at or after
+    pub created_from: Option<DateTime<Utc>>,
+    /// Events created strictly before
+    pub created_before: Option<DateTime<Utc>>,
+    /// Smallest global position, inclusive
+    pub from_position: Option<usize>,
+    /// Largest global position, inclusive
+    pub to_position: Option<usize>,
+    /// Key/value pairs the event metadata must contain, keys are the fields of the
+    /// serialized metadata
+    pub meta: Vec<(String, String)>,
+}
+
+impl EventFilter {
+    /// Filter matching every event
+    pub fn new() -> EventFilter {
+        EventFilter::default()
+    }
+
+    pub fn aggregate_id(mut self, aggregate_id: &str) -> Self {
+        self.aggregate_ids.push(aggregate_id.to_string());
+        self
+    }
+
+    pub fn aggregate_type(mut self, aggregate_type: &str) -> Self {
+        self.aggregate_types.push(aggregate_type.to_string());
+        self
+    }
+
+    pub fn event_name(mut self, event_name: &str) -> Self {
+        self.event_names.push(event_name.to_string());
+        self
+    }
+
+    pub fn created_from(self, created_from: DateTime<Utc>) -> Self {
+        EventFilter {
+            created_from: Some(created_from),
+            ..self
+        }
+    }
+
+    pub fn created_before(self, created_before: DateTime<Utc>) -> Self {
+        EventFilter {
+            created_before: Some(created_before),
+            ..self
+        }
+    }
+
+    pub fn from_position(self, from_position: usize) -> Self {
+        EventFilter {
+            from_position: Some(from_position),
+            ..self
+        }
+    }
+
+    pub fn to_position(self, to_position: usize) -> Self {
+        EventFilter {
+            to_position: Some(to_position),
+            ..self
+        }
+    }
+
+    pub fn meta(mut self, key: &str, value: &str) -> Self {
+        self.meta.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// The only aggregate type matched, if there is exactly one
+    pub fn single_aggregate_type(&self) -> Option<&str> {
+        match self.aggregate_types.as_slice() {
+            [aggregate_type] => Some(aggregate_type),
+            _ => None,
+        }
+    }
+
+    /// Checks if `event` satisfies every criterion
+    pub fn matches<A: Aggregate, E: DomainEvent<A>, M: Meta>(
+        &self,
+        event: &FormatedEvent<A, E, M>,
+    ) -> bool {
+        if !self.aggregate_ids.is_empty() && !self.aggregate_ids.contains(&event.aggregate_id) {
+            return false;
+        }
+
+        if !self.aggregate_types.is_empty() && !self.aggregate_types.contains(&event.aggregate_type)
+        {
+            return false;
+        }
+
+        if self.from_position.is_some_and(|p| event.position < p)
+            || self.to_position.is_some_and(|p| event.position > p)
+        {
+            return false;
+        }
+
+        if !self.meta.is_empty() {
+            let meta = serde_json::to_value(&event.meta).unwrap_or_default();
+            if !self
+                .meta
+                .iter()
+                .all(|(key, value)| meta_entry(&meta, key).as_ref() == Some(value))
+            {
+                return false;
+            }
+        }
+
+        if self.created_from.is_some() || self.created_before.is_some() {
+            let created_at = match parse_created_at(&event.created_at) {
+                Some(created_at) => created_at,
+                None => return false,
+            };
+
+            if self.created_from.is_some_and(|t| created_at < t)
+                || self.created_before.is_some_and(|t| created_at >= t)
+            {
+                return false;
+            }
+        }
+
+        self.event_names.is_empty() || self.event_names.contains(&event.event_name())
+    }
+}
+
+/// Events are stamped in RFC 2822, RFC 3339 is accepted too
+fn parse_created_at(created_at: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(created_at)
+        .or_else(|_| DateTime::parse_from_rfc3339(created_at))
+        .map(|t| t.with_timezone(&Utc))
+        .ok()
+}
+
+/// Field `key` of serialized metadata as text, strings are compared without quotes
+fn meta_entry(meta: &serde_json::Value, key: &str) -> Option<String> {
+    match meta.get(key)? {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
@@ -0,0 +1,57 @@
+use futures::future::{ready, Future};
+use futures::stream::{self, StreamExt};
+
+use crate::{Aggregate, DomainEvent, EventStream, FormatedResult, Meta};
+
+/// Number of events fetched per page by `paged`
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub(crate) const PAGE_SIZE: usize = 256;
+
+/// Stream the events of a materialized result
+pub(crate) fn from_result<'a, A, E, M, F>(events: F) -> EventStream<'a, A, E, M>
+where
+    A: Aggregate + 'a,
+    E: DomainEvent<A> + 'a,
+    M: Meta + 'a,
+    F: Future<Output = FormatedResult<A, E, M>> + Send + 'a,
+{
+    stream::once(events)
+        .flat_map(|result| match result {
+            Ok(events) => stream::iter(events.into_iter().map(Ok)).left_stream(),
+            Err(e) => stream::once(ready(Err(e))).right_stream(),
+        })
+        .boxed()
+}
+
+/// Stream events page by page
+///
+/// `fetch` gets the position of the last event seen and returns up to `PAGE_SIZE`
+/// events after it, ordered by position
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub(crate) fn paged<'a, A, E, M, F, Fut>(fetch: F) -> EventStream<'a, A, E, M>
+where
+    A: Aggregate + 'a,
+    E: DomainEvent<A> + 'a,
+    M: Meta + 'a,
+    F: Fn(usize) -> Fut + Send + 'a,
+    Fut: Future<Output = FormatedResult<A, E, M>> + Send + 'a,
+{
+    stream::unfold(Some(0), move |after| {
+        let page = after.map(&fetch);
+
+        async move {
+            let result = page?.await;
+            let next = match &result {
+                Ok(events) if events.len() == PAGE_SIZE => events.last().map(|e| e.position),
+                _ => None,
+            };
+
+            Some((result, next))
+        }
+    })
+    .flat_map(|result| match result {
+        Ok(events) => stream::iter(events.into_iter().map(Ok)).left_stream(),
+        Err(e) => stream::once(ready(Err(e))).right_stream(),
+    })
+    .boxed()
+}
@@ -0,0 +1,34 @@
+use crate::{Aggregate, DomainEvent, FormatedEvents, Meta, MetaData};
+
+/// Outcome of `CQRS::execute`
+#[derive(Debug)]
+pub struct ExecutionResult<A, E, M = MetaData>
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    M: Meta,
+{
+    /// Id of the aggregate, generated when the command didn't name one
+    pub aggregate_id: String,
+    /// Version of the aggregate after the command, unchanged if no events were produced
+    pub version: usize,
+    /// Events appended by the command
+    pub events: FormatedEvents<A, E, M>,
+    /// State of the aggregate after the command, when `CQRS::with_aggregate_state` is set
+    pub aggregate: Option<A>,
+    /// Whether the outcome was remembered from an earlier command with the same
+    /// idempotency key, nothing was appended this time
+    pub replayed: bool,
+}
+
+impl<A, E, M> ExecutionResult<A, E, M>
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    M: Meta,
+{
+    /// Checks if the command created the aggregate
+    pub fn is_created(&self) -> bool {
+        self.events.first().is_some_and(|e| e.version == 1)
+    }
+}
@@ -1,101 +1,1330 @@
 use async_trait::async_trait;
+use fs2::FileExt;
+use futures::future::ready;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use serde_json::value::RawValue;
 use std::marker::PhantomData;
 use std::{
-    fs::{File, OpenOptions},
-    io::{self, prelude::*},
-    path::Path,
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+    fmt,
+    io::{self, SeekFrom},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+    time::delay_for,
 };
 
+use crate::file_index::{Index, IndexEntry, IndexedEvent};
+use crate::idempotency::KeyFile;
+use crate::upcaster::upcast_error;
 use crate::{
-    Aggregate, AggregateContext, DomainEvent, Error, FormatedEvent, FormatedResult, Handlers,
-    MetaData, Store, CQRS,
+    Aggregate, AggregateContext, Compression, CompressionPolicy, CorruptedRecord, DomainEvent,
+    DuplicateVersion, Error, EventFilter, EventStream, FormatedEvent, FormatedResult, Handlers,
+    IdempotencyRecord, Meta, MetaData, Serializer, SnapshotStore, Store, Upcasters, VerifyReport,
+    VersionGap, CQRS,
 };
 
+/// Bytes read from the store file per trip to the blocking pool
+const READ_BUFFER: usize = 64 * 1024;
+
+/// Wait between attempts to take the file lock
+const LOCK_RETRY: Duration = Duration::from_millis(10);
+
+/// First byte of the records of each binary format, JSON lines start with `{`
+const RECORD_TAGS: [(u8, Serializer); 3] = [
+    (1, Serializer::Cbor),
+    (2, Serializer::MessagePack),
+    (3, Serializer::Bincode),
+];
+
+/// Bytes in front of a binary record: its tag, the lengths of its header and
+/// payload, the CRC32 of the tag and lengths, and the CRC32 of header and payload
+const RECORD_FRAME: usize = 17;
+
+/// What `FileEventStore` does with a line it can't decode
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CorruptLinePolicy {
+    /// Reading the store fails with a `CORRUPT` error
+    #[default]
+    Fail,
+    /// The line is skipped with a warning
+    Skip,
+    /// The line is copied to the quarantine file and skipped with a warning
+    Quarantine,
+}
+
+/// Problem a store recovered from on its own
+///
+/// Warnings go to the handler set with `with_warning_handler`, stores without
+/// one don't report them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreWarning {
+    /// A corrupt line was skipped
+    SkippedLine {
+        path: String,
+        line: usize,
+        reason: String,
+    },
+    /// A corrupt line was copied to the quarantine file and skipped
+    QuarantinedLine {
+        path: String,
+        line: usize,
+        reason: String,
+        quarantine_path: String,
+    },
+    /// The side index was missing or didn't match the log, it is rebuilt from the log
+    IndexRebuilt { path: String },
+    /// The side index couldn't be saved, the next read rescans the log
+    IndexNotSaved { path: String, reason: String },
+    /// A torn batch was cut from the end of the log
+    TruncatedBatch { path: String, bytes: u64 },
+    /// The snapshot of an aggregate couldn't be loaded, it is rebuilt from its events
+    SnapshotNotLoaded {
+        aggregate_id: String,
+        reason: String,
+    },
+    /// The snapshot of an aggregate couldn't be saved, its events are stored
+    SnapshotNotSaved {
+        aggregate_id: String,
+        version: usize,
+        reason: String,
+    },
+}
+
+impl fmt::Display for StoreWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreWarning::SkippedLine { path, line, reason } => {
+                write!(f, "skipped corrupt line {} of {}: {}", line, path, reason)
+            }
+            StoreWarning::QuarantinedLine {
+                path,
+                line,
+                reason,
+                quarantine_path,
+            } => write!(
+                f,
+                "quarantined corrupt line {} of {} to {}: {}",
+                line, path, quarantine_path, reason
+            ),
+            StoreWarning::IndexRebuilt { path } => write!(f, "rebuilding index of {}", path),
+            StoreWarning::IndexNotSaved { path, reason } => {
+                write!(f, "index of {} not saved: {}", path, reason)
+            }
+            StoreWarning::TruncatedBatch { path, bytes } => {
+                write!(f, "truncated torn batch of {} bytes from {}", bytes, path)
+            }
+            StoreWarning::SnapshotNotLoaded {
+                aggregate_id,
+                reason,
+            } => write!(f, "snapshot of {} not loaded: {}", aggregate_id, reason),
+            StoreWarning::SnapshotNotSaved {
+                aggregate_id,
+                version,
+                reason,
+            } => write!(
+                f,
+                "snapshot of {} at version {} not saved: {}",
+                aggregate_id, version, reason
+            ),
+        }
+    }
+}
+
+/// Receives the warnings of a store
+pub type WarningHandler = Arc<dyn Fn(&StoreWarning) + Send + Sync>;
+
+/// Line and content checksum of the quarantined lines, loaded from the quarantine file once
+type QuarantineKeys = HashSet<(usize, u32)>;
+
+/// Entry of the quarantine file
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct QuarantinedLine {
+    pub line: usize,
+    pub reason: String,
+    pub content: String,
+}
+
+/// How hard `FileEventStore::append` pushes a batch to the device before returning
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Durability {
+    /// Left in OS buffers, a crash of the machine can lose the batch
+    Buffered,
+    /// Batch data is synced, file metadata may lag
+    #[default]
+    Data,
+    /// Batch data and file metadata are synced
+    Full,
+}
+
+/// Line read from the store file
+///
+/// Lines are unwrapped as soon as they are parsed, so the event isn't boxed.
+#[allow(clippy::large_enum_variant)]
+enum Line<A: Aggregate, E: DomainEvent<A>, M: Meta> {
+    Event(FormatedEvent<A, E, M>),
+    Skipped,
+}
+
+/// Bytes of a line read from the store file
+struct RawLine {
+    bytes: Vec<u8>,
+    /// The end of the file cut the line, a crash stopped its write
+    torn: bool,
+}
+
+/// Why a line can't be turned into an event
+enum Unreadable {
+    /// The line is damaged, the corrupt line policy decides what happens to it
+    Corrupt(String),
+    /// The line is sound but the store can't decode it, like a codec that isn't compiled in
+    Failed(Error),
+}
+
+impl From<String> for Unreadable {
+    fn from(reason: String) -> Unreadable {
+        Unreadable::Corrupt(reason)
+    }
+}
+
+/// Lines of one append, yielded once every line of it has been read
+struct Batch<A: Aggregate, E: DomainEvent<A>, M: Meta> {
+    /// Lines with their byte offset
+    lines: Vec<(u64, Line<A, E, M>)>,
+    /// Line number of the last line
+    last_line: usize,
+    /// Byte offset right after the batch
+    end: u64,
+}
+
+/// Reading state of `stream_batches` and `verify`
+struct Scan<A: Aggregate, E: DomainEvent<A>, M: Meta> {
+    reader: BufReader<File>,
+    position: usize,
+    offset: u64,
+    pending: Vec<(u64, Line<A, E, M>)>,
+    /// Corrupt lines of an audit, which records them instead of applying the corrupt line policy
+    corrupted: Option<Vec<CorruptedRecord>>,
+}
+
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Scan<A, E, M> {
+    /// Scan `file` from byte `offset`, the end of line `position`
+    fn new(file: File, offset: u64, position: usize) -> Scan<A, E, M> {
+        Scan {
+            reader: BufReader::with_capacity(READ_BUFFER, file),
+            position,
+            offset,
+            pending: Vec::new(),
+            corrupted: None,
+        }
+    }
+}
+
+/// Version bookkeeping of `FileEventStore::verify`
+#[derive(Default)]
+struct Audit {
+    report: VerifyReport,
+    /// Last version of each stream
+    versions: HashMap<(String, String), usize>,
+    /// Line each version of each stream was first seen at
+    lines: HashMap<(String, String, usize), usize>,
+}
+
+impl Audit {
+    fn check<A: Aggregate, E: DomainEvent<A>, M: Meta>(&mut self, event: &FormatedEvent<A, E, M>) {
+        self.report.records += 1;
+
+        let stream = (event.aggregate_type.clone(), event.aggregate_id.clone());
+        let key = (stream.0.clone(), stream.1.clone(), event.version);
+
+        if let Some(first_line) = self.lines.get(&key) {
+            self.report.duplicate_versions.push(DuplicateVersion {
+                aggregate_type: event.aggregate_type.clone(),
+                aggregate_id: event.aggregate_id.clone(),
+                version: event.version,
+                first_line: *first_line,
+                line: event.position,
+            });
+            return;
+        }
+        self.lines.insert(key, event.position);
+
+        let last = self.versions.entry(stream).or_insert(0);
+        if event.version > *last + 1 {
+            self.report.version_gaps.push(VersionGap {
+                aggregate_type: event.aggregate_type.clone(),
+                aggregate_id: event.aggregate_id.clone(),
+                line: event.position,
+                expected: *last + 1,
+                found: event.version,
+            });
+        }
+        *last = (*last).max(event.version);
+    }
+}
+
+/// Events of a stream read through the index
+struct IndexedRead {
+    aggregate_id: String,
+    /// Version of the last event read
+    version: usize,
+    /// Lines left to read, loaded from the index on the first read
+    entries: Option<VecDeque<IndexEntry>>,
+    /// Reader and the offset it is at, reused while lines follow each other
+    reader: Option<(BufReader<File>, u64)>,
+    /// The index was rebuilt once already
+    rebuilt: bool,
+}
+
+/// Batch size and index of a line, `None` when the line isn't framed
+type Frame = Option<(usize, usize)>;
+
 /// FileEventStore
 ///
 /// NOTE: Only use the for develpment and not for production
-pub struct FileEventStore<A: Aggregate, E: DomainEvent<A>> {
+///
+/// Clones share the same write lock, so appends made through clones of a
+/// store are checked against each other.
+///
+/// File access goes through `tokio::fs`, which runs it on the blocking pool,
+/// so reading a large store never stalls the executor threads.
+///
+/// Every line records its batch size and index in the batch. Readers only see
+/// complete batches, and appends truncate a torn batch left by a crash before
+/// writing, so a batch is stored whole or not at all.
+///
+/// Writers take an advisory lock on the file, so several processes can append
+/// to the same store. Readers don't lock and run alongside the writer.
+///
+/// A side index, `{path}.index`, maps each stream to the byte offsets of its
+/// lines, so loading an aggregate reads only its records. The index is kept up
+/// to date on append and rebuilt when it is missing or doesn't match the log.
+///
+/// Lines carry a CRC32 of their payload, a mismatch is handled like any
+/// corrupt line. `verify` audits the whole log.
+///
+/// Payloads are encoded with the store's `Serializer`, JSON by default, and
+/// each line names its format so lines of several formats can be read back.
+/// A binary format writes binary records rather than JSON lines: a frame with
+/// the format, the lengths of the header and payload and their CRC32s, then
+/// the header and payload in that format and a newline. Binary records count
+/// as one line each.
+/// Payloads can be compressed above a size threshold, see `CompressionPolicy`.
+/// Lines record the schema version of their event, older ones go through the
+/// store's `Upcasters` when read.
+///
+/// Idempotency keys are remembered in a side file, `{path}.keys`, which drops
+/// expired keys whenever a new one is written. A key is written under the
+/// store locks before its events and only counts once they are stored.
+///
+/// Problems the store recovers from, like skipped lines or a rebuilt index,
+/// are reported to the `WarningHandler` set with `with_warning_handler`.
+pub struct FileEventStore<A: Aggregate, E: DomainEvent<A>, M: Meta = MetaData> {
     path: String,
+    corrupt_line_policy: CorruptLinePolicy,
+    durability: Durability,
+    serializer: Serializer,
+    compression: CompressionPolicy,
+    upcasters: Upcasters,
+    lock_timeout: Duration,
+    warning_handler: Option<WarningHandler>,
+    write_lock: Arc<Mutex<()>>,
+    index: Arc<Mutex<Option<Index>>>,
+    quarantine_keys: Arc<Mutex<Option<QuarantineKeys>>>,
     _a: PhantomData<A>,
     _e: PhantomData<E>,
+    _m: PhantomData<M>,
 }
 
-impl<A: Aggregate, E: DomainEvent<A>> FileEventStore<A, E> {
-    pub fn new(path: &str) -> FileEventStore<A, E> {
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> FileEventStore<A, E, M> {
+    pub fn new(path: &str) -> FileEventStore<A, E, M> {
         FileEventStore {
             path: path.to_owned(),
+            corrupt_line_policy: CorruptLinePolicy::default(),
+            durability: Durability::default(),
+            serializer: Serializer::default(),
+            compression: CompressionPolicy::default(),
+            upcasters: Upcasters::default(),
+            lock_timeout: Duration::from_secs(5),
+            warning_handler: None,
+            write_lock: Arc::new(Mutex::new(())),
+            index: Arc::new(Mutex::new(None)),
+            quarantine_keys: Arc::new(Mutex::new(None)),
             _a: PhantomData,
             _e: PhantomData,
+            _m: PhantomData,
         }
     }
 
+    /// Open the store, truncating a torn batch left at the end of the file
+    pub async fn open(path: &str) -> Result<FileEventStore<A, E, M>, Error> {
+        let store = FileEventStore::new(path);
+        store.recover().await?;
+
+        Ok(store)
+    }
+
     /// Creates CQRS with store
-    pub fn create_cqrs(path: &str, handlers: Handlers<A, E>) -> CQRS<A, E, FileEventStore<A, E>> {
+    pub fn create_cqrs(
+        path: &str,
+        handlers: Handlers<A, E, M>,
+    ) -> CQRS<A, E, FileEventStore<A, E, M>, M> {
         CQRS::new(FileEventStore::new(path), handlers)
     }
 
-    /// Get store file from device
-    pub fn get_file(&self) -> Result<File, Error> {
-        let path = Path::new(&self.path);
+    /// Set what happens to lines that can't be decoded
+    pub fn with_corrupt_line_policy(self, corrupt_line_policy: CorruptLinePolicy) -> Self {
+        FileEventStore {
+            corrupt_line_policy,
+            ..self
+        }
+    }
+
+    /// Set how appends are synced to the device
+    pub fn with_durability(self, durability: Durability) -> Self {
+        FileEventStore { durability, ..self }
+    }
+
+    /// Set the format payloads are appended in, lines already written keep theirs
+    pub fn with_serializer(self, serializer: Serializer) -> Self {
+        FileEventStore { serializer, ..self }
+    }
+
+    /// Set which payloads are compressed, lines already written keep their encoding
+    pub fn with_compression(self, compression: CompressionPolicy) -> Self {
+        FileEventStore {
+            compression,
+            ..self
+        }
+    }
+
+    /// Set the upcasters applied to lines of an older schema version
+    pub fn with_upcasters(self, upcasters: Upcasters) -> Self {
+        FileEventStore { upcasters, ..self }
+    }
+
+    /// Set how long appends wait for another process to release the file lock
+    pub fn with_lock_timeout(self, lock_timeout: Duration) -> Self {
+        FileEventStore {
+            lock_timeout,
+            ..self
+        }
+    }
+
+    /// Set the handler receiving warnings, see `StoreWarning`
+    pub fn with_warning_handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(&StoreWarning) + Send + Sync + 'static,
+    {
+        self.with_warnings(Some(Arc::new(handler)))
+    }
+
+    /// Set or clear the handler receiving warnings
+    pub(crate) fn with_warnings(self, warning_handler: Option<WarningHandler>) -> Self {
+        FileEventStore {
+            warning_handler,
+            ..self
+        }
+    }
+
+    /// Pass `warning` to the warning handler, if there is one
+    fn warn(&self, warning: StoreWarning) {
+        if let Some(handler) = &self.warning_handler {
+            handler(&warning);
+        }
+    }
+
+    /// Side file mapping streams to byte offsets
+    pub fn index_path(&self) -> String {
+        format!("{}.index", self.path)
+    }
 
-        return match OpenOptions::new().read(true).append(true).open(path) {
+    /// Side file remembering idempotency keys
+    pub fn keys_path(&self) -> String {
+        format!("{}.keys", self.path)
+    }
+
+    /// Side file receiving quarantined lines
+    pub fn quarantine_path(&self) -> String {
+        format!("{}.quarantine", self.path)
+    }
+
+    /// Get store file from device, creating it when missing
+    pub async fn get_file(&self) -> Result<File, Error> {
+        OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| {
+                Error::file(
+                    "FileEventStore: cannot open store",
+                    &self.path,
+                    None,
+                    &e.to_string(),
+                )
+            })
+    }
+
+    /// Open the store file holding its write lock, released when the file is dropped
+    ///
+    /// Returns `Error::locked` if another process holds the lock past the lock timeout
+    async fn lock_file(&self) -> Result<File, Error> {
+        let file = self.get_file().await?;
+        lock_exclusive(file, &self.path, self.lock_timeout).await
+    }
+
+    /// Open the store file for reading, sealed segments of a `SegmentedEventStore` are read-only
+    async fn open_read(&self) -> Result<File, Error> {
+        match File::open(&self.path).await {
             Ok(file) => Ok(file),
-            Err(e) => match e.kind() {
-                io::ErrorKind::NotFound => match File::create(path) {
-                    Ok(file) => Ok(file),
-                    _ => {
-                        panic!("Oops! Error create store")
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.get_file().await,
+            Err(e) => Err(Error::file(
+                "FileEventStore: cannot open store",
+                &self.path,
+                None,
+                &e.to_string(),
+            )),
+        }
+    }
+
+    /// Stream the complete batches of the store file, applying the corrupt line policy
+    ///
+    /// Lines of a batch that is still being written, or was torn by a crash, are never yielded
+    fn stream_batches(&self) -> BoxStream<'_, Result<Batch<A, E, M>, Error>> {
+        self.stream_batches_from(0, 0)
+    }
+
+    /// Stream the complete batches after byte `offset`, which is the end of line `position`
+    fn stream_batches_from(
+        &self,
+        offset: u64,
+        position: usize,
+    ) -> BoxStream<'_, Result<Batch<A, E, M>, Error>> {
+        let open = async move {
+            let mut file = self.open_read().await?;
+            file.seek(SeekFrom::Start(offset)).await.map_err(|e| {
+                Error::file(
+                    "FileEventStore: cannot read store",
+                    &self.path,
+                    Some(position + 1),
+                    &e.to_string(),
+                )
+            })?;
+
+            Ok::<_, Error>(file)
+        };
+
+        stream::once(open)
+            .map_ok(move |file| {
+                let scan = Scan::new(file, offset, position);
+
+                stream::unfold(Some(scan), move |state| async move {
+                    let mut scan = state?;
+
+                    match self.next_batch(&mut scan).await {
+                        Ok(Some(batch)) => Some((Ok(batch), Some(scan))),
+                        Ok(None) => None,
+                        Err(e) => Some((Err(e), None)),
+                    }
+                })
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Read lines until a batch is complete, `None` at the end of the committed data
+    async fn next_batch(&self, scan: &mut Scan<A, E, M>) -> Result<Option<Batch<A, E, M>>, Error> {
+        loop {
+            // Position is the line number
+            let position = scan.position + 1;
+            let bytes = match self.read_line(&mut scan.reader, position).await? {
+                Some(RawLine { bytes, torn: false }) => bytes,
+                // Only the last line can be cut, by a crash
+                Some(RawLine { bytes, torn: true }) => {
+                    scan.offset += bytes.len() as u64;
+                    return Ok(None);
+                }
+                None => return Ok(None),
+            };
+
+            let (line, frame) = match FileData::<M>::parse(&bytes, position, &self.upcasters) {
+                Ok(parsed) => parsed,
+                Err(Unreadable::Failed(e)) => return Err(e),
+                Err(Unreadable::Corrupt(reason)) => match scan.corrupted.as_mut() {
+                    Some(corrupted) => {
+                        corrupted.push(CorruptedRecord {
+                            line: position,
+                            reason,
+                        });
+                        (Line::Skipped, None)
+                    }
+                    None => {
+                        let line = String::from_utf8_lossy(&bytes);
+                        (self.corrupt_line(&line, position, &reason).await?, None)
                     }
                 },
-                _ => {
-                    panic!("Oops! Error open store")
+            };
+
+            // A batch followed by another one was committed, even if it looks short
+            let mut committed = None;
+            if frame.is_some_and(|(_, index)| index == 0) && !scan.pending.is_empty() {
+                committed = Some(Batch {
+                    lines: scan.pending.drain(..).collect(),
+                    last_line: scan.position,
+                    end: scan.offset,
+                });
+            }
+
+            let complete = match frame {
+                Some((size, index)) => index + 1 >= size,
+                None => scan.pending.is_empty(),
+            };
+
+            scan.pending.push((scan.offset, line));
+            scan.position = position;
+            scan.offset += bytes.len() as u64;
+
+            if committed.is_some() && !complete {
+                return Ok(committed);
+            }
+
+            if complete {
+                let batch = Batch {
+                    lines: scan.pending.drain(..).collect(),
+                    last_line: scan.position,
+                    end: scan.offset,
+                };
+
+                // The lines of the earlier batch are put first
+                return Ok(Some(match committed {
+                    Some(mut earlier) => {
+                        earlier.lines.extend(batch.lines);
+                        Batch {
+                            lines: earlier.lines,
+                            ..batch
+                        }
+                    }
+                    None => batch,
+                }));
+            }
+        }
+    }
+
+    /// Stream the lines of the store file
+    fn stream_lines(&self) -> BoxStream<'_, Result<Line<A, E, M>, Error>> {
+        self.stream_batches()
+            .map_ok(|batch| stream::iter(batch.lines.into_iter().map(|(_, line)| Ok(line))))
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Read line `position`, `None` at the end of the file
+    ///
+    /// Binary records are read to the end their frame gives, their bytes may hold
+    /// newlines. A record with a damaged frame runs to the next newline.
+    async fn read_line(
+        &self,
+        reader: &mut BufReader<File>,
+        position: usize,
+    ) -> Result<Option<RawLine>, Error> {
+        let read_error = |e: io::Error| {
+            Error::file(
+                "FileEventStore: cannot read store",
+                &self.path,
+                Some(position),
+                &e.to_string(),
+            )
+        };
+
+        let mut bytes = Vec::new();
+        let read = (&mut *reader)
+            .take(1)
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(read_error)?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        if tag_format(bytes[0]).is_some() {
+            (&mut *reader)
+                .take(RECORD_FRAME as u64 - 1)
+                .read_to_end(&mut bytes)
+                .await
+                .map_err(read_error)?;
+            if bytes.len() < RECORD_FRAME {
+                return Ok(Some(RawLine { bytes, torn: true }));
+            }
+
+            if let Some((header, payload)) = frame_lengths(&bytes) {
+                let rest = (header + payload + 1) as u64;
+                let read = (&mut *reader)
+                    .take(rest)
+                    .read_to_end(&mut bytes)
+                    .await
+                    .map_err(read_error)?;
+                if (read as u64) < rest {
+                    return Ok(Some(RawLine { bytes, torn: true }));
                 }
-            },
-        };
-    }
-
-    /// Find Events from store
-    async fn read_file(&self) -> FormatedResult<A, E> {
-        let mut file = self.get_file()?;
-        let mut content = String::new();
-        file.read_to_string(&mut content)?;
-
-        let mut events = Vec::new();
-        if content.len() > 0 {
-            events = content
-                .lines()
-                .map(|e| {
-                    let data: FileData = serde_json::from_str(&e).unwrap();
-                    let payload: E = serde_json::from_str(&data.payload).unwrap();
-
-                    return FormatedEvent::new(
-                        data.aggregate_id,
-                        data.aggregate_type,
-                        data.version,
-                        payload,
-                        data.meta,
-                        Some(&data.created_at),
-                    );
-                })
-                .collect();
+                if bytes.ends_with(b"\n") {
+                    return Ok(Some(RawLine { bytes, torn: false }));
+                }
+            }
+        }
+
+        if !bytes.ends_with(b"\n") {
+            reader
+                .read_until(b'\n', &mut bytes)
+                .await
+                .map_err(read_error)?;
+        }
+        let torn = !bytes.ends_with(b"\n");
+
+        Ok(Some(RawLine { bytes, torn }))
+    }
+
+    /// Bring the index up to date with the log, loading or rebuilding it first if needed
+    async fn refresh_index<'s>(&self, slot: &'s mut Option<Index>) -> Result<&'s mut Index, Error> {
+        let read_error = |e: io::Error| {
+            Error::file(
+                "FileEventStore: cannot read store",
+                &self.path,
+                None,
+                &e.to_string(),
+            )
+        };
+        let mut file = self.open_read().await?;
+        let len = file.metadata().await.map_err(read_error)?.len();
+
+        if slot.is_none() {
+            *slot = Index::load(&self.index_path()).await;
+        }
+
+        // The log was cut or rewritten behind the index
+        let stale = match slot {
+            Some(index) => {
+                index.end > len
+                    || !is_line_end(&mut file, index.end)
+                        .await
+                        .map_err(read_error)?
+            }
+            None => true,
+        };
+        if stale {
+            if len > 0 {
+                self.warn(StoreWarning::IndexRebuilt {
+                    path: self.path.clone(),
+                });
+            }
+            *slot = Some(Index::rebuilt());
+        }
+        let index = slot.get_or_insert_with(Index::rebuilt);
+
+        // Batches appended since, by this process or another one
+        if index.end < len {
+            let mut batches = self.stream_batches_from(index.end, index.lines);
+            while let Some(batch) = batches.try_next().await? {
+                let events = batch
+                    .lines
+                    .into_iter()
+                    .filter_map(|(offset, line)| match line {
+                        Line::Event(e) => Some(IndexedEvent {
+                            aggregate_type: e.aggregate_type,
+                            aggregate_id: e.aggregate_id,
+                            version: e.version,
+                            offset,
+                            line: e.position,
+                        }),
+                        Line::Skipped => None,
+                    })
+                    .collect();
+
+                index.add(batch.end, batch.last_line, events);
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Save the index, the log stays the source of truth so a failure only costs a rescan
+    async fn save_index(&self, index: &mut Index) {
+        if let Err(e) = index.save(&self.index_path()).await {
+            let reason = e
+                .extension()
+                .and_then(|extension| extension.get("reason"))
+                .map(String::as_str)
+                .unwrap_or_else(|| e.message());
+
+            self.warn(StoreWarning::IndexNotSaved {
+                path: self.path.clone(),
+                reason: reason.to_string(),
+            });
+        }
+    }
+
+    /// Stream the events of a stream after `version` through the index, a line at a time
+    ///
+    /// The stream holds a clone of the store, so it doesn't borrow it
+    pub(crate) fn stream_indexed<'a>(
+        &self,
+        aggregate_id: &str,
+        version: usize,
+    ) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        let read = IndexedRead {
+            aggregate_id: aggregate_id.to_string(),
+            version,
+            entries: None,
+            reader: None,
+            rebuilt: false,
+        };
+
+        stream::unfold(Some((self.clone(), read)), |state| async move {
+            let (store, mut read) = state?;
+
+            match store.next_indexed(&mut read).await {
+                Ok(Some(event)) => Some((Ok(event), Some((store, read)))),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+        .boxed()
+    }
+
+    /// Read the next event of an indexed read, `None` at the end of the stream
+    async fn next_indexed(
+        &self,
+        read: &mut IndexedRead,
+    ) -> Result<Option<FormatedEvent<A, E, M>>, Error> {
+        loop {
+            if read.entries.is_none() {
+                let mut index = self.index.lock().await;
+                let index = self.refresh_index(&mut index).await?;
+                let version = read.version;
+
+                read.entries = Some(
+                    index
+                        .stream(A::aggregate_type(), &read.aggregate_id)
+                        .iter()
+                        .filter(|entry| entry.version > version)
+                        .copied()
+                        .collect(),
+                );
+            }
+
+            let entry = match read.entries.as_mut().and_then(VecDeque::pop_front) {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            if let Some(event) = self.read_entry(read, &entry).await? {
+                read.version = event.version;
+                return Ok(Some(event));
+            }
+
+            // A record that doesn't match the index means the log changed behind it,
+            // the read goes on after the last event returned
+            if read.rebuilt {
+                return Err(Error::file(
+                    "FileEventStore: index doesn't match store",
+                    &self.path,
+                    None,
+                    "store changed while it was read",
+                ));
+            }
+
+            *self.index.lock().await = Some(Index::rebuilt());
+            read.rebuilt = true;
+            read.entries = None;
+            read.reader = None;
+        }
+    }
+
+    /// Read the line of `entry`, `None` if it isn't the event the index expects
+    async fn read_entry(
+        &self,
+        read: &mut IndexedRead,
+        entry: &IndexEntry,
+    ) -> Result<Option<FormatedEvent<A, E, M>>, Error> {
+        // Seeking drops the buffered bytes along with the reader
+        let mut reader = match read.reader.take() {
+            Some((reader, cursor)) if cursor == entry.offset => reader,
+            reader => {
+                let mut file = match reader {
+                    Some((reader, _)) => reader.into_inner(),
+                    None => self.open_read().await?,
+                };
+                file.seek(SeekFrom::Start(entry.offset))
+                    .await
+                    .map_err(|e| {
+                        Error::file(
+                            "FileEventStore: cannot read store",
+                            &self.path,
+                            Some(entry.line),
+                            &e.to_string(),
+                        )
+                    })?;
+                BufReader::new(file)
+            }
+        };
+
+        let bytes = match self.read_line(&mut reader, entry.line).await? {
+            Some(RawLine { bytes, torn: false }) => bytes,
+            _ => return Ok(None),
+        };
+        read.reader = Some((reader, entry.offset + bytes.len() as u64));
+
+        match FileData::<M>::parse::<A, E>(&bytes, entry.line, &self.upcasters) {
+            Ok((Line::Event(event), _))
+                if event.aggregate_id == read.aggregate_id
+                    && event.aggregate_type == A::aggregate_type()
+                    && event.version == entry.version =>
+            {
+                Ok(Some(event))
+            }
+            Err(Unreadable::Failed(e)) => Err(e),
+            _ => Ok(None),
+        }
+    }
+
+    /// Cut the file back to `end`, dropping a torn batch
+    ///
+    /// Returns the number of bytes removed
+    async fn truncate(&self, file: &mut File, end: u64) -> Result<u64, Error> {
+        let write_error = |e: io::Error| {
+            Error::file(
+                "FileEventStore: cannot truncate store",
+                &self.path,
+                None,
+                &e.to_string(),
+            )
+        };
+
+        let len = file.metadata().await.map_err(write_error)?.len();
+        if len <= end {
+            return Ok(0);
+        }
+
+        file.set_len(end).await.map_err(write_error)?;
+        file.sync_all().await.map_err(write_error)?;
+        self.warn(StoreWarning::TruncatedBatch {
+            path: self.path.clone(),
+            bytes: len - end,
+        });
+
+        Ok(len - end)
+    }
+
+    /// Truncate a torn batch left at the end of the file by a crash
+    ///
+    /// Returns the number of bytes removed
+    pub async fn recover(&self) -> Result<u64, Error> {
+        let _guard = self.write_lock.lock().await;
+        let mut file = self.lock_file().await?;
+        let mut index = self.index.lock().await;
+        let index = self.refresh_index(&mut index).await?;
+
+        let removed = self.truncate(&mut file, index.end).await?;
+        self.save_index(index).await;
+
+        Ok(removed)
+    }
+
+    /// Append events to a stream whose earlier events may live in other files
+    ///
+    /// `previous_version` is the version of the stream before this file, the
+    /// stream's own lines take over once it has some. The idempotency key of
+    /// `context` is remembered in `keys`, checked against the events of `store`.
+    pub(crate) async fn append_after<S: Store<A, E, M>>(
+        &self,
+        events: Vec<E>,
+        context: &AggregateContext<A>,
+        meta: M,
+        previous_version: usize,
+        keys: &str,
+        store: &S,
+    ) -> FormatedResult<A, E, M> {
+        let mut formated_events = FormatedEvent::create_in(context, events, meta);
+
+        if formated_events.is_empty() && context.idempotency.is_none() {
+            return Ok(Vec::default());
+        }
+
+        // Hold the write locks until the events are written so the version check stays valid,
+        // the file lock keeps out other processes
+        let _guard = self.write_lock.lock().await;
+        let mut file = self.lock_file().await?;
+
+        let keys = KeyFile { path: keys };
+        if let Some(idempotency) = &context.idempotency {
+            if keys.written(store, &idempotency.key).await?.is_some() {
+                return Err(idempotency.duplicate::<A>());
+            }
+            if formated_events.is_empty() {
+                keys.put(idempotency.record(context, &formated_events))
+                    .await?;
+                return Ok(formated_events);
+            }
+        }
+
+        let mut index = self.index.lock().await;
+        let index = self.refresh_index(&mut index).await?;
+        let version = match index.version(A::aggregate_type(), &context.id) {
+            0 => previous_version,
+            version => version,
+        };
+
+        // Check expected version against the stored stream
+        if version != context.version {
+            return Err(Error::conflict(&context.id, context.version, version));
+        }
+
+        // The key goes before the events, it only counts once they follow
+        if let Some(idempotency) = &context.idempotency {
+            keys.put(idempotency.record(context, &formated_events))
+                .await?;
+        }
+
+        let size = formated_events.len();
+        let mut data = Vec::new();
+        let mut indexed = Vec::with_capacity(size);
+        for (i, event) in formated_events.iter_mut().enumerate() {
+            event.position = index.lines + i + 1;
+            indexed.push(IndexedEvent {
+                aggregate_type: event.aggregate_type.clone(),
+                aggregate_id: event.aggregate_id.clone(),
+                version: event.version,
+                offset: index.end + data.len() as u64,
+                line: event.position,
+            });
+
+            data.extend(FileData::line(
+                event,
+                size,
+                i,
+                self.serializer,
+                self.compression,
+            )?);
+            data.push(b'\n');
+        }
+
+        // Insert into store
+        let write_error = |e: io::Error| {
+            Error::file(
+                "FileEventStore: cannot write store",
+                &self.path,
+                None,
+                &e.to_string(),
+            )
+        };
+        self.truncate(&mut file, index.end).await?;
+        file.write_all(&data).await.map_err(write_error)?;
+        file.flush().await.map_err(write_error)?;
+
+        match self.durability {
+            Durability::Buffered => {}
+            Durability::Data => file.sync_data().await.map_err(write_error)?,
+            Durability::Full => file.sync_all().await.map_err(write_error)?,
+        }
+
+        index.add(index.end + data.len() as u64, index.lines + size, indexed);
+        self.save_index(index).await;
+
+        Ok(formated_events)
+    }
+
+    /// Last version of a stream in this file, `0` if it has no events here
+    pub(crate) async fn version(&self, aggregate_id: &str) -> Result<usize, Error> {
+        let mut index = self.index.lock().await;
+        let index = self.refresh_index(&mut index).await?;
+
+        Ok(index.version(A::aggregate_type(), aggregate_id))
+    }
+
+    /// Bytes and lines of the committed part of the file
+    pub(crate) async fn committed(&self) -> Result<(u64, usize), Error> {
+        let mut index = self.index.lock().await;
+        let index = self.refresh_index(&mut index).await?;
+
+        Ok((index.end, index.lines))
+    }
+
+    /// Write a copy of the file to `dest` without the events covered by `snapshots`
+    ///
+    /// Dropped events leave a blank line so positions don't move, and the last
+    /// event of each stream is kept so the file still knows the stream version.
+    /// Skipped corrupt lines are blanked too. Returns the number of events
+    /// dropped, and the highest version dropped of each stream that lost some.
+    pub(crate) async fn compact_into<S: SnapshotStore<A>>(
+        &self,
+        dest: &str,
+        snapshots: &S,
+    ) -> Result<(usize, HashMap<String, usize>), Error> {
+        let mut last = HashMap::new();
+        let mut batches = self.stream_batches();
+        while let Some(batch) = batches.try_next().await? {
+            for (_, line) in batch.lines {
+                if let Line::Event(e) = line {
+                    if e.aggregate_type == A::aggregate_type() {
+                        last.insert(e.aggregate_id, e.version);
+                    }
+                }
+            }
+        }
+
+        let mut covered = HashMap::new();
+        for aggregate_id in last.keys() {
+            if let Some(snapshot) = snapshots.load(aggregate_id).await? {
+                covered.insert(aggregate_id.clone(), snapshot.version);
+            }
+        }
+        let dropped = |e: &FormatedEvent<A, E, M>| {
+            e.aggregate_type == A::aggregate_type()
+                && covered
+                    .get(&e.aggregate_id)
+                    .is_some_and(|v| e.version <= *v)
+                && last.get(&e.aggregate_id) != Some(&e.version)
+        };
+
+        let write_error = |e: io::Error| {
+            Error::file(
+                "FileEventStore: cannot write compacted store",
+                dest,
+                None,
+                &e.to_string(),
+            )
+        };
+        let mut file = File::create(dest).await.map_err(write_error)?;
+        let (mut count, mut floors) = (0, HashMap::new());
+
+        let mut batches = self.stream_batches();
+        while let Some(batch) = batches.try_next().await? {
+            let size = batch
+                .lines
+                .iter()
+                .filter(|(_, line)| matches!(line, Line::Event(e) if !dropped(e)))
+                .count();
+
+            // Kept events are framed as a smaller batch, blank lines join it
+            let mut data = Vec::new();
+            let mut index = 0;
+            for (_, line) in batch.lines {
+                match line {
+                    Line::Event(e) if !dropped(&e) => {
+                        data.extend(FileData::line(
+                            &e,
+                            size,
+                            index,
+                            self.serializer,
+                            self.compression,
+                        )?);
+                        index += 1;
+                    }
+                    Line::Event(e) => {
+                        count += 1;
+                        let floor = floors.entry(e.aggregate_id).or_insert(0);
+                        *floor = e.version.max(*floor);
+                    }
+                    Line::Skipped => {}
+                }
+                data.push(b'\n');
+            }
+
+            file.write_all(&data).await.map_err(write_error)?;
+        }
+
+        file.flush().await.map_err(write_error)?;
+        file.sync_all().await.map_err(write_error)?;
+
+        Ok((count, floors))
+    }
+
+    /// Scan the whole log and report corrupt records, version gaps and duplicate versions
+    ///
+    /// Corrupt lines are reported whatever the corrupt line policy is
+    pub async fn verify(&self) -> Result<VerifyReport, Error> {
+        let mut scan = Scan::new(self.open_read().await?, 0, 0);
+        scan.corrupted = Some(Vec::new());
+        let mut audit = Audit::default();
+        let mut committed = 0;
+
+        while let Some(batch) = self.next_batch(&mut scan).await? {
+            for (_, line) in batch.lines.iter() {
+                if let Line::Event(event) = line {
+                    audit.check(event);
+                }
+            }
+            committed = batch.end;
+        }
+
+        // Lines of a batch cut by a crash, and a torn last line
+        audit.report.torn_bytes = scan.offset - committed;
+        audit.report.corrupted = scan.corrupted.unwrap_or_default();
+        Ok(audit.report)
+    }
+
+    /// Apply the corrupt line policy to line `position`
+    async fn corrupt_line(
+        &self,
+        line: &str,
+        position: usize,
+        reason: &str,
+    ) -> Result<Line<A, E, M>, Error> {
+        match self.corrupt_line_policy {
+            CorruptLinePolicy::Fail => Err(Error::corrupt(&self.path, position, reason)),
+            CorruptLinePolicy::Skip => {
+                self.warn(StoreWarning::SkippedLine {
+                    path: self.path.clone(),
+                    line: position,
+                    reason: reason.to_string(),
+                });
+                Ok(Line::Skipped)
+            }
+            CorruptLinePolicy::Quarantine => {
+                self.quarantine(line, position, reason).await?;
+                self.warn(StoreWarning::QuarantinedLine {
+                    path: self.path.clone(),
+                    line: position,
+                    reason: reason.to_string(),
+                    quarantine_path: self.quarantine_path(),
+                });
+                Ok(Line::Skipped)
+            }
         }
+    }
+
+    /// Copy line `position` to the quarantine file, once
+    async fn quarantine(&self, line: &str, position: usize, reason: &str) -> Result<(), Error> {
+        let path = self.quarantine_path();
+        let file_error = |e: io::Error| {
+            Error::file(
+                "FileEventStore: cannot write quarantine",
+                &path,
+                None,
+                &e.to_string(),
+            )
+        };
+
+        let content = line.trim_end_matches('\n');
+
+        // Every read of the store meets the line again, a line rewritten since is quarantined anew
+        let mut quarantined = self.quarantine_keys.lock().await;
+        if quarantined.is_none() {
+            *quarantined = Some(
+                self.quarantined()
+                    .await?
+                    .iter()
+                    .map(|entry| (entry.line, crc32fast::hash(entry.content.as_bytes())))
+                    .collect(),
+            );
+        }
+        let seen = quarantined.get_or_insert_with(HashSet::new);
+        let key = (position, crc32fast::hash(content.as_bytes()));
+        if seen.contains(&key) {
+            return Ok(());
+        }
+
+        let entry = QuarantinedLine {
+            line: position,
+            reason: reason.to_string(),
+            content: content.to_string(),
+        };
+        let mut data = serde_json::to_string(&entry)?;
+        data.push('\n');
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .await
+            .map_err(file_error)?;
+        file.write_all(data.as_bytes()).await.map_err(file_error)?;
+        file.flush().await.map_err(file_error)?;
+        seen.insert(key);
+
+        Ok(())
+    }
+
+    /// Lines copied to the quarantine file so far
+    pub async fn quarantined(&self) -> Result<Vec<QuarantinedLine>, Error> {
+        let path = self.quarantine_path();
 
-        Ok(events)
+        let data = match fs::read_to_string(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(Error::file(
+                    "FileEventStore: cannot read quarantine",
+                    &path,
+                    None,
+                    &e.to_string(),
+                ))
+            }
+        };
+
+        data.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|e| Error::corrupt(&path, i + 1, &e.to_string()))
+            })
+            .collect()
+    }
+
+    /// Stream Events from store, reading the file one line at a time
+    fn stream_file(&self) -> EventStream<'_, A, E, M> {
+        self.stream_lines()
+            .try_filter_map(|line| {
+                ready(Ok(match line {
+                    Line::Event(event) => Some(event),
+                    Line::Skipped => None,
+                }))
+            })
+            .boxed()
+    }
+
+    /// Stream Events from store matching `filter`
+    fn filtered(&self, filter: EventFilter) -> EventStream<'_, A, E, M> {
+        self.stream_file()
+            .try_filter(move |e| ready(filter.matches(e)))
+            .boxed()
     }
 }
 
-impl<A: Aggregate, E: DomainEvent<A>> Clone for FileEventStore<A, E> {
-    fn clone(&self) -> FileEventStore<A, E> {
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Clone for FileEventStore<A, E, M> {
+    fn clone(&self) -> FileEventStore<A, E, M> {
         FileEventStore {
             path: self.path.clone(),
+            corrupt_line_policy: self.corrupt_line_policy,
+            durability: self.durability,
+            serializer: self.serializer,
+            compression: self.compression,
+            upcasters: self.upcasters.clone(),
+            lock_timeout: self.lock_timeout,
+            warning_handler: self.warning_handler.clone(),
+            write_lock: self.write_lock.clone(),
+            index: self.index.clone(),
+            quarantine_keys: self.quarantine_keys.clone(),
             _a: PhantomData,
             _e: PhantomData,
+            _m: PhantomData,
         }
     }
 }
 
 #[async_trait]
-impl<A: Aggregate, E: DomainEvent<A>> Store<A, E> for FileEventStore<A, E> {
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Store<A, E, M> for FileEventStore<A, E, M> {
     /// Rebuilding the aggregate
     async fn assemble_aggregate(&self, id: Option<String>) -> Result<AggregateContext<A>, Error> {
         let mut context = AggregateContext::default();
@@ -103,8 +1332,9 @@ impl<A: Aggregate, E: DomainEvent<A>> Store<A, E> for FileEventStore<A, E> {
 
         // Populate aggregate if id is provided
         if let Some(x) = id {
-            for fmt_event in self.retrieve(x.as_str()).await? {
-                fmt_event.payload.clone().apply(&mut context.aggregate);
+            let mut events = self.stream(x.as_str());
+            while let Some(fmt_event) = events.try_next().await? {
+                fmt_event.payload.apply(&mut context.aggregate);
                 context.version = fmt_event.version;
             }
         }
@@ -116,78 +1346,478 @@ impl<A: Aggregate, E: DomainEvent<A>> Store<A, E> for FileEventStore<A, E> {
     async fn append(
         &self,
         events: Vec<E>,
-        context: AggregateContext<A>,
-        meta: MetaData,
-    ) -> FormatedResult<A, E> {
-        let formated_events =
-            FormatedEvent::create_many(context.id.as_str(), context.version, events, meta);
+        context: &AggregateContext<A>,
+        meta: M,
+    ) -> FormatedResult<A, E, M> {
+        let keys = self.keys_path();
+        self.append_after(events, context, meta, 0, &keys, self)
+            .await
+    }
 
-        if formated_events.len() == 0 {
-            return Ok(Vec::default());
-        }
+    /// Retrive Events for command store, reading only the lines of the stream
+    async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<A, E, M> {
+        self.stream_indexed(aggregate_id, 0).try_collect().await
+    }
 
-        let data =
-            formated_events
-                .iter()
-                .fold(String::default(), |acc, cur| {
-                    match serde_json::to_string(&cur) {
-                        Ok(e) => format!("{}\n", e),
-                        _ => acc,
-                    }
-                });
+    /// Stream Events for command store, reading only the lines of the stream
+    fn stream<'a>(&'a self, aggregate_id: &'a str) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        self.stream_indexed(aggregate_id, 0)
+    }
 
-        if data.len() == 0 {
-            return Ok(Vec::default());
+    /// Retrive Events of an aggregate after `version`
+    async fn retrieve_from(&self, aggregate_id: &str, version: usize) -> FormatedResult<A, E, M> {
+        self.stream_indexed(aggregate_id, version)
+            .try_collect()
+            .await
+    }
+
+    /// Stream Events of an aggregate after `version`
+    fn stream_from<'a>(&'a self, aggregate_id: &'a str, version: usize) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        self.stream_indexed(aggregate_id, version)
+    }
+
+    /// Read events from global `position` on, ordered by position
+    async fn read_all(
+        &self,
+        position: usize,
+        aggregate_type: Option<&str>,
+    ) -> FormatedResult<A, E, M> {
+        let mut filter = EventFilter::new().from_position(position);
+        if let Some(aggregate_type) = aggregate_type {
+            filter = filter.aggregate_type(aggregate_type);
         }
 
-        // Insert into store
-        let mut file = self.get_file()?;
-        file.write_all(data.as_bytes())?;
+        self.filtered(filter).try_collect().await
+    }
 
-        println!("[FileEventStore: Events Appended]\n");
-        Ok(formated_events)
+    /// Retrive Events matching `filter`, ordered by position
+    async fn retrieve_filtered(&self, filter: &EventFilter) -> FormatedResult<A, E, M> {
+        self.stream_filtered(filter).try_collect().await
     }
 
-    /// Retrive Events for command store
-    async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<A, E> {
-        let events = self.read_file().await?;
-        let mut filtered_events = Vec::new();
+    /// Stream Events matching `filter`, ordered by position
+    fn stream_filtered<'a>(&'a self, filter: &'a EventFilter) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        self.filtered(filter.clone())
+    }
 
-        for e in events.iter() {
-            if e.aggregate_id == aggregate_id && e.aggregate_type == A::aggregate_type() {
-                filtered_events.push(e.clone());
-            }
+    /// Retrive Events for query
+    ///
+    /// Returns the events of `aggregate_id` when provided, otherwise every event of the aggregate type
+    async fn retrieve_for_query(&self, aggregate_id: Option<&str>) -> FormatedResult<A, E, M> {
+        self.stream_for_query(aggregate_id).try_collect().await
+    }
+
+    /// Stream Events for query
+    fn stream_for_query<'a>(&'a self, aggregate_id: Option<&'a str>) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        match aggregate_id {
+            Some(id) => self.stream(id),
+            None => self.filtered(EventFilter::new().aggregate_type(A::aggregate_type())),
         }
+    }
 
-        Ok(filtered_events)
+    /// Live outcome remembered under idempotency `key`
+    async fn remembered(&self, key: &str) -> Result<Option<IdempotencyRecord>, Error> {
+        let keys = self.keys_path();
+        KeyFile { path: &keys }.written(self, key).await
     }
+}
 
-    /// Retrive Events for query
-    async fn retrieve_for_query(&self, aggregate_id: Option<&str>) -> FormatedResult<A, E> {
-        let events = self.read_file().await?;
-        let mut filtered_events = Vec::new();
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct FileData<M> {
+    /// Missing on lines written before event ids
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub causation_id: Option<String>,
+    pub aggregate_id: String,
+    pub aggregate_type: String,
+    pub version: usize,
+    /// JSON payloads are inlined, compressed ones are a base64 string, and lines
+    /// written before formats hold the JSON text as a string
+    pub payload: Box<RawValue>,
+    /// Format of `payload`, missing on lines written before formats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<Serializer>,
+    /// Codec `payload` was compressed with, missing when it isn't
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// `DomainEvent::schema_version` of the payload, missing on lines written before versions
+    #[serde(default)]
+    pub schema_version: Option<usize>,
+    pub meta: M,
+    pub created_at: String,
+    /// Events written by the same append, `0` for lines written before batches were framed
+    #[serde(default)]
+    pub batch_size: usize,
+    #[serde(default)]
+    pub batch_index: usize,
+    /// CRC32 of the stored payload bytes, missing on lines written before checksums
+    #[serde(default)]
+    pub checksum: Option<u32>,
+}
+
+impl<M: Meta> FileData<M> {
+    /// Parse a line of the store file into an event at `position` and its batch frame,
+    /// upcasting payloads of an older schema version
+    ///
+    /// Blank lines are skipped, damaged lines give the reason
+    fn parse<A: Aggregate, E: DomainEvent<A>>(
+        bytes: &[u8],
+        position: usize,
+        upcasters: &Upcasters,
+    ) -> Result<(Line<A, E, M>, Frame), Unreadable> {
+        let (header, format, stored) = match bytes.first().copied().and_then(tag_format) {
+            Some(format) => RecordHeader::read(format, bytes)?,
+            None => {
+                // Invalid UTF-8 is left to the corrupt line policy
+                let line = String::from_utf8_lossy(bytes);
+                if line.trim().is_empty() {
+                    return Ok((Line::Skipped, None));
+                }
+                FileData::read(&line)?
+            }
+        };
 
-        let id = match aggregate_id {
-            Some(i) => i,
-            None => "",
+        // The checksum vouches for the stored bytes, a codec failure isn't damage to the line
+        let bytes = match header.compression {
+            Some(compression) => compression
+                .decompress(&stored)
+                .map_err(Unreadable::Failed)?,
+            None => stored,
         };
+        // Lines older or newer than the event need upcasters, which can fail on sound lines
+        let payload: E = match header.schema_version.unwrap_or(1) {
+            version if version == E::schema_version() => format.decode(&bytes)?,
+            version if !format.is_self_describing() => {
+                let reason = format!("{} payloads don't record their shape", format.name());
+                return Err(Unreadable::Failed(upcast_error(
+                    version,
+                    E::schema_version(),
+                    &reason,
+                )));
+            }
+            version => upcasters
+                .deserialize::<A, E>(format.decode(&bytes)?, version)
+                .map_err(Unreadable::Failed)?,
+        };
+
+        let mut event = FormatedEvent::new(
+            header.aggregate_id,
+            header.aggregate_type,
+            header.version,
+            payload,
+            header.meta,
+            Some(&header.created_at),
+        );
+        event.position = position;
+        event.restore_ids(header.event_id, header.correlation_id, header.causation_id);
 
-        for e in events.iter() {
-            if e.aggregate_id == id || e.aggregate_type == A::aggregate_type() {
-                filtered_events.push(e.clone());
+        let frame = match header.batch_size {
+            0 => None,
+            size => Some((size, header.batch_index)),
+        };
+
+        Ok((Line::Event(event), frame))
+    }
+
+    /// Header, format and stored payload of a JSON line, checking its checksum
+    fn read(line: &str) -> Result<(RecordHeader<M>, Serializer, Vec<u8>), Unreadable> {
+        let data: FileData<M> = serde_json::from_str(line).map_err(|e| e.to_string())?;
+        let stored = match data.format {
+            Some(format) if format.is_text() && data.compression.is_none() => {
+                data.payload.get().as_bytes().to_vec()
             }
+            Some(_) => {
+                let text: String =
+                    serde_json::from_str(data.payload.get()).map_err(|e| e.to_string())?;
+                base64::decode(text).map_err(|e| e.to_string())?
+            }
+            None => serde_json::from_str::<String>(data.payload.get())
+                .map_err(|e| e.to_string())?
+                .into_bytes(),
+        };
+
+        if let Some(checksum) = data.checksum {
+            check_crc(checksum, &stored)?;
         }
 
-        Ok(filtered_events)
+        let header = RecordHeader {
+            event_id: data.event_id,
+            correlation_id: data.correlation_id,
+            causation_id: data.causation_id,
+            aggregate_id: data.aggregate_id,
+            aggregate_type: data.aggregate_type,
+            version: data.version,
+            compression: data.compression,
+            schema_version: data.schema_version,
+            meta: data.meta,
+            created_at: data.created_at,
+            batch_size: data.batch_size,
+            batch_index: data.batch_index,
+        };
+
+        Ok((header, data.format.unwrap_or_default(), stored))
+    }
+
+    /// Line `batch_index` of a batch of `batch_size` events without its newline,
+    /// its payload encoded with `format` and compressed under `policy`
+    fn line<A: Aggregate, E: DomainEvent<A>>(
+        event: &FormatedEvent<A, E, M>,
+        batch_size: usize,
+        batch_index: usize,
+        format: Serializer,
+        policy: CompressionPolicy,
+    ) -> Result<Vec<u8>, Error> {
+        let mut bytes = format.serialize(&event.payload)?;
+        let compression = policy.compression_for(bytes.len());
+        if let Some(compression) = compression {
+            bytes = compression.compress(&bytes)?;
+        }
+
+        if let Some(tag) = record_tag(format) {
+            let header = RecordHeader {
+                event_id: Some(event.event_id.clone()),
+                correlation_id: event.correlation_id.clone(),
+                causation_id: event.causation_id.clone(),
+                aggregate_id: event.aggregate_id.clone(),
+                aggregate_type: event.aggregate_type.clone(),
+                version: event.version,
+                compression,
+                schema_version: Some(E::schema_version()),
+                meta: event.meta.clone(),
+                created_at: event.created_at.clone(),
+                batch_size,
+                batch_index,
+            };
+
+            return binary_record(tag, &format.serialize(&header)?, &bytes);
+        }
+
+        let checksum = crc32fast::hash(&bytes);
+        let payload = if format.is_text() && compression.is_none() {
+            String::from_utf8_lossy(&bytes).into_owned()
+        } else {
+            serde_json::to_string(&base64::encode(&bytes))?
+        };
+
+        let data = FileData {
+            event_id: Some(event.event_id.clone()),
+            correlation_id: event.correlation_id.clone(),
+            causation_id: event.causation_id.clone(),
+            aggregate_id: event.aggregate_id.clone(),
+            aggregate_type: event.aggregate_type.clone(),
+            version: event.version,
+            payload: RawValue::from_string(payload)?,
+            format: Some(format),
+            compression,
+            schema_version: Some(E::schema_version()),
+            meta: event.meta.clone(),
+            created_at: event.created_at.clone(),
+            batch_size,
+            batch_index,
+            checksum: Some(checksum),
+        };
+
+        Ok(serde_json::to_vec(&data)?)
     }
 }
 
+/// Fields of a line besides its payload
+///
+/// Binary records hold it in their format, which writes every field in
+/// order. The metadata goes as JSON text there, `Meta` types may use serde
+/// attributes binary formats don't support.
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct FileData {
-    pub aggregate_id: String,
-    pub aggregate_type: String,
-    pub version: usize,
-    pub payload: String,
-    pub meta: MetaData,
-    pub created_at: String,
+struct RecordHeader<M: Meta> {
+    event_id: Option<String>,
+    correlation_id: Option<String>,
+    causation_id: Option<String>,
+    aggregate_id: String,
+    aggregate_type: String,
+    version: usize,
+    compression: Option<Compression>,
+    schema_version: Option<usize>,
+    #[serde(with = "json_text")]
+    meta: M,
+    created_at: String,
+    batch_size: usize,
+    batch_index: usize,
+}
+
+impl<M: Meta> RecordHeader<M> {
+    /// Header, format and stored payload of a binary record, checking its frame and CRC32
+    fn read(
+        format: Serializer,
+        record: &[u8],
+    ) -> Result<(RecordHeader<M>, Serializer, Vec<u8>), Unreadable> {
+        let (header_len, payload_len) = frame_lengths(record)
+            .filter(|(header, payload)| RECORD_FRAME + header + payload + 1 == record.len())
+            .ok_or_else(|| "binary record doesn't match its frame".to_string())?;
+
+        let body = &record[RECORD_FRAME..RECORD_FRAME + header_len + payload_len];
+        check_crc(word(record, 13), body)?;
+
+        // A sound record in a format that isn't compiled in isn't damaged
+        let (header, payload) = body.split_at(header_len);
+        let header = match format.is_enabled() {
+            true => format.decode(header)?,
+            false => format.deserialize(header).map_err(Unreadable::Failed)?,
+        };
+
+        Ok((header, format, payload.to_vec()))
+    }
+}
+
+/// Serde adapter keeping a value as its JSON text
+mod json_text {
+    use serde::{de::DeserializeOwned, de::Error as _, ser::Error as _, Deserialize, Serialize};
+
+    pub fn serialize<T: Serialize, S: serde::Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let text = serde_json::to_string(value).map_err(S::Error::custom)?;
+        serializer.serialize_str(&text)
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        serde_json::from_str(&text).map_err(D::Error::custom)
+    }
+}
+
+/// Format of the binary records starting with `tag`
+fn tag_format(tag: u8) -> Option<Serializer> {
+    RECORD_TAGS
+        .iter()
+        .find(|(record_tag, _)| *record_tag == tag)
+        .map(|(_, format)| *format)
+}
+
+/// First byte of the binary records of `format`, `None` for JSON lines
+fn record_tag(format: Serializer) -> Option<u8> {
+    RECORD_TAGS
+        .iter()
+        .find(|(_, record_format)| *record_format == format)
+        .map(|(tag, _)| *tag)
+}
+
+/// Big-endian `u32` at byte `at` of `bytes`
+fn word(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// Header and payload lengths in the frame of a binary record, `None` if the frame is damaged
+fn frame_lengths(record: &[u8]) -> Option<(usize, usize)> {
+    if record.len() < RECORD_FRAME || word(record, 9) != crc32fast::hash(&record[..9]) {
+        return None;
+    }
+
+    Some((word(record, 1) as usize, word(record, 5) as usize))
+}
+
+/// Binary record of `header` and `payload`, without its newline
+fn binary_record(tag: u8, header: &[u8], payload: &[u8]) -> Result<Vec<u8>, Error> {
+    let length = |bytes: &[u8]| {
+        u32::try_from(bytes.len())
+            .map_err(|_| Error::new("FileEventStore: record too large", Some("INTERNAL"), None))
+    };
+
+    let mut record = Vec::with_capacity(RECORD_FRAME + header.len() + payload.len() + 1);
+    record.push(tag);
+    record.extend_from_slice(&length(header)?.to_be_bytes());
+    record.extend_from_slice(&length(payload)?.to_be_bytes());
+    let frame_crc = crc32fast::hash(&record);
+    record.extend_from_slice(&frame_crc.to_be_bytes());
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(payload);
+    record.extend_from_slice(&hasher.finalize().to_be_bytes());
+    record.extend_from_slice(header);
+    record.extend_from_slice(payload);
+
+    Ok(record)
+}
+
+/// Compare `checksum` with the CRC32 of `bytes`
+fn check_crc(checksum: u32, bytes: &[u8]) -> Result<(), String> {
+    let actual = crc32fast::hash(bytes);
+    if checksum != actual {
+        return Err(format!(
+            "checksum mismatch: expected {:08x}, found {:08x}",
+            checksum, actual
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks if byte `offset` of `file` starts a line
+async fn is_line_end(file: &mut File, offset: u64) -> io::Result<bool> {
+    if offset == 0 {
+        return Ok(true);
+    }
+
+    file.seek(SeekFrom::Start(offset - 1)).await?;
+    let mut byte = [0; 1];
+    let read = file.read(&mut byte).await?;
+
+    Ok(read == 1 && byte[0] == b'\n')
+}
+
+/// Take the exclusive advisory lock of `file`, waiting up to `timeout` for another process
+///
+/// The lock is released when the file is dropped
+pub(crate) async fn lock_exclusive(
+    file: File,
+    path: &str,
+    timeout: Duration,
+) -> Result<File, Error> {
+    let file = file.into_std().await;
+    let started = Instant::now();
+
+    loop {
+        match file.try_lock_exclusive() {
+            Ok(()) => return Ok(File::from_std(file)),
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                if started.elapsed() >= timeout {
+                    return Err(Error::locked(path, timeout.as_millis()));
+                }
+                delay_for(LOCK_RETRY).await;
+            }
+            Err(e) => {
+                return Err(Error::file(
+                    "FileEventStore: cannot lock store",
+                    path,
+                    None,
+                    &e.to_string(),
+                ))
+            }
+        }
+    }
 }
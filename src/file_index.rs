@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::io;
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::Error;
+
+/// Byte offsets of each stream of a `FileEventStore` log
+///
+/// Saved next to the log as JSON lines, one `IndexRecord` per committed batch.
+/// Records are contiguous, so the index knows exactly how much of the log it covers.
+#[derive(Debug, Default)]
+pub(crate) struct Index {
+    /// Bytes of the log covered
+    pub end: u64,
+    /// Lines of the log covered
+    pub lines: usize,
+    streams: HashMap<(String, String), Vec<IndexEntry>>,
+    /// Records missing from the index file
+    unsaved: Vec<IndexRecord>,
+    /// The index file is written from scratch on the next save
+    rewrite: bool,
+}
+
+/// Line of the log holding one event
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct IndexEntry {
+    pub offset: u64,
+    pub line: usize,
+    pub version: usize,
+}
+
+/// Events of one batch of the log
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub(crate) struct IndexRecord {
+    pub start: u64,
+    pub end: u64,
+    /// Lines of the log up to the end of the batch
+    pub lines: usize,
+    pub events: Vec<IndexedEvent>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub(crate) struct IndexedEvent {
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    pub version: usize,
+    pub offset: u64,
+    pub line: usize,
+}
+
+impl Index {
+    /// Empty index replacing the index file once saved
+    pub fn rebuilt() -> Index {
+        Index {
+            rewrite: true,
+            ..Index::default()
+        }
+    }
+
+    /// Load the index file, `None` if it is missing or doesn't describe a contiguous log
+    pub async fn load(path: &str) -> Option<Index> {
+        let data = fs::read_to_string(path).await.ok()?;
+        let mut index = Index::default();
+
+        for line in data.lines().filter(|line| !line.trim().is_empty()) {
+            let record: IndexRecord = serde_json::from_str(line).ok()?;
+
+            // Another writer may have saved the same batches
+            if record.end <= index.end {
+                continue;
+            }
+            if record.start != index.end {
+                return None;
+            }
+
+            index.apply(&record);
+        }
+
+        Some(index)
+    }
+
+    /// Add the batch following the covered part of the log
+    pub fn add(&mut self, end: u64, lines: usize, events: Vec<IndexedEvent>) {
+        let record = IndexRecord {
+            start: self.end,
+            end,
+            lines,
+            events,
+        };
+
+        self.apply(&record);
+        self.unsaved.push(record);
+    }
+
+    fn apply(&mut self, record: &IndexRecord) {
+        for event in record.events.iter() {
+            let key = (event.aggregate_type.clone(), event.aggregate_id.clone());
+            self.streams.entry(key).or_default().push(IndexEntry {
+                offset: event.offset,
+                line: event.line,
+                version: event.version,
+            });
+        }
+
+        self.end = record.end;
+        self.lines = record.lines;
+    }
+
+    /// Lines of a stream, in log order
+    pub fn stream(&self, aggregate_type: &str, aggregate_id: &str) -> &[IndexEntry] {
+        let key = (aggregate_type.to_string(), aggregate_id.to_string());
+
+        match self.streams.get(&key) {
+            Some(entries) => entries,
+            None => &[],
+        }
+    }
+
+    /// Last version of a stream, `0` if it has no events
+    pub fn version(&self, aggregate_type: &str, aggregate_id: &str) -> usize {
+        self.stream(aggregate_type, aggregate_id)
+            .iter()
+            .map(|entry| entry.version)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Write the records missing from the index file
+    pub async fn save(&mut self, path: &str) -> Result<(), Error> {
+        let file_error = |e: io::Error| {
+            Error::file(
+                "FileEventStore: cannot write index",
+                path,
+                None,
+                &e.to_string(),
+            )
+        };
+
+        if self.rewrite {
+            let mut events: Vec<IndexedEvent> = self
+                .streams
+                .iter()
+                .flat_map(|((aggregate_type, aggregate_id), entries)| {
+                    entries.iter().map(move |entry| IndexedEvent {
+                        aggregate_type: aggregate_type.clone(),
+                        aggregate_id: aggregate_id.clone(),
+                        version: entry.version,
+                        offset: entry.offset,
+                        line: entry.line,
+                    })
+                })
+                .collect();
+            events.sort_by_key(|event| event.offset);
+
+            let record = IndexRecord {
+                start: 0,
+                end: self.end,
+                lines: self.lines,
+                events,
+            };
+            let data = serde_json::to_string(&record)? + "\n";
+
+            // Replace the index file at once
+            let tmp_path = format!("{}.tmp", path);
+            fs::write(&tmp_path, data).await.map_err(file_error)?;
+            fs::rename(&tmp_path, path).await.map_err(file_error)?;
+        } else if !self.unsaved.is_empty() {
+            let mut data = String::new();
+            for record in self.unsaved.iter() {
+                data.push_str(&serde_json::to_string(record)?);
+                data.push('\n');
+            }
+
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .await
+                .map_err(file_error)?;
+            file.write_all(data.as_bytes()).await.map_err(file_error)?;
+            file.flush().await.map_err(file_error)?;
+        }
+
+        self.unsaved.clear();
+        self.rewrite = false;
+
+        Ok(())
+    }
+}
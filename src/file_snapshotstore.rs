@@ -0,0 +1,85 @@
+use async_trait::async_trait;
+use std::marker::PhantomData;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::{fs, io::AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::{Aggregate, Error, Snapshot, SnapshotStore};
+
+/// FileSnapshotStore
+///
+/// Keeps the latest snapshot of each aggregate as a JSON file in `dir`.
+/// Files are accessed through `tokio::fs`, like `FileEventStore`.
+///
+/// NOTE: Only use the for develpment and not for production
+pub struct FileSnapshotStore<A: Aggregate> {
+    dir: String,
+    _a: PhantomData<A>,
+}
+
+impl<A: Aggregate> FileSnapshotStore<A> {
+    pub fn new(dir: &str) -> FileSnapshotStore<A> {
+        FileSnapshotStore {
+            dir: dir.to_owned(),
+            _a: PhantomData,
+        }
+    }
+
+    /// Path of the snapshot file of an aggregate
+    fn snapshot_path(&self, aggregate_id: &str) -> PathBuf {
+        let name: String = format!("{}-{}", A::aggregate_type(), aggregate_id)
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    true => c,
+                    false => '_',
+                },
+            )
+            .collect();
+
+        Path::new(&self.dir).join(format!("{}.snapshot", name))
+    }
+}
+
+impl<A: Aggregate> Clone for FileSnapshotStore<A> {
+    fn clone(&self) -> FileSnapshotStore<A> {
+        FileSnapshotStore {
+            dir: self.dir.clone(),
+            _a: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<A: Aggregate> SnapshotStore<A> for FileSnapshotStore<A> {
+    /// Load the latest snapshot of an aggregate
+    async fn load(&self, aggregate_id: &str) -> Result<Option<Snapshot<A>>, Error> {
+        match fs::read_to_string(self.snapshot_path(aggregate_id)).await {
+            Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save a snapshot, replacing older ones of the aggregate
+    ///
+    /// The snapshot is written to a temporary file first, so readers never see a partial snapshot
+    async fn save(&self, snapshot: Snapshot<A>) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir).await?;
+
+        let path = self.snapshot_path(&snapshot.aggregate_id);
+        // Concurrent saves of one aggregate each write their own file
+        let tmp_path = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(serde_json::to_string(&snapshot)?.as_bytes())
+            .await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, &path).await?;
+
+        Ok(())
+    }
+}
@@ -2,34 +2,49 @@ use chrono::prelude::*;
 use serde::Serialize;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use uuid::Uuid;
 
-use crate::{Aggregate, DomainEvent, FormatedEvents, MetaData};
+use crate::{Aggregate, AggregateContext, DomainEvent, FormatedEvents, Meta, MetaData};
 
 #[derive(Debug, Serialize)]
-pub struct FormatedEvent<A, E>
+pub struct FormatedEvent<A, E, M = MetaData>
 where
     A: Aggregate,
     E: DomainEvent<A>,
+    M: Meta,
 {
+    /// Unique id of the event, a UUID
+    pub event_id: String,
+    /// Id shared by every command and event of a chain, set by `CQRS::execute`
+    pub correlation_id: Option<String>,
+    /// Id of the command or event that caused this event, set by `CQRS::execute`
+    pub causation_id: Option<String>,
     pub aggregate_id: String,
     pub aggregate_type: String,
     pub version: usize,
+    /// Position in the whole store assigned on append, `0` until the event is stored
+    pub position: usize,
     pub payload: E,
-    pub meta: MetaData,
+    pub meta: M,
     pub created_at: String,
     pub(crate) _phantom: PhantomData<A>,
 }
 
-impl<A, E> Clone for FormatedEvent<A, E>
+impl<A, E, M> Clone for FormatedEvent<A, E, M>
 where
     A: Aggregate,
     E: DomainEvent<A>,
+    M: Meta,
 {
-    fn clone(&self) -> FormatedEvent<A, E> {
+    fn clone(&self) -> FormatedEvent<A, E, M> {
         FormatedEvent {
+            event_id: self.event_id.clone(),
+            correlation_id: self.correlation_id.clone(),
+            causation_id: self.causation_id.clone(),
             aggregate_id: self.aggregate_id.clone(),
             aggregate_type: self.aggregate_type.clone(),
             version: self.version,
+            position: self.position,
             payload: self.payload.clone(),
             meta: self.meta.clone(),
             created_at: self.created_at.clone(),
@@ -38,24 +53,29 @@ where
     }
 }
 
-impl<A, E> FormatedEvent<A, E>
+impl<A, E, M> FormatedEvent<A, E, M>
 where
     A: Aggregate,
     E: DomainEvent<A>,
+    M: Meta,
 {
-    /// Create a new FormatedEvent
+    /// Create a new FormatedEvent with a new event id
     pub fn new(
         aggregate_id: String,
         aggregate_type: String,
         version: usize,
         payload: E,
-        meta: MetaData,
+        meta: M,
         created_at: Option<&str>,
-    ) -> FormatedEvent<A, E> {
+    ) -> FormatedEvent<A, E, M> {
         FormatedEvent {
+            event_id: Uuid::new_v4().to_string(),
+            correlation_id: None,
+            causation_id: None,
             aggregate_id,
             aggregate_type,
             version,
+            position: 0,
             payload,
             meta,
             created_at: match created_at {
@@ -71,9 +91,9 @@ where
         aggregate_id: &str,
         current_version: usize,
         events: Vec<E>,
-        meta: MetaData,
-    ) -> FormatedEvents<A, E> {
-        let mut formated_events: FormatedEvents<A, E> = Vec::new();
+        meta: M,
+    ) -> FormatedEvents<A, E, M> {
+        let mut formated_events: FormatedEvents<A, E, M> = Vec::new();
         let mut version = current_version;
 
         for payload in events {
@@ -93,4 +113,60 @@ where
 
         formated_events
     }
+
+    /// Create FormatedEvents from DomainEvents handled in `context`, stamped with
+    /// its correlation and causation ids
+    pub fn create_in(
+        context: &AggregateContext<A>,
+        events: Vec<E>,
+        meta: M,
+    ) -> FormatedEvents<A, E, M> {
+        let mut formated_events =
+            FormatedEvent::create_many(context.id.as_str(), context.version, events, meta);
+
+        for event in formated_events.iter_mut() {
+            event.correlation_id = context.correlation_id.clone();
+            event.causation_id = context.causation_id.clone();
+        }
+
+        formated_events
+    }
+
+    /// Set the ids read back from a store
+    ///
+    /// Records written before event ids get one derived from the aggregate and
+    /// version, so it stays the same across reads.
+    pub(crate) fn restore_ids(
+        &mut self,
+        event_id: Option<String>,
+        correlation_id: Option<String>,
+        causation_id: Option<String>,
+    ) {
+        self.event_id = match event_id {
+            Some(id) => id,
+            None => {
+                let name = format!(
+                    "{}/{}/{}",
+                    self.aggregate_type, self.aggregate_id, self.version
+                );
+                Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes()).to_string()
+            }
+        };
+        self.correlation_id = correlation_id;
+        self.causation_id = causation_id;
+    }
+
+    /// Name of the event variant
+    ///
+    /// Taken from the serde tag of the payload (`{"Accepted": {..}}` or `"Accepted"`),
+    /// falls back to `DomainEvent::name`
+    pub fn event_name(&self) -> String {
+        match serde_json::to_value(&self.payload) {
+            Ok(serde_json::Value::String(name)) => name,
+            Ok(serde_json::Value::Object(map)) if map.len() == 1 => {
+                map.keys().next().cloned().unwrap_or_default()
+            }
+            _ => E::name().to_string(),
+        }
+    }
 }
@@ -1,20 +1,21 @@
 use async_trait::async_trait;
-use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 
 use crate::{
-    Aggregate, AggregateContext, Command, DomainEvent, Error, FormatedEvent, FormatedResult,
-    MetaData, Store,
+    Aggregate, AggregateContext, Command, DomainEvent, Error, FormatedEvent, FormatedEvents,
+    FormatedResult, IdempotencyRecord, Meta, MetaData, Store,
 };
 
-pub type GivenThen<A, E, C> = GivenThenTest<A, E, TestStore<A, E>, C>;
+pub type GivenThen<A, E, C, M = MetaData> = GivenThenTest<A, E, TestStore<A, E, M>, C, M>;
 
-pub struct GivenThenTest<A, E, S, C>
+pub struct GivenThenTest<A, E, S, C, M = MetaData>
 where
     A: Aggregate,
     E: DomainEvent<A>,
-    S: Store<A, E>,
-    C: Command<A, E>,
+    S: Store<A, E, M>,
+    C: Command<A, E, M>,
+    M: Meta,
 {
     given: Vec<E>,
     when: Option<C>,
@@ -22,16 +23,18 @@ where
     then_error: Option<Error>,
     _a: PhantomData<A>,
     _s: PhantomData<S>,
+    _m: PhantomData<M>,
 }
 
-impl<A, E, S, C> GivenThenTest<A, E, S, C>
+impl<A, E, S, C, M> GivenThenTest<A, E, S, C, M>
 where
     A: Aggregate,
     E: DomainEvent<A>,
-    S: Store<A, E>,
-    C: Command<A, E>,
+    S: Store<A, E, M>,
+    C: Command<A, E, M>,
+    M: Meta,
 {
-    pub fn new() -> GivenThenTest<A, E, S, C> {
+    pub fn new() -> GivenThenTest<A, E, S, C, M> {
         GivenThenTest {
             given: Vec::new(),
             when: None,
@@ -39,30 +42,31 @@ where
             then_error: None,
             _a: PhantomData,
             _s: PhantomData,
+            _m: PhantomData,
         }
     }
-    pub fn given(self, events: Vec<E>) -> GivenThenTest<A, E, S, C> {
+    pub fn given(self, events: Vec<E>) -> GivenThenTest<A, E, S, C, M> {
         GivenThenTest {
             given: events,
             ..self
         }
     }
 
-    pub fn when(self, command: C) -> GivenThenTest<A, E, S, C> {
+    pub fn when(self, command: C) -> GivenThenTest<A, E, S, C, M> {
         GivenThenTest {
             when: Some(command),
             ..self
         }
     }
 
-    pub fn then(self, expected: Vec<E>) -> GivenThenTest<A, E, S, C> {
+    pub fn then(self, expected: Vec<E>) -> GivenThenTest<A, E, S, C, M> {
         GivenThenTest {
             then: expected,
             ..self
         }
     }
 
-    pub fn then_error(self, expected_error: Error) -> GivenThenTest<A, E, S, C> {
+    pub fn then_error(self, expected_error: Error) -> GivenThenTest<A, E, S, C, M> {
         GivenThenTest {
             then_error: Some(expected_error),
             ..self
@@ -94,7 +98,11 @@ where
             }
         };
 
-        let store = TestStore::new(self.given.clone());
+        // The given events are the history of the aggregate the command names
+        let store = match command.id() {
+            Some(id) => TestStore::<A, E, M>::for_aggregate(&id, self.given.clone()),
+            None => TestStore::<A, E, M>::new(self.given.clone()),
+        };
         let cmd = C::before(command.clone(), &store).await?;
         let context = &store.assemble_aggregate(cmd.id()).await?;
         let generated = &cmd.handle(context).await?;
@@ -103,34 +111,68 @@ where
     }
 }
 
+impl<A, E, S, C, M> Default for GivenThenTest<A, E, S, C, M>
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    S: Store<A, E, M>,
+    C: Command<A, E, M>,
+    M: Meta,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// TestStore
-pub struct TestStore<A: Aggregate, E: DomainEvent<A>> {
-    events: Vec<FormatedEvent<A, E>>,
+///
+/// Clones share the same events, appended events are kept in memory along
+/// with their idempotency keys.
+pub struct TestStore<A: Aggregate, E: DomainEvent<A>, M: Meta = MetaData> {
+    events: Arc<RwLock<FormatedEvents<A, E, M>>>,
+    /// Written while the events are locked
+    keys: Arc<RwLock<Vec<IdempotencyRecord>>>,
     _a: PhantomData<A>,
     _e: PhantomData<E>,
 }
 
-impl<A: Aggregate, E: DomainEvent<A>> TestStore<A, E> {
-    pub fn new(given: Vec<E>) -> TestStore<A, E> {
-        let formated = FormatedEvent::create_many(
-            "86d786e8-4e24-4abf-b2f3-ccd24e606335",
-            0,
-            given,
-            HashMap::new(),
-        );
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> TestStore<A, E, M> {
+    pub fn new(given: Vec<E>) -> TestStore<A, E, M> {
+        TestStore::for_aggregate("86d786e8-4e24-4abf-b2f3-ccd24e606335", given)
+    }
+
+    /// Store holding `given` as the events of `aggregate_id`
+    pub fn for_aggregate(aggregate_id: &str, given: Vec<E>) -> TestStore<A, E, M> {
+        let mut formated = FormatedEvent::create_many(aggregate_id, 0, given, M::default());
+        for (i, event) in formated.iter_mut().enumerate() {
+            event.position = i + 1;
+        }
 
         TestStore {
-            events: formated,
+            events: Arc::new(RwLock::new(formated)),
+            keys: Arc::new(RwLock::new(Vec::new())),
             _a: PhantomData,
             _e: PhantomData,
         }
     }
+
+    fn events(&self) -> Result<RwLockReadGuard<'_, FormatedEvents<A, E, M>>, Error> {
+        match self.events.read() {
+            Ok(events) => Ok(events),
+            Err(_) => Err(poisoned()),
+        }
+    }
 }
 
-impl<A: Aggregate, E: DomainEvent<A>> Clone for TestStore<A, E> {
-    fn clone(&self) -> TestStore<A, E> {
+fn poisoned() -> Error {
+    Error::new("TestStore lock poisoned", Some("INTERNAL"), None)
+}
+
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Clone for TestStore<A, E, M> {
+    fn clone(&self) -> TestStore<A, E, M> {
         TestStore {
-            events: self.events.clone(),
+            events: Arc::clone(&self.events),
+            keys: Arc::clone(&self.keys),
             _a: PhantomData,
             _e: PhantomData,
         }
@@ -138,17 +180,19 @@ impl<A: Aggregate, E: DomainEvent<A>> Clone for TestStore<A, E> {
 }
 
 #[async_trait]
-impl<A: Aggregate, E: DomainEvent<A>> Store<A, E> for TestStore<A, E> {
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Store<A, E, M> for TestStore<A, E, M> {
     /// Rebuilding the aggregate
     async fn assemble_aggregate(&self, id: Option<String>) -> Result<AggregateContext<A>, Error> {
         let mut context = AggregateContext::default();
         context.set_id(id.clone());
 
         // Populate aggregate if id is provided
-        if let Some(_x) = id {
-            for fmt_event in &self.events {
-                fmt_event.payload.clone().apply(&mut context.aggregate);
-                context.version = fmt_event.version;
+        if let Some(x) = id {
+            for fmt_event in self.events()?.iter() {
+                if fmt_event.aggregate_id == x && fmt_event.aggregate_type == A::aggregate_type() {
+                    fmt_event.payload.clone().apply(&mut context.aggregate);
+                    context.version = fmt_event.version;
+                }
             }
         }
 
@@ -159,24 +203,62 @@ impl<A: Aggregate, E: DomainEvent<A>> Store<A, E> for TestStore<A, E> {
     async fn append(
         &self,
         events: Vec<E>,
-        context: AggregateContext<A>,
-        meta: MetaData,
-    ) -> FormatedResult<A, E> {
-        let formated_events =
-            FormatedEvent::create_many(context.id.as_str(), context.version, events, meta);
+        context: &AggregateContext<A>,
+        meta: M,
+    ) -> FormatedResult<A, E, M> {
+        let mut formated_events = FormatedEvent::create_in(context, events, meta);
 
-        if formated_events.len() == 0 {
+        if formated_events.is_empty() && context.idempotency.is_none() {
             return Ok(Vec::default());
         }
 
+        let mut stored = self.events.write().map_err(|_| poisoned())?;
+        let mut keys = self.keys.write().map_err(|_| poisoned())?;
+
+        if let Some(idempotency) = &context.idempotency {
+            let aggregate_type = A::aggregate_type();
+            if keys
+                .iter()
+                .any(|record| record.is_for(aggregate_type, &idempotency.key))
+            {
+                return Err(idempotency.duplicate::<A>());
+            }
+        }
+
+        // Check expected version against the stored stream
+        let current_version = stored
+            .iter()
+            .filter(|e| e.aggregate_id == context.id && e.aggregate_type == A::aggregate_type())
+            .map(|e| e.version)
+            .last()
+            .unwrap_or(0);
+
+        if !formated_events.is_empty() && current_version != context.version {
+            return Err(Error::conflict(
+                &context.id,
+                context.version,
+                current_version,
+            ));
+        }
+
+        for event in formated_events.iter_mut() {
+            event.position = stored.len() + 1;
+            stored.push(event.clone());
+        }
+
+        if let Some(idempotency) = &context.idempotency {
+            keys.retain(|record| !record.is_expired());
+            keys.push(idempotency.record(context, &formated_events));
+        }
+
         Ok(formated_events)
     }
 
     /// Retrive Events for command store
-    async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<A, E> {
+    async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<A, E, M> {
         let mut filtered_events = Vec::new();
 
-        for e in self.events.iter() {
+        for e in self.events()?.iter() {
             if e.aggregate_id == aggregate_id && e.aggregate_type == A::aggregate_type() {
                 filtered_events.push(e.clone());
             }
@@ -185,21 +267,46 @@ impl<A: Aggregate, E: DomainEvent<A>> Store<A, E> for TestStore<A, E> {
         Ok(filtered_events)
     }
 
+    /// Read events from global `position` on, ordered by position
+    async fn read_all(
+        &self,
+        position: usize,
+        aggregate_type: Option<&str>,
+    ) -> FormatedResult<A, E, M> {
+        Ok(self
+            .events()?
+            .iter()
+            .filter(|e| {
+                e.position >= position && aggregate_type.map_or(true, |t| e.aggregate_type == t)
+            })
+            .cloned()
+            .collect())
+    }
+
     /** Retrive Events for query */
-    async fn retrieve_for_query(&self, aggregate_id: Option<&str>) -> FormatedResult<A, E> {
+    async fn retrieve_for_query(&self, aggregate_id: Option<&str>) -> FormatedResult<A, E, M> {
         let mut filtered_events = Vec::new();
 
-        let id = match aggregate_id {
-            Some(i) => i,
-            None => "",
-        };
-
-        for e in self.events.iter() {
-            if e.aggregate_id == id || e.aggregate_type == A::aggregate_type() {
+        // The aggregate's stream when an id is given, every stream of its type otherwise
+        for e in self.events()?.iter() {
+            if e.aggregate_type == A::aggregate_type()
+                && aggregate_id.map_or(true, |id| e.aggregate_id == id)
+            {
                 filtered_events.push(e.clone());
             }
         }
 
         Ok(filtered_events)
     }
+
+    /// Live outcome remembered under idempotency `key`
+    async fn remembered(&self, key: &str) -> Result<Option<IdempotencyRecord>, Error> {
+        let keys = self.keys.read().map_err(|_| poisoned())?;
+
+        Ok(keys
+            .iter()
+            .rev()
+            .find(|record| record.is_for(A::aggregate_type(), key))
+            .cloned())
+    }
 }
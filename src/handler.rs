@@ -1,14 +1,16 @@
 use async_trait::async_trait;
 
-use crate::{Aggregate, DomainEvent, FormatedEvent};
+use crate::{Aggregate, DomainEvent, FormatedEvent, Meta, MetaData};
 
-pub type Handlers<A, E> = Vec<Box<dyn Handler<A, E> + Send>>;
+pub type Handlers<A, E, M = MetaData> = Vec<Box<dyn Handler<A, E, M> + Send>>;
 
 #[async_trait]
-pub trait Handler<A, E>
+pub trait Handler<A, E, M = MetaData>
 where
     A: Aggregate,
     E: DomainEvent<A>,
+    M: Meta,
 {
-    async fn handle(&self, events: &Vec<FormatedEvent<A, E>>);
+    #[allow(clippy::ptr_arg)]
+    async fn handle(&self, events: &Vec<FormatedEvent<A, E, M>>);
 }
@@ -0,0 +1,263 @@
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::{io, time::Duration};
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::{Aggregate, AggregateContext, DomainEvent, Error, FormatedEvent, Meta, Store};
+
+/// How long `CQRS::execute` remembers idempotency keys by default
+pub const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// IdempotencyRecord
+///
+/// Outcome of a command remembered under its idempotency key, see
+/// `Command::idempotency_key`. Only commands that succeeded are remembered.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    pub key: String,
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    /// Version of the aggregate the command was handled against
+    pub from_version: usize,
+    /// Version of the aggregate after the command
+    pub version: usize,
+    pub recorded_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Id of the first event appended, tells whether the events were written with the key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+}
+
+impl IdempotencyRecord {
+    /// Record remembered for `window` from now
+    pub fn new(
+        key: &str,
+        aggregate_type: &str,
+        aggregate_id: &str,
+        from_version: usize,
+        version: usize,
+        window: Duration,
+    ) -> IdempotencyRecord {
+        let recorded_at = Utc::now();
+        let expires_at = chrono::Duration::from_std(window)
+            .ok()
+            .and_then(|window| recorded_at.checked_add_signed(window))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+
+        IdempotencyRecord {
+            key: key.to_string(),
+            aggregate_type: aggregate_type.to_string(),
+            aggregate_id: aggregate_id.to_string(),
+            from_version,
+            version,
+            recorded_at,
+            expires_at,
+            event_id: None,
+        }
+    }
+
+    /// Checks if the key may be used again
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+
+    /// Checks if the record is the live one for `key` of `aggregate_type`
+    pub(crate) fn is_for(&self, aggregate_type: &str, key: &str) -> bool {
+        self.aggregate_type == aggregate_type && self.key == key && !self.is_expired()
+    }
+}
+
+/// IdempotencyKey
+///
+/// Key of the command whose events are appended, set on `AggregateContext` by
+/// `CQRS::execute`. Stores remembering keys keep it with the events, atomically.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdempotencyKey {
+    pub key: String,
+    /// How long the key is remembered
+    pub window: Duration,
+}
+
+impl IdempotencyKey {
+    pub fn new(key: &str, window: Duration) -> IdempotencyKey {
+        IdempotencyKey {
+            key: key.to_string(),
+            window,
+        }
+    }
+
+    /// Record remembering `events` appended with `context` under the key
+    pub fn record<A, E, M>(
+        &self,
+        context: &AggregateContext<A>,
+        events: &[FormatedEvent<A, E, M>],
+    ) -> IdempotencyRecord
+    where
+        A: Aggregate,
+        E: DomainEvent<A>,
+        M: Meta,
+    {
+        let version = events.last().map_or(context.version, |e| e.version);
+        let mut record = IdempotencyRecord::new(
+            &self.key,
+            A::aggregate_type(),
+            &context.id,
+            context.version,
+            version,
+            self.window,
+        );
+        record.event_id = events.first().map(|e| e.event_id.clone());
+
+        record
+    }
+
+    /// `Error::duplicate` of the key
+    pub(crate) fn duplicate<A: Aggregate>(&self) -> Error {
+        Error::duplicate(A::aggregate_type(), &self.key)
+    }
+}
+
+/// Side file of the file stores, one `IdempotencyRecord` per line
+///
+/// Callers hold the store's file lock, so writers don't interleave. A key is
+/// written before its events, so a crash between the two writes leaves a
+/// record whose events are missing, which `written` ignores.
+pub(crate) struct KeyFile<'a> {
+    pub path: &'a str,
+}
+
+impl<'a> KeyFile<'a> {
+    /// Live record of `key`
+    pub async fn get(
+        &self,
+        aggregate_type: &str,
+        key: &str,
+    ) -> Result<Option<IdempotencyRecord>, Error> {
+        let (mut records, _) = self.read().await?;
+        records.retain(|record| record.is_for(aggregate_type, key));
+
+        Ok(records.pop())
+    }
+
+    /// Live record of `key` whose events `store` holds
+    pub async fn written<A, E, M, S>(
+        &self,
+        store: &S,
+        key: &str,
+    ) -> Result<Option<IdempotencyRecord>, Error>
+    where
+        A: Aggregate,
+        E: DomainEvent<A>,
+        M: Meta,
+        S: Store<A, E, M>,
+    {
+        let record = match self.get(A::aggregate_type(), key).await? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        // Records without an event id had no events, or were written after them
+        let event_id = match &record.event_id {
+            Some(event_id) => event_id,
+            None => return Ok(Some(record)),
+        };
+
+        let written = {
+            let mut events = store.stream_from(&record.aggregate_id, record.from_version);
+            match events.try_next().await {
+                Ok(event) => event.is_some_and(|event| &event.event_id == event_id),
+                // Only stored events get compacted
+                Err(e) if e.is_compacted() => true,
+                Err(e) => return Err(e),
+            }
+        };
+
+        Ok(match written {
+            true => Some(record),
+            false => None,
+        })
+    }
+
+    /// Add `record`, rewriting the file without expired records or a torn line when there are some
+    pub async fn put(&self, record: IdempotencyRecord) -> Result<(), Error> {
+        let (mut records, torn) = self.read().await?;
+        let count = records.len();
+        records.retain(|record| !record.is_expired());
+
+        if records.len() == count && !torn {
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(self.path)
+                .await
+                .map_err(|e| self.error(e))?;
+            let data = serde_json::to_string(&record)? + "\n";
+            file.write_all(data.as_bytes())
+                .await
+                .map_err(|e| self.error(e))?;
+            file.flush().await.map_err(|e| self.error(e))?;
+        } else {
+            records.push(record);
+
+            let mut data = String::new();
+            for record in records.iter() {
+                data.push_str(&serde_json::to_string(record)?);
+                data.push('\n');
+            }
+
+            // Replace the file at once
+            let tmp_path = format!("{}.tmp", self.path);
+            fs::write(&tmp_path, data)
+                .await
+                .map_err(|e| self.error(e))?;
+            fs::rename(&tmp_path, self.path)
+                .await
+                .map_err(|e| self.error(e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Records of the complete lines, and whether a torn line follows them
+    async fn read(&self) -> Result<(Vec<IdempotencyRecord>, bool), Error> {
+        let data = match fs::read_to_string(self.path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), false)),
+            Err(e) => {
+                return Err(Error::file(
+                    "Store: cannot read idempotency keys",
+                    self.path,
+                    None,
+                    &e.to_string(),
+                ))
+            }
+        };
+
+        // Readers don't lock, the last line may still be being written
+        let complete = match data.rfind('\n') {
+            Some(end) => &data[..=end],
+            None => "",
+        };
+
+        let records = complete
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| Error::corrupt(self.path, i + 1, &e.to_string()))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok((records, complete.len() < data.len()))
+    }
+
+    fn error(&self, e: io::Error) -> Error {
+        Error::file(
+            "Store: cannot write idempotency keys",
+            self.path,
+            None,
+            &e.to_string(),
+        )
+    }
+}
@@ -16,23 +16,88 @@ pub use query::*;
 mod handler;
 pub use handler::*;
 
+mod middleware;
+pub use middleware::*;
+
 mod cqrs;
 pub use cqrs::*;
 
+mod execution_result;
+pub use execution_result::*;
+
+mod command_bus;
+pub use command_bus::*;
+
+mod idempotency;
+pub use idempotency::*;
+
+mod retry;
+pub use retry::*;
+
 mod query_processor;
 pub use query_processor::*;
 
 mod types;
 pub use types::*;
 
+mod event_stream;
+
+mod event_filter;
+pub use event_filter::*;
+
 mod error;
 pub use error::*;
 
+mod meta;
+pub use meta::*;
+
+mod serializer;
+pub use serializer::*;
+
+mod compression;
+pub use compression::*;
+
+mod upcaster;
+pub use upcaster::*;
+
 mod store;
 pub use store::*;
 
+mod file_index;
+
 mod file_eventstore;
 pub use file_eventstore::*;
 
+mod segmented_eventstore;
+pub use segmented_eventstore::*;
+
+mod verify_report;
+pub use verify_report::*;
+
+mod memory_eventstore;
+pub use memory_eventstore::*;
+
+mod snapshot;
+pub use snapshot::*;
+
+mod snapshotting_store;
+pub use snapshotting_store::*;
+
+mod file_snapshotstore;
+pub use file_snapshotstore::*;
+
+mod memory_snapshotstore;
+pub use memory_snapshotstore::*;
+
+#[cfg(feature = "sqlite")]
+mod sqlite_eventstore;
+#[cfg(feature = "sqlite")]
+pub use sqlite_eventstore::*;
+
+#[cfg(feature = "postgres")]
+mod postgres_eventstore;
+#[cfg(feature = "postgres")]
+pub use postgres_eventstore::*;
+
 mod given_then_test;
 pub use given_then_test::*;
@@ -0,0 +1,241 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{
+    Aggregate, AggregateContext, DomainEvent, Error, EventFilter, FormatedEvent, FormatedResult,
+    Handlers, IdempotencyRecord, Meta, MetaData, Store, CQRS,
+};
+
+/// InMemoryEventStore
+///
+/// Keeps events in memory, streams are indexed by `(aggregate_type, aggregate_id)`.
+/// Clones share the same events, so a store can be handed to several `CQRS` instances.
+///
+/// Idempotency keys are kept alongside the events and remembered under the same
+/// lock, expired ones are dropped as new keys are remembered.
+pub struct InMemoryEventStore<A: Aggregate, E: DomainEvent<A>, M: Meta = MetaData> {
+    inner: Arc<RwLock<Streams<A, E, M>>>,
+}
+
+struct Streams<A: Aggregate, E: DomainEvent<A>, M: Meta> {
+    /// Every event in the order it was appended
+    events: Vec<FormatedEvent<A, E, M>>,
+    /// Positions in `events` of each stream
+    streams: HashMap<(String, String), Vec<usize>>,
+    /// Remembered idempotency keys
+    keys: Vec<IdempotencyRecord>,
+}
+
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> InMemoryEventStore<A, E, M> {
+    pub fn new() -> InMemoryEventStore<A, E, M> {
+        InMemoryEventStore {
+            inner: Arc::new(RwLock::new(Streams {
+                events: Vec::new(),
+                streams: HashMap::new(),
+                keys: Vec::new(),
+            })),
+        }
+    }
+
+    /// Creates CQRS with store
+    pub fn create_cqrs(handlers: Handlers<A, E, M>) -> CQRS<A, E, InMemoryEventStore<A, E, M>, M> {
+        CQRS::new(InMemoryEventStore::new(), handlers)
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, Streams<A, E, M>>, Error> {
+        match self.inner.read() {
+            Ok(inner) => Ok(inner),
+            Err(_) => Err(poisoned()),
+        }
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, Streams<A, E, M>>, Error> {
+        match self.inner.write() {
+            Ok(inner) => Ok(inner),
+            Err(_) => Err(poisoned()),
+        }
+    }
+}
+
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Streams<A, E, M> {
+    fn stream(&self, aggregate_id: &str) -> StreamEvents<'_, A, E, M> {
+        let key = (A::aggregate_type().to_string(), aggregate_id.to_string());
+
+        match self.streams.get(&key) {
+            Some(positions) => positions.iter().map(|p| &self.events[*p]).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+type StreamEvents<'a, A, E, M> = Vec<&'a FormatedEvent<A, E, M>>;
+
+fn poisoned() -> Error {
+    Error::new("InMemoryEventStore lock poisoned", Some("INTERNAL"), None)
+}
+
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Default for InMemoryEventStore<A, E, M> {
+    fn default() -> InMemoryEventStore<A, E, M> {
+        InMemoryEventStore::new()
+    }
+}
+
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Clone for InMemoryEventStore<A, E, M> {
+    fn clone(&self) -> InMemoryEventStore<A, E, M> {
+        InMemoryEventStore {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+#[async_trait]
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Store<A, E, M> for InMemoryEventStore<A, E, M> {
+    /// Rebuilding the aggregate
+    async fn assemble_aggregate(&self, id: Option<String>) -> Result<AggregateContext<A>, Error> {
+        let mut context = AggregateContext::default();
+        context.set_id(id.clone());
+
+        // Populate aggregate if id is provided
+        if let Some(x) = id {
+            for fmt_event in self.read()?.stream(&x) {
+                fmt_event.payload.clone().apply(&mut context.aggregate);
+                context.version = fmt_event.version;
+            }
+        }
+
+        Ok(context)
+    }
+
+    ///  Append formated events to store
+    async fn append(
+        &self,
+        events: Vec<E>,
+        context: &AggregateContext<A>,
+        meta: M,
+    ) -> FormatedResult<A, E, M> {
+        let mut formated_events = FormatedEvent::create_in(context, events, meta);
+
+        if formated_events.is_empty() && context.idempotency.is_none() {
+            return Ok(Vec::default());
+        }
+
+        let mut inner = self.write()?;
+
+        if let Some(idempotency) = &context.idempotency {
+            let aggregate_type = A::aggregate_type();
+            if inner
+                .keys
+                .iter()
+                .any(|record| record.is_for(aggregate_type, &idempotency.key))
+            {
+                return Err(idempotency.duplicate::<A>());
+            }
+        }
+
+        // Check expected version against the stored stream
+        let current_version = match inner.stream(&context.id).last() {
+            Some(e) => e.version,
+            None => 0,
+        };
+
+        if !formated_events.is_empty() && current_version != context.version {
+            return Err(Error::conflict(
+                &context.id,
+                context.version,
+                current_version,
+            ));
+        }
+
+        let key = (A::aggregate_type().to_string(), context.id.clone());
+        for event in formated_events.iter_mut() {
+            let index = inner.events.len();
+            event.position = index + 1;
+            inner.events.push(event.clone());
+            inner.streams.entry(key.clone()).or_default().push(index);
+        }
+
+        // Remembered along with the events, dropping expired keys
+        if let Some(idempotency) = &context.idempotency {
+            let record = idempotency.record(context, &formated_events);
+            inner.keys.retain(|record| !record.is_expired());
+            inner.keys.push(record);
+        }
+
+        Ok(formated_events)
+    }
+
+    /// Retrive Events for command store
+    async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<A, E, M> {
+        Ok(self
+            .read()?
+            .stream(aggregate_id)
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    /// Read events from global `position` on, ordered by position
+    async fn read_all(
+        &self,
+        position: usize,
+        aggregate_type: Option<&str>,
+    ) -> FormatedResult<A, E, M> {
+        let inner = self.read()?;
+        let start = position.saturating_sub(1).min(inner.events.len());
+
+        Ok(inner.events[start..]
+            .iter()
+            .filter(|e| aggregate_type.map_or(true, |t| e.aggregate_type == t))
+            .cloned()
+            .collect())
+    }
+
+    /// Retrive Events matching `filter`, ordered by position
+    async fn retrieve_filtered(&self, filter: &EventFilter) -> FormatedResult<A, E, M> {
+        let inner = self.read()?;
+        let start = filter
+            .from_position
+            .unwrap_or(0)
+            .saturating_sub(1)
+            .min(inner.events.len());
+        let end = filter
+            .to_position
+            .unwrap_or(inner.events.len())
+            .clamp(start, inner.events.len());
+
+        Ok(inner.events[start..end]
+            .iter()
+            .filter(|e| filter.matches(e))
+            .cloned()
+            .collect())
+    }
+
+    /// Retrive Events for query
+    ///
+    /// Returns the events of `aggregate_id` when provided, otherwise every event of the aggregate type
+    async fn retrieve_for_query(&self, aggregate_id: Option<&str>) -> FormatedResult<A, E, M> {
+        let inner = self.read()?;
+
+        match aggregate_id {
+            Some(id) => Ok(inner.stream(id).into_iter().cloned().collect()),
+            None => Ok(inner
+                .events
+                .iter()
+                .filter(|e| e.aggregate_type == A::aggregate_type())
+                .cloned()
+                .collect()),
+        }
+    }
+
+    /// Live outcome remembered under idempotency `key`
+    async fn remembered(&self, key: &str) -> Result<Option<IdempotencyRecord>, Error> {
+        Ok(self
+            .read()?
+            .keys
+            .iter()
+            .rev()
+            .find(|record| record.is_for(A::aggregate_type(), key))
+            .cloned())
+    }
+}
@@ -0,0 +1,76 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+
+use crate::{Aggregate, Error, Snapshot, SnapshotStore};
+
+/// InMemorySnapshotStore
+///
+/// Keeps the latest serialized snapshot of each aggregate, clones share the same snapshots.
+pub struct InMemorySnapshotStore<A: Aggregate> {
+    snapshots: Arc<RwLock<HashMap<(String, String), String>>>,
+    _a: PhantomData<A>,
+}
+
+impl<A: Aggregate> InMemorySnapshotStore<A> {
+    pub fn new() -> InMemorySnapshotStore<A> {
+        InMemorySnapshotStore {
+            snapshots: Arc::new(RwLock::new(HashMap::new())),
+            _a: PhantomData,
+        }
+    }
+}
+
+fn poisoned() -> Error {
+    Error::new(
+        "InMemorySnapshotStore lock poisoned",
+        Some("INTERNAL"),
+        None,
+    )
+}
+
+impl<A: Aggregate> Default for InMemorySnapshotStore<A> {
+    fn default() -> InMemorySnapshotStore<A> {
+        InMemorySnapshotStore::new()
+    }
+}
+
+impl<A: Aggregate> Clone for InMemorySnapshotStore<A> {
+    fn clone(&self) -> InMemorySnapshotStore<A> {
+        InMemorySnapshotStore {
+            snapshots: Arc::clone(&self.snapshots),
+            _a: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<A: Aggregate> SnapshotStore<A> for InMemorySnapshotStore<A> {
+    /// Load the latest snapshot of an aggregate
+    async fn load(&self, aggregate_id: &str) -> Result<Option<Snapshot<A>>, Error> {
+        let key = (A::aggregate_type().to_string(), aggregate_id.to_string());
+        let snapshots = self.snapshots.read().map_err(|_| poisoned())?;
+
+        match snapshots.get(&key) {
+            Some(data) => Ok(Some(serde_json::from_str(data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Save a snapshot, replacing older ones of the aggregate
+    async fn save(&self, snapshot: Snapshot<A>) -> Result<(), Error> {
+        let key = (
+            snapshot.aggregate_type.clone(),
+            snapshot.aggregate_id.clone(),
+        );
+        let data = serde_json::to_string(&snapshot)?;
+
+        self.snapshots
+            .write()
+            .map_err(|_| poisoned())?
+            .insert(key, data);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt::Debug;
+
+use crate::MetaData;
+
+/// Metadata recorded with each event
+///
+/// `CQRS`, `Store` and `FormatedEvent` take it as a type parameter, `MetaData`
+/// when left out. Any serializable type qualifies, `EventMeta` holds the
+/// well-known fields.
+pub trait Meta: Debug + Default + Clone + Serialize + DeserializeOwned + Sync + Send {}
+
+impl<T> Meta for T where T: Debug + Default + Clone + Serialize + DeserializeOwned + Sync + Send {}
+
+/// EventMeta
+///
+/// Well-known metadata fields, other entries go in `extra`
+///
+/// There is no correlation field: events carry it as
+/// `FormatedEvent::correlation_id`, taken from `Command::correlation_id`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventMeta {
+    /// User or service issuing the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Where the command came from, e.g. an IP address or application name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// When the client issued the command, by its own clock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_timestamp: Option<DateTime<Utc>>,
+    #[serde(default, flatten)]
+    pub extra: MetaData,
+}
+
+impl EventMeta {
+    pub fn new() -> EventMeta {
+        EventMeta::default()
+    }
+
+    pub fn with_actor(self, actor: &str) -> Self {
+        EventMeta {
+            actor: Some(actor.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_tenant(self, tenant: &str) -> Self {
+        EventMeta {
+            tenant: Some(tenant.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_source(self, source: &str) -> Self {
+        EventMeta {
+            source: Some(source.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_client_timestamp(self, client_timestamp: DateTime<Utc>) -> Self {
+        EventMeta {
+            client_timestamp: Some(client_timestamp),
+            ..self
+        }
+    }
+
+    /// Add an entry without a field of its own
+    pub fn with_extra(mut self, key: &str, value: &str) -> Self {
+        self.extra.insert(key.to_string(), value.to_string());
+        self
+    }
+}
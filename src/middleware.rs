@@ -0,0 +1,84 @@
+use async_trait::async_trait;
+use std::any::type_name;
+use std::time::{Duration, Instant};
+
+use crate::{Aggregate, AggregateContext, DomainEvent, Error, FormatedEvents, Meta, MetaData};
+
+/// Command being executed, handed to every `Middleware` hook
+#[derive(Debug, Clone)]
+pub struct CommandInfo {
+    /// Type name of the command
+    pub command: &'static str,
+    /// `Aggregate::aggregate_type` of the aggregate handling it
+    pub aggregate_type: &'static str,
+    /// `Command::id` before `Command::before` ran
+    pub aggregate_id: Option<String>,
+    /// When `CQRS::execute` was called
+    pub started_at: Instant,
+}
+
+impl CommandInfo {
+    pub(crate) fn new<A: Aggregate, C>(aggregate_id: Option<String>) -> CommandInfo {
+        CommandInfo {
+            command: type_name::<C>(),
+            aggregate_type: A::aggregate_type(),
+            aggregate_id,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Time since `CQRS::execute` was called
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+}
+
+/// Hooks run by `CQRS::execute` around each command
+///
+/// Every hook does nothing by default. Before hooks run in the order the
+/// middlewares were added and after hooks in reverse, so each middleware wraps
+/// the ones added after it. An error from a hook stops the command, nothing is
+/// appended and the error is returned after the `on_error` hooks ran.
+///
+/// `before_handle` and `after_handle` run on every attempt, retries after a
+/// version conflict start again from the metadata `before_command` left.
+#[async_trait]
+pub trait Middleware<A, E, M = MetaData>: Send + Sync
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    M: Meta,
+{
+    /// Runs before `Command::before`
+    async fn before_command(&self, _command: &CommandInfo, _meta: &mut M) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Runs before `Command::handle` with the assembled aggregate
+    async fn before_handle(
+        &self,
+        _command: &CommandInfo,
+        _context: &AggregateContext<A>,
+        _meta: &mut M,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Runs after `Command::handle` with the events to append
+    #[allow(clippy::ptr_arg)]
+    async fn after_handle(
+        &self,
+        _command: &CommandInfo,
+        _context: &AggregateContext<A>,
+        _events: &mut Vec<E>,
+        _meta: &mut M,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Runs once the events are appended, before the handlers
+    async fn after_append(&self, _command: &CommandInfo, _events: &FormatedEvents<A, E, M>) {}
+
+    /// Runs when the command fails, whichever step failed
+    async fn on_error(&self, _command: &CommandInfo, _error: &Error) {}
+}
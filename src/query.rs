@@ -1,12 +1,13 @@
 use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::Debug;
 
-use crate::{Aggregate, DomainEvent, FormatedEvent};
+use crate::{Aggregate, DomainEvent, FormatedEvent, Meta, MetaData};
 
-pub trait Query<A, E>: Debug + Default + Serialize + DeserializeOwned
+pub trait Query<A, E, M = MetaData>: Debug + Default + Serialize + DeserializeOwned
 where
     A: Aggregate,
     E: DomainEvent<A>,
+    M: Meta,
 {
-    fn populate(&mut self, event: &FormatedEvent<A, E>);
+    fn populate(&mut self, event: &FormatedEvent<A, E, M>);
 }
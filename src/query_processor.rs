@@ -1,33 +1,52 @@
+use futures::StreamExt;
 use std::marker::PhantomData;
 
-use crate::{Aggregate, DomainEvent, Error, Query, Store};
+use crate::{Aggregate, DomainEvent, Error, EventFilter, Meta, MetaData, Query, Store};
 
-pub struct QueryProcessor<A, E, Q>
+pub struct QueryProcessor<A, E, Q, M = MetaData>
 where
     A: Aggregate,
     E: DomainEvent<A>,
-    Q: Query<A, E>,
+    Q: Query<A, E, M>,
+    M: Meta,
 {
     _a: PhantomData<A>,
     _e: PhantomData<E>,
     _q: PhantomData<Q>,
+    _m: PhantomData<M>,
 }
 
-impl<A, E, Q> QueryProcessor<A, E, Q>
+impl<A, E, Q, M> QueryProcessor<A, E, Q, M>
 where
     A: Aggregate,
     E: DomainEvent<A>,
-    Q: Query<A, E>,
+    Q: Query<A, E, M>,
+    M: Meta,
 {
-    pub async fn process<S: Store<A, E>>(
+    pub async fn process<S: Store<A, E, M>>(
         store: &S,
         aggregate_id: Option<&str>,
     ) -> Result<Q, Error> {
         let mut query = Q::default();
-        let events = store.retrieve_for_query(aggregate_id).await?;
+        let mut events = store.stream_for_query(aggregate_id);
 
-        for event in events {
-            query.populate(&event);
+        while let Some(event) = events.next().await {
+            query.populate(&event?);
+        }
+
+        Ok(query)
+    }
+
+    /// Populate the query with the events matching `filter`
+    pub async fn process_filtered<S: Store<A, E, M>>(
+        store: &S,
+        filter: &EventFilter,
+    ) -> Result<Q, Error> {
+        let mut query = Q::default();
+        let mut events = store.stream_filtered(filter);
+
+        while let Some(event) = events.next().await {
+            query.populate(&event?);
         }
 
         Ok(query)
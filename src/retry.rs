@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+/// Retry policy used by `CQRS::execute` when `Store::append` reports a version conflict
+///
+/// Every retry reloads the aggregate and handles the command again, waiting
+/// `backoff(attempt)` before doing so.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: u32,
+}
+
+impl RetryPolicy {
+    /// Create a new RetryPolicy with exponential backoff
+    pub fn new(max_retries: usize, initial_backoff: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// A policy that never retries
+    pub fn disabled() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Delay before the given retry attempt (starting from 0)
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt as u32);
+        let delay = self
+            .initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff);
+
+        delay.min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2,
+        }
+    }
+}
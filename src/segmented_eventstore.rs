@@ -0,0 +1,646 @@
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::marker::PhantomData;
+use std::{collections::HashMap, io, path::Path, sync::Arc, time::Duration};
+use tokio::{
+    fs::{self, OpenOptions},
+    sync::Mutex,
+};
+
+use crate::file_eventstore::lock_exclusive;
+use crate::idempotency::KeyFile;
+use crate::{
+    Aggregate, AggregateContext, CompressionPolicy, DomainEvent, Error, EventStream,
+    FileEventStore, FormatedEvents, FormatedResult, Handlers, IdempotencyRecord, Meta, MetaData,
+    Serializer, SnapshotStore, Store, StoreWarning, Upcasters, WarningHandler, CQRS,
+};
+
+/// When `SegmentedEventStore` seals the active segment and starts a new one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RolloverPolicy {
+    max_bytes: Option<u64>,
+    max_events: Option<usize>,
+}
+
+impl RolloverPolicy {
+    /// Roll over once the active segment holds `bytes` or more
+    pub fn size(bytes: u64) -> RolloverPolicy {
+        RolloverPolicy {
+            max_bytes: Some(bytes),
+            max_events: None,
+        }
+    }
+
+    /// Roll over once the active segment holds `events` lines or more
+    pub fn count(events: usize) -> RolloverPolicy {
+        RolloverPolicy {
+            max_bytes: None,
+            max_events: Some(events),
+        }
+    }
+
+    /// Checks if a segment of `bytes` and `lines` is full
+    pub fn should_roll(&self, bytes: u64, lines: usize) -> bool {
+        self.max_bytes.is_some_and(|max| bytes >= max)
+            || self.max_events.is_some_and(|max| lines >= max)
+    }
+}
+
+impl Default for RolloverPolicy {
+    fn default() -> RolloverPolicy {
+        RolloverPolicy::size(64 * 1024 * 1024)
+    }
+}
+
+/// Layout of a `SegmentedEventStore` directory, saved as `manifest.json`
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct SegmentManifest {
+    /// Segments in log order, the last one is active
+    pub segments: Vec<Segment>,
+}
+
+/// Segment file of a `SegmentedEventStore`
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Segment {
+    /// File name in the store directory, a log readable by `FileEventStore`
+    pub file: String,
+    /// Global position of the line before the segment
+    pub base: usize,
+    /// Lines of the segment, `0` while it is active
+    pub lines: usize,
+    /// Sealed segments are read-only and never written again, except by compaction
+    pub sealed: bool,
+    /// Highest version compaction dropped from the segment, by aggregate id
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub compacted: HashMap<String, usize>,
+}
+
+impl Segment {
+    /// The `number`th segment, starting after global position `base`
+    fn new(number: usize, base: usize) -> Segment {
+        Segment {
+            file: format!("segment-{:08}.jsonl", number),
+            base,
+            lines: 0,
+            sealed: false,
+            compacted: HashMap::new(),
+        }
+    }
+}
+
+/// SegmentedEventStore
+///
+/// NOTE: Only use the for develpment and not for production
+///
+/// Keeps the log in a directory of segment files. Events are appended to the
+/// last segment, which is sealed and made read-only once the `RolloverPolicy`
+/// says it is full. Each segment is a `FileEventStore` log with its own side
+/// index, and `manifest.json` lists them in order with the global position
+/// each one starts after, so the layout stays readable one segment at a time.
+///
+/// Positions are global: line `n` of a segment is at `base + n`.
+///
+/// `compact` rewrites sealed segments without the events covered by snapshots.
+/// Reads that would replay dropped events fail with a `COMPACTED` error
+/// instead of building the wrong state, aggregates are rebuilt through a
+/// `SnapshottingStore` using the same snapshots.
+///
+/// Idempotency keys are remembered in `keys.jsonl`, next to the manifest,
+/// written before their events like those of `FileEventStore`.
+pub struct SegmentedEventStore<A: Aggregate, E: DomainEvent<A>, M: Meta = MetaData> {
+    dir: String,
+    policy: RolloverPolicy,
+    serializer: Serializer,
+    compression: CompressionPolicy,
+    upcasters: Upcasters,
+    lock_timeout: Duration,
+    warning_handler: Option<WarningHandler>,
+    write_lock: Arc<Mutex<()>>,
+    segments: Arc<Mutex<Segments<A, E, M>>>,
+    _a: PhantomData<A>,
+    _e: PhantomData<E>,
+    _m: PhantomData<M>,
+}
+
+/// Stores of the segments opened so far, by file name
+type Segments<A, E, M> = HashMap<String, FileEventStore<A, E, M>>;
+
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> SegmentedEventStore<A, E, M> {
+    pub fn new(dir: &str) -> SegmentedEventStore<A, E, M> {
+        SegmentedEventStore {
+            dir: dir.to_owned(),
+            policy: RolloverPolicy::default(),
+            serializer: Serializer::default(),
+            compression: CompressionPolicy::default(),
+            upcasters: Upcasters::default(),
+            lock_timeout: Duration::from_secs(5),
+            warning_handler: None,
+            write_lock: Arc::new(Mutex::new(())),
+            segments: Arc::new(Mutex::new(HashMap::new())),
+            _a: PhantomData,
+            _e: PhantomData,
+            _m: PhantomData,
+        }
+    }
+
+    /// Creates CQRS with store
+    pub fn create_cqrs(
+        dir: &str,
+        handlers: Handlers<A, E, M>,
+    ) -> CQRS<A, E, SegmentedEventStore<A, E, M>, M> {
+        CQRS::new(SegmentedEventStore::new(dir), handlers)
+    }
+
+    /// Set when the active segment is sealed
+    pub fn with_rollover_policy(self, policy: RolloverPolicy) -> Self {
+        SegmentedEventStore { policy, ..self }
+    }
+
+    /// Set the format payloads are appended in
+    pub fn with_serializer(self, serializer: Serializer) -> Self {
+        SegmentedEventStore { serializer, ..self }
+    }
+
+    /// Set which payloads are compressed
+    pub fn with_compression(self, compression: CompressionPolicy) -> Self {
+        SegmentedEventStore {
+            compression,
+            ..self
+        }
+    }
+
+    /// Set the upcasters applied to records of an older schema version
+    pub fn with_upcasters(self, upcasters: Upcasters) -> Self {
+        SegmentedEventStore { upcasters, ..self }
+    }
+
+    /// Set how long appends wait for another process to release the store lock
+    pub fn with_lock_timeout(self, lock_timeout: Duration) -> Self {
+        SegmentedEventStore {
+            lock_timeout,
+            ..self
+        }
+    }
+
+    /// Set the handler receiving the warnings of the segments, see `StoreWarning`
+    pub fn with_warning_handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(&StoreWarning) + Send + Sync + 'static,
+    {
+        SegmentedEventStore {
+            warning_handler: Some(Arc::new(handler)),
+            ..self
+        }
+    }
+
+    /// File listing the segments
+    pub fn manifest_path(&self) -> String {
+        self.path("manifest.json")
+    }
+
+    /// File remembering idempotency keys
+    pub fn keys_path(&self) -> String {
+        self.path("keys.jsonl")
+    }
+
+    /// Path of a segment file
+    pub fn segment_path(&self, segment: &Segment) -> String {
+        self.path(&segment.file)
+    }
+
+    /// Fail unless the events of `aggregate_id` after `version` are all still in the log
+    fn check_replay(
+        &self,
+        manifest: &SegmentManifest,
+        aggregate_id: &str,
+        version: usize,
+    ) -> Result<(), Error> {
+        for segment in manifest.segments.iter() {
+            match segment.compacted.get(aggregate_id) {
+                Some(dropped) if *dropped > version => {
+                    let reason = format!(
+                        "events of {} up to version {} were dropped, read from a snapshot",
+                        aggregate_id, dropped
+                    );
+                    return Err(Error::compacted(&self.segment_path(segment), &reason));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn path(&self, file: &str) -> String {
+        Path::new(&self.dir)
+            .join(file)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Current layout of the store, a single empty segment before the first append
+    pub async fn manifest(&self) -> Result<SegmentManifest, Error> {
+        let path = self.manifest_path();
+        let file_error = |e: io::Error| {
+            Error::file(
+                "SegmentedEventStore: cannot read manifest",
+                &path,
+                None,
+                &e.to_string(),
+            )
+        };
+
+        match fs::read_to_string(&path).await {
+            Ok(data) => Ok(serde_json::from_str(&data)
+                .map_err(|e| Error::corrupt(&path, e.line(), &e.to_string()))?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir_all(&self.dir).await.map_err(file_error)?;
+
+                Ok(SegmentManifest {
+                    segments: vec![Segment::new(1, 0)],
+                })
+            }
+            Err(e) => Err(file_error(e)),
+        }
+    }
+
+    /// Replace the manifest at once
+    async fn save_manifest(&self, manifest: &SegmentManifest) -> Result<(), Error> {
+        let path = self.manifest_path();
+        let file_error = |e: io::Error| {
+            Error::file(
+                "SegmentedEventStore: cannot write manifest",
+                &path,
+                None,
+                &e.to_string(),
+            )
+        };
+
+        let tmp_path = format!("{}.tmp", path);
+        let data = serde_json::to_string_pretty(manifest)? + "\n";
+        fs::write(&tmp_path, data).await.map_err(file_error)?;
+        fs::rename(&tmp_path, &path).await.map_err(file_error)
+    }
+
+    /// Store of a segment file, clones share the segment stores and their indexes
+    async fn segment(&self, segment: &Segment) -> FileEventStore<A, E, M> {
+        let mut segments = self.segments.lock().await;
+
+        segments
+            .entry(segment.file.clone())
+            .or_insert_with(|| self.segment_store(&self.segment_path(segment)))
+            .clone()
+    }
+
+    fn segment_store(&self, path: &str) -> FileEventStore<A, E, M> {
+        FileEventStore::new(path)
+            .with_serializer(self.serializer)
+            .with_compression(self.compression)
+            .with_upcasters(self.upcasters.clone())
+            .with_lock_timeout(self.lock_timeout)
+            .with_warnings(self.warning_handler.clone())
+    }
+
+    /// Take the store lock, keeping other processes from appending, rolling over or compacting
+    async fn lock_store(&self) -> Result<fs::File, Error> {
+        let path = self.path("manifest.lock");
+        let file_error = |e: io::Error| {
+            Error::file(
+                "SegmentedEventStore: cannot open lock file",
+                &path,
+                None,
+                &e.to_string(),
+            )
+        };
+
+        // The first append to a new store may come before anything created its directory
+        fs::create_dir_all(&self.dir).await.map_err(file_error)?;
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .await
+            .map_err(file_error)?;
+
+        lock_exclusive(file, &path, self.lock_timeout).await
+    }
+
+    /// Seal the active segment of `lines` lines and start a new one
+    async fn roll_over(&self, manifest: &mut SegmentManifest, lines: usize) -> Result<(), Error> {
+        let number = manifest.segments.len() + 1;
+        let active = match manifest.segments.last_mut() {
+            Some(active) => active,
+            None => return Ok(()),
+        };
+
+        // Saves the index of the segment, it can't be written once sealed
+        self.segment(active).await.recover().await?;
+
+        active.sealed = true;
+        active.lines = lines;
+        let path = self.segment_path(active);
+        let next = Segment::new(number, active.base + lines);
+        manifest.segments.push(next);
+        self.save_manifest(manifest).await?;
+
+        set_read_only(&path).await
+    }
+
+    /// Rewrite sealed segments without the events covered by `snapshots`
+    ///
+    /// Events at or below the snapshot version of their aggregate become blank
+    /// lines, so positions don't move. The last event of each stream in a segment
+    /// is kept for version checks. The manifest records what was dropped, so
+    /// reads that would replay it fail with a `COMPACTED` error: a compacted
+    /// store only rebuilds aggregates through a `SnapshottingStore` using the
+    /// same snapshots, and `read_all` only reads after the compacted segments.
+    ///
+    /// Returns the number of events dropped
+    pub async fn compact<S: SnapshotStore<A>>(&self, snapshots: &S) -> Result<usize, Error> {
+        let _guard = self.write_lock.lock().await;
+        let _lock = self.lock_store().await?;
+        let mut manifest = self.manifest().await?;
+        let mut dropped = 0;
+
+        for number in 0..manifest.segments.len() {
+            let segment = manifest.segments[number].clone();
+            if !segment.sealed {
+                continue;
+            }
+
+            let path = self.segment_path(&segment);
+            let tmp_path = format!("{}.compact", path);
+            let file_error = |e: io::Error| {
+                Error::file(
+                    "SegmentedEventStore: cannot replace segment",
+                    &path,
+                    None,
+                    &e.to_string(),
+                )
+            };
+
+            let (count, floors) = self
+                .segment(&segment)
+                .await
+                .compact_into(&tmp_path, snapshots)
+                .await?;
+            if count == 0 {
+                fs::remove_file(&tmp_path).await.map_err(file_error)?;
+                continue;
+            }
+
+            // Replays are refused before the events are gone, a crash in between costs nothing
+            let compacted = &mut manifest.segments[number].compacted;
+            for (aggregate_id, version) in floors {
+                let floor = compacted.entry(aggregate_id).or_insert(0);
+                *floor = version.max(*floor);
+            }
+            self.save_manifest(&manifest).await?;
+
+            // Offsets moved, the index of the segment is rebuilt from the new file
+            fs::rename(&tmp_path, &path).await.map_err(file_error)?;
+            let store = self.segment_store(&path);
+            match fs::remove_file(store.index_path()).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(file_error(e)),
+                _ => {}
+            }
+            store.recover().await?;
+            set_read_only(&path).await?;
+
+            self.segments
+                .lock()
+                .await
+                .insert(segment.file.clone(), store);
+            dropped += count;
+        }
+
+        Ok(dropped)
+    }
+}
+
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Clone for SegmentedEventStore<A, E, M> {
+    fn clone(&self) -> SegmentedEventStore<A, E, M> {
+        SegmentedEventStore {
+            dir: self.dir.clone(),
+            policy: self.policy,
+            serializer: self.serializer,
+            compression: self.compression,
+            upcasters: self.upcasters.clone(),
+            lock_timeout: self.lock_timeout,
+            warning_handler: self.warning_handler.clone(),
+            write_lock: self.write_lock.clone(),
+            segments: self.segments.clone(),
+            _a: PhantomData,
+            _e: PhantomData,
+            _m: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Store<A, E, M> for SegmentedEventStore<A, E, M> {
+    /// Rebuilding the aggregate
+    async fn assemble_aggregate(&self, id: Option<String>) -> Result<AggregateContext<A>, Error> {
+        let mut context = AggregateContext::default();
+        context.set_id(id.clone());
+
+        // Populate aggregate if id is provided
+        if let Some(x) = id {
+            let mut events = self.stream(x.as_str());
+            while let Some(fmt_event) = events.try_next().await? {
+                fmt_event.payload.apply(&mut context.aggregate);
+                context.version = fmt_event.version;
+            }
+        }
+
+        Ok(context)
+    }
+
+    /// Append formated events to the active segment, rolling over once it is full
+    async fn append(
+        &self,
+        events: Vec<E>,
+        context: &AggregateContext<A>,
+        meta: M,
+    ) -> FormatedResult<A, E, M> {
+        // The store lock keeps the manifest and the version of sealed segments still
+        let _guard = self.write_lock.lock().await;
+        let _lock = self.lock_store().await?;
+
+        let mut manifest = match fs::metadata(self.manifest_path()).await {
+            Ok(_) => self.manifest().await?,
+            Err(_) => {
+                let manifest = self.manifest().await?;
+                self.save_manifest(&manifest).await?;
+                manifest
+            }
+        };
+        let (active, sealed) = match manifest.segments.split_last() {
+            Some(segments) => segments,
+            None => {
+                return Err(Error::corrupt(
+                    &self.manifest_path(),
+                    1,
+                    "manifest lists no segment",
+                ))
+            }
+        };
+
+        // The stream's latest sealed segment holds its version
+        let mut previous_version = 0;
+        for segment in sealed.iter().rev() {
+            previous_version = self.segment(segment).await.version(&context.id).await?;
+            if previous_version > 0 {
+                break;
+            }
+        }
+
+        let store = self.segment(active).await;
+        let base = active.base;
+        let keys = self.keys_path();
+        let events = store
+            .append_after(events, context, meta, previous_version, &keys, self)
+            .await?;
+
+        let (bytes, lines) = store.committed().await?;
+        if self.policy.should_roll(bytes, lines) {
+            self.roll_over(&mut manifest, lines).await?;
+        }
+
+        Ok(at_base(events, base))
+    }
+
+    /// Retrive Events for command store
+    async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<A, E, M> {
+        self.retrieve_from(aggregate_id, 0).await
+    }
+
+    /// Retrive Events of an aggregate after `version`, reading only the lines of the stream
+    async fn retrieve_from(&self, aggregate_id: &str, version: usize) -> FormatedResult<A, E, M> {
+        self.stream_from(aggregate_id, version).try_collect().await
+    }
+
+    /// Stream Events for command store, a segment at a time
+    fn stream<'a>(&'a self, aggregate_id: &'a str) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        self.stream_from(aggregate_id, 0)
+    }
+
+    /// Stream Events of an aggregate after `version`, a segment at a time
+    fn stream_from<'a>(&'a self, aggregate_id: &'a str, version: usize) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        let manifest = async move {
+            let manifest = self.manifest().await?;
+            self.check_replay(&manifest, aggregate_id, version)?;
+
+            Ok::<_, Error>(manifest)
+        };
+
+        stream::once(manifest)
+            .map_ok(move |manifest| {
+                stream::iter(manifest.segments)
+                    .then(
+                        move |segment| async move { (self.segment(&segment).await, segment.base) },
+                    )
+                    .flat_map(move |(store, base)| {
+                        store
+                            .stream_indexed(aggregate_id, version)
+                            .map_ok(move |mut event| {
+                                event.position += base;
+                                event
+                            })
+                    })
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Read events from global `position` on, ordered by position
+    async fn read_all(
+        &self,
+        position: usize,
+        aggregate_type: Option<&str>,
+    ) -> FormatedResult<A, E, M> {
+        let mut events = Vec::new();
+
+        for segment in self.manifest().await?.segments.iter() {
+            // Sealed segments before `position` are skipped whole
+            if segment.sealed && segment.base + segment.lines < position {
+                continue;
+            }
+
+            // The read would silently miss the dropped events
+            if !segment.compacted.is_empty()
+                && aggregate_type.map_or(true, |t| t == A::aggregate_type())
+            {
+                let reason = format!(
+                    "events were dropped, read from position {} on",
+                    segment.base + segment.lines + 1
+                );
+                return Err(Error::compacted(&self.segment_path(segment), &reason));
+            }
+
+            let store = self.segment(segment).await;
+            let found = store
+                .read_all(position.saturating_sub(segment.base), aggregate_type)
+                .await?;
+            events.extend(at_base(found, segment.base));
+        }
+
+        Ok(events)
+    }
+
+    /// Retrive Events for query
+    ///
+    /// Returns the events of `aggregate_id` when provided, otherwise every event of the aggregate type
+    async fn retrieve_for_query(&self, aggregate_id: Option<&str>) -> FormatedResult<A, E, M> {
+        match aggregate_id {
+            Some(id) => self.retrieve(id).await,
+            None => self.read_all(0, Some(A::aggregate_type())).await,
+        }
+    }
+
+    /// Live outcome remembered under idempotency `key`
+    async fn remembered(&self, key: &str) -> Result<Option<IdempotencyRecord>, Error> {
+        let keys = self.keys_path();
+        KeyFile { path: &keys }.written(self, key).await
+    }
+}
+
+/// Move events of a segment to global positions
+fn at_base<A: Aggregate, E: DomainEvent<A>, M: Meta>(
+    mut events: FormatedEvents<A, E, M>,
+    base: usize,
+) -> FormatedEvents<A, E, M> {
+    for event in events.iter_mut() {
+        event.position += base;
+    }
+
+    events
+}
+
+/// Make a sealed segment read-only
+async fn set_read_only(path: &str) -> Result<(), Error> {
+    let file_error = |e: io::Error| {
+        Error::file(
+            "SegmentedEventStore: cannot seal segment",
+            path,
+            None,
+            &e.to_string(),
+        )
+    };
+
+    let mut permissions = fs::metadata(path).await.map_err(file_error)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)
+        .await
+        .map_err(file_error)
+}
@@ -0,0 +1,108 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::Error;
+
+/// Encoding of event payloads in store records
+///
+/// JSON is always available, the binary formats need their cargo feature:
+/// `cbor`, `msgpack` and `bincode`. Each record names its format, so a log can
+/// mix formats while it is migrated from one to another.
+///
+/// Used by `FileEventStore`, which writes binary formats as length-prefixed
+/// binary records rather than JSON lines. The SQL stores don't use it, they
+/// keep payloads in their JSON columns so they stay queryable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Serializer {
+    #[default]
+    Json,
+    Cbor,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Bincode,
+}
+
+impl Serializer {
+    /// Name recorded with each record
+    pub fn name(&self) -> &'static str {
+        match self {
+            Serializer::Json => "json",
+            Serializer::Cbor => "cbor",
+            Serializer::MessagePack => "msgpack",
+            Serializer::Bincode => "bincode",
+        }
+    }
+
+    /// Checks if the format was compiled in
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            Serializer::Json => true,
+            Serializer::Cbor => cfg!(feature = "cbor"),
+            Serializer::MessagePack => cfg!(feature = "msgpack"),
+            Serializer::Bincode => cfg!(feature = "bincode"),
+        }
+    }
+
+    /// Checks if the format is text, which `FileEventStore` writes as JSON lines
+    pub fn is_text(&self) -> bool {
+        *self == Serializer::Json
+    }
+
+    /// Checks if payloads can be read without their type, which `Upcasters` need
+    pub fn is_self_describing(&self) -> bool {
+        *self != Serializer::Bincode
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        self.encode(value)
+            .map_err(|reason| self.error("Serializer: cannot serialize", &reason))
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        self.decode(bytes)
+            .map_err(|reason| self.error("Serializer: cannot deserialize", &reason))
+    }
+
+    /// Serialize `value`, failures give the reason
+    pub(crate) fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Serializer::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            #[cfg(feature = "cbor")]
+            Serializer::Cbor => serde_cbor::to_vec(value).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            Serializer::MessagePack => rmp_serde::to_vec(value).map_err(|e| e.to_string()),
+            #[cfg(feature = "bincode")]
+            Serializer::Bincode => bincode::serialize(value).map_err(|e| e.to_string()),
+            #[allow(unreachable_patterns)]
+            _ => Err(self.disabled()),
+        }
+    }
+
+    /// Deserialize `bytes`, failures give the reason
+    pub(crate) fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Serializer::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "cbor")]
+            Serializer::Cbor => serde_cbor::from_slice(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            Serializer::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "bincode")]
+            Serializer::Bincode => bincode::deserialize(bytes).map_err(|e| e.to_string()),
+            #[allow(unreachable_patterns)]
+            _ => Err(self.disabled()),
+        }
+    }
+
+    fn disabled(&self) -> String {
+        format!("{} format needs the `{}` feature", self.name(), self.name())
+    }
+
+    fn error(&self, message: &'static str, reason: &str) -> Error {
+        let mut extension = HashMap::new();
+        extension.insert("format".to_string(), self.name().to_string());
+        extension.insert("reason".to_string(), reason.to_string());
+
+        Error::new(message, Some("INTERNAL"), Some(extension))
+    }
+}
@@ -0,0 +1,64 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{Aggregate, Error};
+
+/// Aggregate state at a version of its stream
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot<A> {
+    pub aggregate_id: String,
+    pub aggregate_type: String,
+    pub version: usize,
+    pub aggregate: A,
+    pub created_at: String,
+}
+
+impl<A: Aggregate> Snapshot<A> {
+    /// Create a new Snapshot
+    pub fn new(aggregate_id: &str, version: usize, aggregate: A) -> Snapshot<A> {
+        Snapshot {
+            aggregate_id: aggregate_id.to_string(),
+            aggregate_type: A::aggregate_type().to_string(),
+            version,
+            aggregate,
+            created_at: Utc::now().to_rfc2822(),
+        }
+    }
+}
+
+/// When to take a snapshot of an aggregate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SnapshotPolicy {
+    every: usize,
+}
+
+impl SnapshotPolicy {
+    /// Snapshot each time the stream grows past a multiple of `events`, `0` never snapshots
+    pub fn every(events: usize) -> SnapshotPolicy {
+        SnapshotPolicy { every: events }
+    }
+
+    /// Checks if appending moved the stream from `previous_version` past a snapshot point
+    pub fn should_snapshot(&self, previous_version: usize, version: usize) -> bool {
+        match self.every {
+            0 => false,
+            n => previous_version / n != version / n,
+        }
+    }
+}
+
+impl Default for SnapshotPolicy {
+    fn default() -> SnapshotPolicy {
+        SnapshotPolicy::every(100)
+    }
+}
+
+#[async_trait]
+pub trait SnapshotStore<A: Aggregate>: Clone + Sync + Send {
+    /// Load the latest snapshot of an aggregate
+    async fn load(&self, aggregate_id: &str) -> Result<Option<Snapshot<A>>, Error>;
+
+    /// Save a snapshot, replacing older ones of the aggregate
+    async fn save(&self, snapshot: Snapshot<A>) -> Result<(), Error>;
+}
@@ -0,0 +1,267 @@
+use async_trait::async_trait;
+use futures::stream::TryStreamExt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::{
+    Aggregate, AggregateContext, DomainEvent, Error, EventFilter, EventStream, FormatedResult,
+    IdempotencyRecord, Meta, MetaData, Snapshot, SnapshotPolicy, SnapshotStore, Store,
+    StoreWarning, WarningHandler,
+};
+
+/// SnapshottingStore
+///
+/// Wraps a `Store` so aggregates are rebuilt from their latest snapshot plus the
+/// events appended after it. A snapshot is saved whenever the `SnapshotPolicy` says so.
+///
+/// Snapshots only save work: an aggregate whose snapshot can't be loaded is
+/// rebuilt from all its events, and events stay appended when their snapshot
+/// can't be saved. Both are reported to the `WarningHandler` set with
+/// `with_warning_handler`.
+pub struct SnapshottingStore<A, E, ES, SS, M = MetaData>
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    ES: Store<A, E, M>,
+    SS: SnapshotStore<A>,
+    M: Meta,
+{
+    store: ES,
+    snapshots: SS,
+    policy: SnapshotPolicy,
+    warning_handler: Option<WarningHandler>,
+    _a: PhantomData<A>,
+    _e: PhantomData<E>,
+    _m: PhantomData<M>,
+}
+
+impl<A, E, ES, SS, M> SnapshottingStore<A, E, ES, SS, M>
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    ES: Store<A, E, M>,
+    SS: SnapshotStore<A>,
+    M: Meta,
+{
+    pub fn new(
+        store: ES,
+        snapshots: SS,
+        policy: SnapshotPolicy,
+    ) -> SnapshottingStore<A, E, ES, SS, M> {
+        SnapshottingStore {
+            store,
+            snapshots,
+            policy,
+            warning_handler: None,
+            _a: PhantomData,
+            _e: PhantomData,
+            _m: PhantomData,
+        }
+    }
+
+    /// Set the handler receiving warnings, see `StoreWarning`
+    pub fn with_warning_handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(&StoreWarning) + Send + Sync + 'static,
+    {
+        SnapshottingStore {
+            warning_handler: Some(Arc::new(handler)),
+            ..self
+        }
+    }
+
+    /// Pass `warning` to the warning handler, if there is one
+    fn warn(&self, warning: StoreWarning) {
+        if let Some(handler) = &self.warning_handler {
+            handler(&warning);
+        }
+    }
+
+    /// The wrapped event store
+    pub fn store(&self) -> &ES {
+        &self.store
+    }
+
+    /// The snapshot store
+    pub fn snapshots(&self) -> &SS {
+        &self.snapshots
+    }
+}
+
+impl<A, E, ES, SS, M> Clone for SnapshottingStore<A, E, ES, SS, M>
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    ES: Store<A, E, M>,
+    SS: SnapshotStore<A>,
+    M: Meta,
+{
+    fn clone(&self) -> SnapshottingStore<A, E, ES, SS, M> {
+        SnapshottingStore {
+            store: self.store.clone(),
+            snapshots: self.snapshots.clone(),
+            policy: self.policy,
+            warning_handler: self.warning_handler.clone(),
+            _a: PhantomData,
+            _e: PhantomData,
+            _m: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<A, E, ES, SS, M> Store<A, E, M> for SnapshottingStore<A, E, ES, SS, M>
+where
+    A: Aggregate,
+    E: DomainEvent<A>,
+    ES: Store<A, E, M>,
+    SS: SnapshotStore<A>,
+    M: Meta,
+{
+    /// Rebuilding the aggregate from its latest snapshot
+    async fn assemble_aggregate(&self, id: Option<String>) -> Result<AggregateContext<A>, Error> {
+        let mut context = AggregateContext::default();
+        context.set_id(id.clone());
+
+        // Populate aggregate if id is provided
+        if let Some(x) = id {
+            match self.snapshots.load(&x).await {
+                Ok(Some(snapshot)) => {
+                    context.aggregate = snapshot.aggregate;
+                    context.version = snapshot.version;
+                }
+                Ok(None) => {}
+                Err(e) => self.warn(StoreWarning::SnapshotNotLoaded {
+                    aggregate_id: x.clone(),
+                    reason: e.message().to_string(),
+                }),
+            }
+
+            let mut events = self.store.stream_from(&x, context.version);
+            while let Some(fmt_event) = events.try_next().await? {
+                fmt_event.payload.apply(&mut context.aggregate);
+                context.version = fmt_event.version;
+            }
+        }
+
+        Ok(context)
+    }
+
+    ///  Append formated events to store, then snapshot if the policy says so
+    async fn append(
+        &self,
+        events: Vec<E>,
+        context: &AggregateContext<A>,
+        meta: M,
+    ) -> FormatedResult<A, E, M> {
+        let previous_version = context.version;
+        let due = self
+            .policy
+            .should_snapshot(previous_version, previous_version + events.len());
+
+        // Snapshots are stored serialized, so a serialized copy is the state they start from
+        let state = match due {
+            true => Some(serde_json::to_value(&context.aggregate)?),
+            false => None,
+        };
+
+        let commited_events = self.store.append(events, context, meta).await?;
+
+        if let (Some(state), Some(last)) = (state, commited_events.last()) {
+            // The events are stored, failing now would get them appended again
+            let saved = match serde_json::from_value::<A>(state) {
+                Ok(mut aggregate) => {
+                    for fmt_event in commited_events.iter() {
+                        fmt_event.payload.clone().apply(&mut aggregate);
+                    }
+                    let snapshot = Snapshot::new(&last.aggregate_id, last.version, aggregate);
+                    self.snapshots.save(snapshot).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = saved {
+                self.warn(StoreWarning::SnapshotNotSaved {
+                    aggregate_id: last.aggregate_id.clone(),
+                    version: last.version,
+                    reason: e.message().to_string(),
+                });
+            }
+        }
+
+        Ok(commited_events)
+    }
+
+    /// Retrive Events for command store
+    async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<A, E, M> {
+        self.store.retrieve(aggregate_id).await
+    }
+
+    /// Stream Events for command store
+    fn stream<'a>(&'a self, aggregate_id: &'a str) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        self.store.stream(aggregate_id)
+    }
+
+    /// Retrive Events of an aggregate after `version`
+    async fn retrieve_from(&self, aggregate_id: &str, version: usize) -> FormatedResult<A, E, M> {
+        self.store.retrieve_from(aggregate_id, version).await
+    }
+
+    /// Stream Events of an aggregate after `version`
+    fn stream_from<'a>(&'a self, aggregate_id: &'a str, version: usize) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        self.store.stream_from(aggregate_id, version)
+    }
+
+    /// Read events from global `position` on, ordered by position
+    async fn read_all(
+        &self,
+        position: usize,
+        aggregate_type: Option<&str>,
+    ) -> FormatedResult<A, E, M> {
+        self.store.read_all(position, aggregate_type).await
+    }
+
+    /// Retrive Events matching `filter`
+    async fn retrieve_filtered(&self, filter: &EventFilter) -> FormatedResult<A, E, M> {
+        self.store.retrieve_filtered(filter).await
+    }
+
+    /// Stream Events matching `filter`
+    fn stream_filtered<'a>(&'a self, filter: &'a EventFilter) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        self.store.stream_filtered(filter)
+    }
+
+    /// Retrive Events for query
+    async fn retrieve_for_query(&self, aggregate_id: Option<&str>) -> FormatedResult<A, E, M> {
+        self.store.retrieve_for_query(aggregate_id).await
+    }
+
+    /// Stream Events for query
+    fn stream_for_query<'a>(&'a self, aggregate_id: Option<&'a str>) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        self.store.stream_for_query(aggregate_id)
+    }
+
+    /// Live outcome remembered under idempotency `key`
+    async fn remembered(&self, key: &str) -> Result<Option<IdempotencyRecord>, Error> {
+        self.store.remembered(key).await
+    }
+}
@@ -0,0 +1,591 @@
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use rusqlite::{params, Connection, TransactionBehavior};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+use crate::compression::CompressedPayload;
+use crate::event_stream::{self, PAGE_SIZE};
+use crate::{
+    Aggregate, AggregateContext, CompressionPolicy, DomainEvent, Error, EventStream, FormatedEvent,
+    FormatedResult, Handlers, IdempotencyRecord, Meta, MetaData, Store, Upcasters, CQRS,
+};
+
+/// Every `FormatedEvent` field maps onto a column, `payload` and `meta` are stored as JSON text.
+/// `sequence` keeps the order events were appended in across aggregates, `schema_version`
+/// is the `DomainEvent::schema_version` of the payload. `compression` names the codec of
+/// a compressed payload and is null otherwise. `event_id` is null on rows written before
+/// event ids.
+///
+/// `idempotency_keys` holds each remembered `IdempotencyRecord` as JSON, written in the
+/// transaction appending its events. `expires_at` is in milliseconds since the epoch.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+    aggregate_type TEXT NOT NULL,
+    aggregate_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    payload TEXT NOT NULL,
+    meta TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    schema_version INTEGER NOT NULL DEFAULT 1,
+    event_id TEXT,
+    correlation_id TEXT,
+    causation_id TEXT,
+    compression TEXT,
+    UNIQUE (aggregate_type, aggregate_id, version)
+);
+CREATE INDEX IF NOT EXISTS events_by_aggregate ON events (aggregate_type, aggregate_id, version);
+CREATE INDEX IF NOT EXISTS events_by_type ON events (aggregate_type, sequence);
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    aggregate_type TEXT NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    record TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    PRIMARY KEY (aggregate_type, idempotency_key)
+);
+";
+
+const SELECT: &str =
+    "SELECT aggregate_id, aggregate_type, version, payload, meta, created_at, sequence, schema_version, event_id, correlation_id, causation_id, compression FROM events";
+
+/// Columns added since the first schema, with the statement adding them to older tables
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "schema_version",
+        "ALTER TABLE events ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 1",
+    ),
+    ("event_id", "ALTER TABLE events ADD COLUMN event_id TEXT"),
+    (
+        "correlation_id",
+        "ALTER TABLE events ADD COLUMN correlation_id TEXT",
+    ),
+    (
+        "causation_id",
+        "ALTER TABLE events ADD COLUMN causation_id TEXT",
+    ),
+    (
+        "compression",
+        "ALTER TABLE events ADD COLUMN compression TEXT",
+    ),
+];
+
+/// SqliteEventStore
+///
+/// Clones share the same connection.
+///
+/// Payloads are always stored as JSON text so they can be queried, the store
+/// takes no `Serializer`.
+pub struct SqliteEventStore<A: Aggregate, E: DomainEvent<A>, M: Meta = MetaData> {
+    conn: Arc<Mutex<Connection>>,
+    compression: CompressionPolicy,
+    upcasters: Upcasters,
+    _a: PhantomData<A>,
+    _e: PhantomData<E>,
+    _m: PhantomData<M>,
+}
+
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> SqliteEventStore<A, E, M> {
+    /// Opens (or creates) the database at `path` and sets up the schema
+    pub fn new(path: &str) -> Result<SqliteEventStore<A, E, M>, Error> {
+        SqliteEventStore::from_connection(Connection::open(path)?)
+    }
+
+    /// Uses an existing connection and sets up the schema
+    pub fn from_connection(conn: Connection) -> Result<SqliteEventStore<A, E, M>, Error> {
+        conn.execute_batch(SCHEMA)?;
+        for (column, migration) in MIGRATIONS {
+            if conn
+                .prepare(&format!("SELECT {} FROM events LIMIT 0", column))
+                .is_err()
+            {
+                conn.execute_batch(migration)?;
+            }
+        }
+
+        Ok(SqliteEventStore {
+            conn: Arc::new(Mutex::new(conn)),
+            compression: CompressionPolicy::default(),
+            upcasters: Upcasters::default(),
+            _a: PhantomData,
+            _e: PhantomData,
+            _m: PhantomData,
+        })
+    }
+
+    /// Creates CQRS with store
+    pub fn create_cqrs(
+        path: &str,
+        handlers: Handlers<A, E, M>,
+    ) -> Result<CQRS<A, E, Self, M>, Error> {
+        Ok(CQRS::new(SqliteEventStore::new(path)?, handlers))
+    }
+
+    /// Set which payloads are compressed, compressed payloads are stored as a
+    /// base64 JSON string with their codec in the `compression` column
+    pub fn with_compression(self, compression: CompressionPolicy) -> Self {
+        SqliteEventStore {
+            compression,
+            ..self
+        }
+    }
+
+    /// Set the upcasters applied to rows of an older schema version
+    pub fn with_upcasters(self, upcasters: Upcasters) -> Self {
+        SqliteEventStore { upcasters, ..self }
+    }
+
+    /// Run `f` with the connection on the blocking thread pool
+    async fn with_connection<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+
+        let result = tokio::task::spawn_blocking(move || match conn.lock() {
+            Ok(mut conn) => f(&mut conn),
+            Err(_) => Err(Error::new(
+                "SqliteEventStore lock poisoned",
+                Some("INTERNAL"),
+                None,
+            )),
+        })
+        .await;
+
+        match result {
+            Ok(x) => x,
+            Err(_) => Err(Error::new(
+                "SqliteEventStore task failed",
+                Some("INTERNAL"),
+                None,
+            )),
+        }
+    }
+
+    /// Find Events from store
+    async fn select(&self, filter: &'static str, args: Vec<String>) -> FormatedResult<A, E, M> {
+        let rows = self
+            .with_connection(move |conn| {
+                let mut stmt = conn.prepare(&format!("{} {}", SELECT, filter))?;
+                let rows = stmt
+                    .query_map(&args, |row| {
+                        Ok(EventRow {
+                            aggregate_id: row.get(0)?,
+                            aggregate_type: row.get(1)?,
+                            version: row.get(2)?,
+                            payload: row.get(3)?,
+                            meta: row.get(4)?,
+                            created_at: row.get(5)?,
+                            sequence: row.get(6)?,
+                            schema_version: row.get(7)?,
+                            event_id: row.get(8)?,
+                            correlation_id: row.get(9)?,
+                            causation_id: row.get(10)?,
+                            compression: row.get(11)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<EventRow>, rusqlite::Error>>()?;
+
+                Ok(rows)
+            })
+            .await?;
+
+        rows.into_iter()
+            .map(|row| row.into_event(&self.upcasters))
+            .collect()
+    }
+}
+
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Clone for SqliteEventStore<A, E, M> {
+    fn clone(&self) -> SqliteEventStore<A, E, M> {
+        SqliteEventStore {
+            conn: Arc::clone(&self.conn),
+            compression: self.compression,
+            upcasters: self.upcasters.clone(),
+            _a: PhantomData,
+            _e: PhantomData,
+            _m: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<A: Aggregate, E: DomainEvent<A>, M: Meta> Store<A, E, M> for SqliteEventStore<A, E, M> {
+    /// Rebuilding the aggregate
+    async fn assemble_aggregate(&self, id: Option<String>) -> Result<AggregateContext<A>, Error> {
+        let mut context = AggregateContext::default();
+        context.set_id(id.clone());
+
+        // Populate aggregate if id is provided
+        if let Some(x) = id {
+            let mut events = self.stream(x.as_str());
+            while let Some(fmt_event) = events.try_next().await? {
+                fmt_event.payload.apply(&mut context.aggregate);
+                context.version = fmt_event.version;
+            }
+        }
+
+        Ok(context)
+    }
+
+    ///  Append formated events to store
+    ///
+    /// All events are inserted in one transaction, along with the idempotency key
+    async fn append(
+        &self,
+        events: Vec<E>,
+        context: &AggregateContext<A>,
+        meta: M,
+    ) -> FormatedResult<A, E, M> {
+        let mut formated_events = FormatedEvent::create_in(context, events, meta);
+
+        if formated_events.is_empty() && context.idempotency.is_none() {
+            return Ok(Vec::default());
+        }
+
+        let rows = formated_events
+            .iter()
+            .map(|event| EventRow::from_event(event, self.compression))
+            .collect::<Result<Vec<EventRow>, Error>>()?;
+        let key = match &context.idempotency {
+            Some(idempotency) => Some(KeyRow::from_record(
+                idempotency.record(context, &formated_events),
+            )?),
+            None => None,
+        };
+        let aggregate_id = context.id.clone();
+        let expected_version = context.version;
+
+        let positions = self
+            .with_connection(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+                if let Some(key) = &key {
+                    let remembered: i64 = tx.query_row(
+                        "SELECT COUNT(*) FROM idempotency_keys
+                     WHERE aggregate_type = ?1 AND idempotency_key = ?2 AND expires_at > ?3",
+                        params![A::aggregate_type(), key.key, now_millis()],
+                        |row| row.get(0),
+                    )?;
+                    if remembered > 0 {
+                        return Err(Error::duplicate(A::aggregate_type(), &key.key));
+                    }
+                }
+
+                // Check expected version against the stored stream
+                let current_version: i64 = tx.query_row(
+                    "SELECT COALESCE(MAX(version), 0) FROM events
+                 WHERE aggregate_type = ?1 AND aggregate_id = ?2",
+                    params![A::aggregate_type(), aggregate_id],
+                    |row| row.get(0),
+                )?;
+
+                if !rows.is_empty() && current_version as usize != expected_version {
+                    return Err(Error::conflict(
+                        &aggregate_id,
+                        expected_version,
+                        current_version as usize,
+                    ));
+                }
+
+                let mut positions = Vec::new();
+                for row in rows.iter() {
+                    let inserted = tx.execute(
+                        "INSERT INTO events
+                     (aggregate_type, aggregate_id, version, payload, meta, created_at, schema_version, event_id, correlation_id, causation_id, compression)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                        params![
+                            row.aggregate_type,
+                            row.aggregate_id,
+                            row.version,
+                            row.payload,
+                            row.meta,
+                            row.created_at,
+                            row.schema_version,
+                            row.event_id,
+                            row.correlation_id,
+                            row.causation_id,
+                            row.compression
+                        ],
+                    );
+
+                    // The unique constraint backs up the version check
+                    if let Err(e) = inserted {
+                        return match is_constraint_violation(&e) {
+                            true => Err(Error::conflict(
+                                &aggregate_id,
+                                expected_version,
+                                row.version as usize,
+                            )),
+                            false => Err(e.into()),
+                        };
+                    }
+                    positions.push(tx.last_insert_rowid());
+                }
+
+                // Remembered with the events, dropping expired keys
+                if let Some(key) = &key {
+                    tx.execute(
+                        "DELETE FROM idempotency_keys WHERE expires_at <= ?1",
+                        params![now_millis()],
+                    )?;
+                    tx.execute(
+                        "INSERT OR REPLACE INTO idempotency_keys
+                     (aggregate_type, idempotency_key, record, expires_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                        params![A::aggregate_type(), key.key, key.record, key.expires_at],
+                    )?;
+                }
+
+                tx.commit()?;
+                Ok(positions)
+            })
+            .await?;
+
+        for (event, position) in formated_events.iter_mut().zip(positions) {
+            event.position = position as usize;
+        }
+
+        Ok(formated_events)
+    }
+
+    /// Retrive Events for command store
+    async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<A, E, M> {
+        self.select(
+            "WHERE aggregate_type = ?1 AND aggregate_id = ?2 ORDER BY version",
+            vec![A::aggregate_type().to_string(), aggregate_id.to_string()],
+        )
+        .await
+    }
+
+    /// Stream Events for command store, `PAGE_SIZE` rows at a time
+    fn stream<'a>(&'a self, aggregate_id: &'a str) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        self.stream_from(aggregate_id, 0)
+    }
+
+    /// Retrive Events of an aggregate after `version`
+    async fn retrieve_from(&self, aggregate_id: &str, version: usize) -> FormatedResult<A, E, M> {
+        self.select(
+            "WHERE aggregate_type = ?1 AND aggregate_id = ?2 AND version > CAST(?3 AS INTEGER) ORDER BY version",
+            vec![
+                A::aggregate_type().to_string(),
+                aggregate_id.to_string(),
+                version.to_string(),
+            ],
+        )
+        .await
+    }
+
+    /// Stream Events of an aggregate after `version`, `PAGE_SIZE` rows at a time
+    fn stream_from<'a>(&'a self, aggregate_id: &'a str, version: usize) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        event_stream::paged(move |after| {
+            self.select(
+                "WHERE aggregate_type = ?1 AND aggregate_id = ?2 AND version > CAST(?3 AS INTEGER) AND sequence > CAST(?4 AS INTEGER) ORDER BY sequence LIMIT CAST(?5 AS INTEGER)",
+                vec![
+                    A::aggregate_type().to_string(),
+                    aggregate_id.to_string(),
+                    version.to_string(),
+                    after.to_string(),
+                    PAGE_SIZE.to_string(),
+                ],
+            )
+        })
+    }
+
+    /// Read events from global `position` on, ordered by position
+    async fn read_all(
+        &self,
+        position: usize,
+        aggregate_type: Option<&str>,
+    ) -> FormatedResult<A, E, M> {
+        match aggregate_type {
+            Some(aggregate_type) => {
+                self.select(
+                    "WHERE sequence >= CAST(?1 AS INTEGER) AND aggregate_type = ?2 ORDER BY sequence",
+                    vec![position.to_string(), aggregate_type.to_string()],
+                )
+                .await
+            }
+            None => {
+                self.select(
+                    "WHERE sequence >= CAST(?1 AS INTEGER) ORDER BY sequence",
+                    vec![position.to_string()],
+                )
+                .await
+            }
+        }
+    }
+
+    /// Retrive Events for query
+    ///
+    /// Returns the events of `aggregate_id` when provided, otherwise every event of the aggregate type
+    async fn retrieve_for_query(&self, aggregate_id: Option<&str>) -> FormatedResult<A, E, M> {
+        match aggregate_id {
+            Some(id) => self.retrieve(id).await,
+            None => {
+                self.select(
+                    "WHERE aggregate_type = ?1 ORDER BY sequence",
+                    vec![A::aggregate_type().to_string()],
+                )
+                .await
+            }
+        }
+    }
+
+    /// Stream Events for query, `PAGE_SIZE` rows at a time
+    fn stream_for_query<'a>(&'a self, aggregate_id: Option<&'a str>) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        match aggregate_id {
+            Some(id) => self.stream(id),
+            None => event_stream::paged(move |after| {
+                self.select(
+                    "WHERE aggregate_type = ?1 AND sequence > CAST(?2 AS INTEGER) ORDER BY sequence LIMIT CAST(?3 AS INTEGER)",
+                    vec![
+                        A::aggregate_type().to_string(),
+                        after.to_string(),
+                        PAGE_SIZE.to_string(),
+                    ],
+                )
+            }),
+        }
+    }
+
+    /// Live outcome remembered under idempotency `key`
+    async fn remembered(&self, key: &str) -> Result<Option<IdempotencyRecord>, Error> {
+        let key = key.to_string();
+        let record = self
+            .with_connection(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT record FROM idempotency_keys
+                 WHERE aggregate_type = ?1 AND idempotency_key = ?2 AND expires_at > ?3",
+                )?;
+                let mut rows = stmt.query(params![A::aggregate_type(), key, now_millis()])?;
+                match rows.next()? {
+                    Some(row) => Ok(Some(row.get::<_, String>(0)?)),
+                    None => Ok(None),
+                }
+            })
+            .await?;
+
+        match record {
+            Some(record) => Ok(Some(serde_json::from_str(&record)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A row of the `idempotency_keys` table, without its aggregate type
+struct KeyRow {
+    key: String,
+    record: String,
+    expires_at: i64,
+}
+
+impl KeyRow {
+    fn from_record(record: IdempotencyRecord) -> Result<KeyRow, Error> {
+        Ok(KeyRow {
+            key: record.key.clone(),
+            record: serde_json::to_string(&record)?,
+            expires_at: record.expires_at.timestamp_millis(),
+        })
+    }
+}
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn is_constraint_violation(err: &rusqlite::Error) -> bool {
+    match err {
+        rusqlite::Error::SqliteFailure(e, _) => e.code == rusqlite::ErrorCode::ConstraintViolation,
+        _ => false,
+    }
+}
+
+/// A row of the `events` table
+struct EventRow {
+    aggregate_id: String,
+    aggregate_type: String,
+    version: i64,
+    payload: String,
+    meta: String,
+    created_at: String,
+    sequence: i64,
+    schema_version: i64,
+    event_id: Option<String>,
+    correlation_id: Option<String>,
+    causation_id: Option<String>,
+    compression: Option<String>,
+}
+
+impl EventRow {
+    fn from_event<A: Aggregate, E: DomainEvent<A>, M: Meta>(
+        event: &FormatedEvent<A, E, M>,
+        compression: CompressionPolicy,
+    ) -> Result<EventRow, Error> {
+        let mut payload = serde_json::to_string(&event.payload)?;
+        let packed = CompressedPayload::pack(compression, payload.as_bytes())?;
+        if let Some(packed) = packed.as_ref() {
+            payload = serde_json::to_string(&packed.data)?;
+        }
+
+        Ok(EventRow {
+            aggregate_id: event.aggregate_id.clone(),
+            aggregate_type: event.aggregate_type.clone(),
+            version: event.version as i64,
+            payload,
+            meta: serde_json::to_string(&event.meta)?,
+            created_at: event.created_at.clone(),
+            sequence: event.position as i64,
+            schema_version: E::schema_version() as i64,
+            event_id: Some(event.event_id.clone()),
+            correlation_id: event.correlation_id.clone(),
+            causation_id: event.causation_id.clone(),
+            compression: packed.map(|packed| packed.compression.name().to_string()),
+        })
+    }
+
+    fn into_event<A: Aggregate, E: DomainEvent<A>, M: Meta>(
+        self,
+        upcasters: &Upcasters,
+    ) -> Result<FormatedEvent<A, E, M>, Error> {
+        let json = match self.compression {
+            Some(codec) => {
+                CompressedPayload::stored(&codec, serde_json::from_str(&self.payload)?)?.unpack()?
+            }
+            None => self.payload.into_bytes(),
+        };
+        let payload = match self.schema_version as usize {
+            version if version == E::schema_version() => serde_json::from_slice(&json)?,
+            version => upcasters.deserialize(serde_json::from_slice(&json)?, version)?,
+        };
+
+        let mut event = FormatedEvent::new(
+            self.aggregate_id,
+            self.aggregate_type,
+            self.version as usize,
+            payload,
+            serde_json::from_str(&self.meta)?,
+            Some(&self.created_at),
+        );
+        event.position = self.sequence as usize;
+        event.restore_ids(self.event_id, self.correlation_id, self.causation_id);
+
+        Ok(event)
+    }
+}
@@ -1,27 +1,130 @@
 use async_trait::async_trait;
 
-use crate::{Aggregate, AggregateContext, DomainEvent, Error, FormatedResult, MetaData};
+use crate::{
+    event_stream, Aggregate, AggregateContext, DomainEvent, Error, EventFilter, EventStream,
+    FormatedResult, IdempotencyRecord, Meta, MetaData,
+};
 
+/// Store
+///
+/// `M` is the metadata stored with each event, see `Meta`
 #[async_trait]
-pub trait Store<A, E>: Clone + Sync + Send
+pub trait Store<A, E, M = MetaData>: Clone + Sync + Send
 where
     A: Aggregate,
     E: DomainEvent<A>,
+    M: Meta,
 {
     /// Rebuilding the aggregate
     async fn assemble_aggregate(&self, id: Option<String>) -> Result<AggregateContext<A>, Error>;
 
     /// Append formated events to store
+    ///
+    /// `context.version` is the version the events were generated against,
+    /// returns `Error::conflict` if the stored stream has moved past it
+    ///
+    /// Stores remembering idempotency keys record `context.idempotency` with the
+    /// events, in the same write, and return `Error::duplicate` without appending
+    /// if the key is already remembered. Other stores ignore the key.
     async fn append(
         &self,
         events: Vec<E>,
-        context: AggregateContext<A>,
-        meta: MetaData,
-    ) -> FormatedResult<A, E>;
+        context: &AggregateContext<A>,
+        meta: M,
+    ) -> FormatedResult<A, E, M>;
 
     /// Retrive Events for command store
-    async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<A, E>;
+    async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<A, E, M>;
+
+    /// Stream Events for command store
+    ///
+    /// The default implementation retrieves every event before streaming them
+    fn stream<'a>(&'a self, aggregate_id: &'a str) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        event_stream::from_result(self.retrieve(aggregate_id))
+    }
+
+    /// Retrive Events of an aggregate after `version`
+    async fn retrieve_from(&self, aggregate_id: &str, version: usize) -> FormatedResult<A, E, M> {
+        let mut events = self.retrieve(aggregate_id).await?;
+        events.retain(|e| e.version > version);
+
+        Ok(events)
+    }
+
+    /// Stream Events of an aggregate after `version`
+    ///
+    /// The default implementation retrieves every event before streaming them
+    fn stream_from<'a>(&'a self, aggregate_id: &'a str, version: usize) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        event_stream::from_result(self.retrieve_from(aggregate_id, version))
+    }
+
+    /// Read events from global `position` on, ordered by position
+    ///
+    /// Only events of `aggregate_type` are returned when provided
+    async fn read_all(
+        &self,
+        position: usize,
+        aggregate_type: Option<&str>,
+    ) -> FormatedResult<A, E, M>;
+
+    /// Retrive Events matching `filter`, ordered by position
+    ///
+    /// The default implementation filters `read_all`
+    async fn retrieve_filtered(&self, filter: &EventFilter) -> FormatedResult<A, E, M> {
+        let position = filter.from_position.unwrap_or(0);
+        let mut events = self
+            .read_all(position, filter.single_aggregate_type())
+            .await?;
+        events.retain(|e| filter.matches(e));
+
+        Ok(events)
+    }
+
+    /// Stream Events matching `filter`, ordered by position
+    ///
+    /// The default implementation retrieves every event before streaming them
+    fn stream_filtered<'a>(&'a self, filter: &'a EventFilter) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        event_stream::from_result(self.retrieve_filtered(filter))
+    }
 
     /// Retrive Events for query
-    async fn retrieve_for_query(&self, aggregate_id: Option<&str>) -> FormatedResult<A, E>;
+    ///
+    /// Returns the events of `aggregate_id` when provided, otherwise every event of the aggregate type
+    async fn retrieve_for_query(&self, aggregate_id: Option<&str>) -> FormatedResult<A, E, M>;
+
+    /// Stream Events for query
+    ///
+    /// The default implementation retrieves every event before streaming them
+    fn stream_for_query<'a>(&'a self, aggregate_id: Option<&'a str>) -> EventStream<'a, A, E, M>
+    where
+        A: 'a,
+        E: 'a,
+        M: 'a,
+    {
+        event_stream::from_result(self.retrieve_for_query(aggregate_id))
+    }
+
+    /// Live outcome remembered under idempotency `key` for the aggregate type,
+    /// `None` if the key is unknown or expired
+    ///
+    /// The default implementation doesn't remember keys and always returns `None`,
+    /// so commands with a key are handled every time
+    async fn remembered(&self, _key: &str) -> Result<Option<IdempotencyRecord>, Error> {
+        Ok(None)
+    }
 }
@@ -1,7 +1,10 @@
+use futures::stream::BoxStream;
 use std::collections::HashMap;
 
 use crate::{Error, FormatedEvent};
 
+/// Untyped metadata, the default `Meta`
 pub type MetaData = HashMap<String, String>;
-pub type FormatedEvents<A, E> = Vec<FormatedEvent<A, E>>;
-pub type FormatedResult<A, E> = Result<FormatedEvents<A, E>, Error>;
+pub type FormatedEvents<A, E, M = MetaData> = Vec<FormatedEvent<A, E, M>>;
+pub type FormatedResult<A, E, M = MetaData> = Result<FormatedEvents<A, E, M>, Error>;
+pub type EventStream<'a, A, E, M = MetaData> = BoxStream<'a, Result<FormatedEvent<A, E, M>, Error>>;
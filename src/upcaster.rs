@@ -0,0 +1,117 @@
+use serde_json::Value;
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::{Aggregate, DomainEvent, Error};
+
+/// Step turning a raw payload of one schema version into the next
+type Upcast = Arc<dyn Fn(Value) -> Value + Send + Sync>;
+
+/// Upcasters
+///
+/// Registry of steps bringing raw payloads stored with an older
+/// `DomainEvent::schema_version` to the current shape before they are
+/// deserialized. Steps are chained, so a version 1 record of an event at
+/// version 3 goes through the `1` and `2` steps.
+///
+/// ```
+/// use cqrs_eventsourcing::Upcasters;
+///
+/// // Version 2 renamed `client` to `customer`
+/// let upcasters = Upcasters::new().register(1, |mut payload| {
+///     if let Some(opened) = payload.get_mut("Opened").and_then(|o| o.as_object_mut()) {
+///         if let Some(client) = opened.remove("client") {
+///             opened.insert("customer".to_string(), client);
+///         }
+///     }
+///     payload
+/// });
+/// ```
+#[derive(Clone, Default)]
+pub struct Upcasters {
+    steps: HashMap<usize, Upcast>,
+}
+
+impl Upcasters {
+    pub fn new() -> Upcasters {
+        Upcasters::default()
+    }
+
+    /// Register the step turning a payload of schema version `from` into `from + 1`
+    pub fn register<F>(mut self, from: usize, upcast: F) -> Self
+    where
+        F: Fn(Value) -> Value + Send + Sync + 'static,
+    {
+        self.steps.insert(from, Arc::new(upcast));
+        self
+    }
+
+    /// Run the steps taking `payload` from schema version `from` to `to`
+    pub fn upcast(&self, payload: Value, from: usize, to: usize) -> Result<Value, Error> {
+        self.apply(payload, from, to)
+            .map_err(|reason| upcast_error(from, to, &reason))
+    }
+
+    /// Deserialize `payload` stored with schema `version`, upcasting it first if it is older
+    pub fn deserialize<A: Aggregate, E: DomainEvent<A>>(
+        &self,
+        payload: Value,
+        version: usize,
+    ) -> Result<E, Error> {
+        self.decode::<A, E>(payload, version)
+            .map_err(|reason| upcast_error(version, E::schema_version(), &reason))
+    }
+
+    /// Deserialize `payload` stored with schema `version`, failures give the reason
+    pub(crate) fn decode<A: Aggregate, E: DomainEvent<A>>(
+        &self,
+        payload: Value,
+        version: usize,
+    ) -> Result<E, String> {
+        let payload = self.apply(payload, version, E::schema_version())?;
+
+        serde_json::from_value(payload).map_err(|e| e.to_string())
+    }
+
+    fn apply(&self, mut payload: Value, from: usize, to: usize) -> Result<Value, String> {
+        if from > to {
+            return Err(format!(
+                "schema version {} is newer than {}, the current one",
+                from, to
+            ));
+        }
+
+        for version in from..to {
+            let step = self
+                .steps
+                .get(&version)
+                .ok_or_else(|| format!("no upcaster from schema version {}", version))?;
+            payload = step(payload);
+        }
+
+        Ok(payload)
+    }
+}
+
+impl fmt::Debug for Upcasters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut versions: Vec<&usize> = self.steps.keys().collect();
+        versions.sort();
+
+        f.debug_struct("Upcasters")
+            .field("from_versions", &versions)
+            .finish()
+    }
+}
+
+pub(crate) fn upcast_error(from: usize, to: usize, reason: &str) -> Error {
+    let mut extension = HashMap::new();
+    extension.insert("from_version".to_string(), from.to_string());
+    extension.insert("to_version".to_string(), to.to_string());
+    extension.insert("reason".to_string(), reason.to_string());
+
+    Error::new(
+        "Upcasters: cannot upcast payload",
+        Some("INTERNAL"),
+        Some(extension),
+    )
+}
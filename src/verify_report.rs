@@ -0,0 +1,51 @@
+use serde::Serialize;
+
+/// Integrity report of a store log, see `FileEventStore::verify`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct VerifyReport {
+    /// Events read successfully
+    pub records: usize,
+    pub corrupted: Vec<CorruptedRecord>,
+    pub version_gaps: Vec<VersionGap>,
+    pub duplicate_versions: Vec<DuplicateVersion>,
+    /// Bytes of a torn batch at the end of the log, dropped by the next append
+    pub torn_bytes: u64,
+}
+
+impl VerifyReport {
+    /// Checks if no record is corrupted and every stream has consecutive versions
+    ///
+    /// A torn tail is not an error, readers never see it
+    pub fn is_ok(&self) -> bool {
+        self.corrupted.is_empty()
+            && self.version_gaps.is_empty()
+            && self.duplicate_versions.is_empty()
+    }
+}
+
+/// Record that can't be decoded or fails its checksum
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CorruptedRecord {
+    pub line: usize,
+    pub reason: String,
+}
+
+/// Stream jumping past `expected` to `found` at `line`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VersionGap {
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    pub line: usize,
+    pub expected: usize,
+    pub found: usize,
+}
+
+/// Version stored at `first_line` and again at `line`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DuplicateVersion {
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    pub version: usize,
+    pub first_line: usize,
+    pub line: usize,
+}
@@ -8,7 +8,7 @@ use std::collections::HashMap;
 
 mod mock;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 struct Dispatch {
     id: String,
     client: String,
@@ -16,17 +16,6 @@ struct Dispatch {
     accepted_at: Option<String>,
 }
 
-impl Default for Dispatch {
-    fn default() -> Dispatch {
-        Dispatch {
-            id: Default::default(),
-            client: Default::default(),
-            dispatcher: Default::default(),
-            accepted_at: Default::default(),
-        }
-    }
-}
-
 impl Aggregate for Dispatch {
     fn aggregate_type() -> &'static str {
         "dispatch"
@@ -90,21 +79,13 @@ impl DomainEvent<Dispatch> for Accepted {
 // QUERY
 type DispatchQuery = QueryProcessor<Dispatch, DispatchEvent, DispatchQueryData>;
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 struct DispatchQueryData {
     dispatches: Vec<Dispatch>,
 }
 
-impl Default for DispatchQueryData {
-    fn default() -> DispatchQueryData {
-        DispatchQueryData {
-            dispatches: Vec::default(),
-        }
-    }
-}
-
 impl DispatchQueryData {
-    fn is_assigned_to(self, id: &str) -> bool {
+    fn is_assigned_to(&self, id: &str) -> bool {
         self.dispatches.iter().any(|i| i.dispatcher == id)
     }
 }
@@ -125,7 +106,7 @@ impl Query<Dispatch, DispatchEvent> for DispatchQueryData {
                     .position(|i| i.id == event.aggregate_id)
                 {
                     let accepted_at = Some(e.accepted_at.clone());
-                    self.dispatches[pos.clone()].accepted_at = accepted_at;
+                    self.dispatches[*pos].accepted_at = accepted_at;
                 }
             }
         }
@@ -219,6 +200,31 @@ impl Command<Dispatch, DispatchEvent> for Accept {
     }
 }
 
+/// `Accept` of the dispatch it names, `Accept` itself always starts a new aggregate
+#[derive(Clone)]
+struct AcceptExisting(Accept);
+
+#[async_trait]
+impl Command<Dispatch, DispatchEvent> for AcceptExisting {
+    fn id(&self) -> Option<String> {
+        Some(self.0.id.clone())
+    }
+
+    async fn handle(
+        self,
+        context: &AggregateContext<Dispatch>,
+    ) -> Result<Vec<DispatchEvent>, Error> {
+        self.0.handle(context).await
+    }
+
+    async fn before<S: Store<Dispatch, DispatchEvent>>(
+        command: AcceptExisting,
+        store: &S,
+    ) -> Result<AcceptExisting, Error> {
+        Ok(AcceptExisting(Accept::before(command.0, store).await?))
+    }
+}
+
 // Handler
 
 #[cfg(test)]
@@ -258,6 +264,7 @@ mod given_then_dispatch_test {
             _query: None,
         };
 
+        #[allow(unused_variables, clippy::useless_vec)]
         let expected = vec![DispatchEvent::Accepted(Accepted {
             dispatcher: mock::DISPATCHER.to_string(),
             accepted_at: mock::FIXEDDATE.to_string(),
@@ -279,6 +286,48 @@ mod given_then_dispatch_test {
 
         Ok(())
     }
+
+    /// Accepts a dispatch for the dispatcher it was requested for
+    #[derive(Clone)]
+    struct AcceptAsRequested {
+        id: String,
+    }
+
+    #[async_trait]
+    impl Command<Dispatch, DispatchEvent> for AcceptAsRequested {
+        fn id(&self) -> Option<String> {
+            Some(self.id.clone())
+        }
+
+        async fn handle(
+            self,
+            context: &AggregateContext<Dispatch>,
+        ) -> Result<Vec<DispatchEvent>, Error> {
+            Ok(vec![DispatchEvent::Accepted(Accepted {
+                dispatcher: context.aggregate().dispatcher.clone(),
+                accepted_at: mock::FIXEDDATE.to_string(),
+            })])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_given_is_the_history_of_the_command_aggregate() -> Result<(), Error> {
+        let command = AcceptAsRequested {
+            id: "c-1".to_string(),
+        };
+
+        GivenThen::new()
+            .given(vec![mock::requested("c-1")])
+            .when(command)
+            .then(vec![DispatchEvent::Accepted(Accepted {
+                dispatcher: mock::DISPATCHER.to_string(),
+                accepted_at: mock::FIXEDDATE.to_string(),
+            })])
+            .run()
+            .await?;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -294,21 +343,21 @@ mod with_store_dispatch_test {
 
     #[tokio::test]
     async fn test_request() -> Result<(), Error> {
-        let mut cqrs = setup_cqrs().await?;
+        let cqrs = setup_cqrs().await?;
         let command = Request {
             client: mock::CLIENT.to_string(),
             dispatcher: mock::DISPATCHER.to_string(),
         };
         let result = cqrs.execute(command, HashMap::new()).await?;
 
-        assert_eq!(result, ());
+        assert_eq!(result.events.len(), 1);
 
         Ok(())
     }
 
     #[tokio::test]
     async fn test_accept() -> Result<(), Error> {
-        let mut cqrs = setup_cqrs().await?;
+        let cqrs = setup_cqrs().await?;
         let command = Accept {
             id: mock::DISPATCHID.to_string(),
             dispatcher: mock::DISPATCHER.to_string(),
@@ -317,7 +366,4079 @@ mod with_store_dispatch_test {
 
         let result = cqrs.execute(command, HashMap::new()).await?;
 
-        assert_eq!(result, ());
+        assert_eq!(result.events.len(), 1);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod concurrency_dispatch_test {
+    use super::*;
+    use cqrs_eventsourcing::{InMemoryEventStore, TestStore};
+    use futures::future::join_all;
+
+    fn context(id: &str, version: usize) -> AggregateContext<Dispatch> {
+        AggregateContext {
+            id: id.to_string(),
+            version,
+            aggregate: Dispatch::default(),
+            ..AggregateContext::default()
+        }
+    }
+
+    fn accepted() -> Vec<DispatchEvent> {
+        vec![DispatchEvent::Accepted(Accepted {
+            dispatcher: mock::DISPATCHER.to_string(),
+            accepted_at: mock::FIXEDDATE.to_string(),
+        })]
+    }
+
+    async fn assert_interleaved_conflict<S: Store<Dispatch, DispatchEvent>>(
+        store: S,
+    ) -> Result<(), Error> {
+        let id = uuid::Uuid::new_v4().to_string();
+
+        // Both writers load the aggregate at the same version
+        let first = store.assemble_aggregate(Some(id.clone())).await?;
+        let second = store.assemble_aggregate(Some(id.clone())).await?;
+        assert_eq!(first.version, second.version);
+
+        store.append(accepted(), &first, HashMap::new()).await?;
+        let err = store
+            .append(accepted(), &second, HashMap::new())
+            .await
+            .unwrap_err();
+
+        assert!(err.is_conflict());
+        assert_eq!(err.code(), "CONFLICT");
+
+        let versions: Vec<usize> = store
+            .retrieve(&id)
+            .await?
+            .iter()
+            .map(|e| e.version)
+            .collect();
+        assert_eq!(versions, vec![1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store_rejects_stale_version() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let result = assert_interleaved_conflict(FileEventStore::new(&path)).await;
+        mock::remove_store(&path);
+
+        result
+    }
+
+    #[tokio::test]
+    async fn test_test_store_rejects_stale_version() -> Result<(), Error> {
+        assert_interleaved_conflict(TestStore::new(vec![])).await
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_rejects_stale_version() -> Result<(), Error> {
+        assert_interleaved_conflict(InMemoryEventStore::new()).await
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn test_postgres_store_rejects_stale_version() -> Result<(), Error> {
+        let store =
+            cqrs_eventsourcing::PostgresEventStore::connect(&mock::postgres_params()).await?;
+        assert_interleaved_conflict(store).await
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_store_rejects_stale_version() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let result =
+            assert_interleaved_conflict(cqrs_eventsourcing::SqliteEventStore::new(&path)?).await;
+        mock::remove_store(&path);
+
+        result
+    }
+
+    #[tokio::test]
+    async fn test_test_store_keeps_streams_apart() -> Result<(), Error> {
+        let given = vec![DispatchEvent::Requested(Requested {
+            id: mock::DISPATCHID.to_string(),
+            client: mock::CLIENT.to_string(),
+            dispatcher: mock::DISPATCHER.to_string(),
+        })];
+        let store = TestStore::<Dispatch, DispatchEvent>::new(given);
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        let requested = cqrs
+            .execute(
+                Request {
+                    client: mock::CLIENT.to_string(),
+                    dispatcher: mock::DISPATCHER.to_string(),
+                },
+                HashMap::new(),
+            )
+            .await?;
+        let accepted = cqrs
+            .execute(
+                AcceptExisting(Accept {
+                    id: requested.aggregate_id.clone(),
+                    dispatcher: mock::DISPATCHER.to_string(),
+                    _query: None,
+                }),
+                HashMap::new(),
+            )
+            .await?;
+        let context = store
+            .assemble_aggregate(Some(requested.aggregate_id.clone()))
+            .await?;
+        let queried = store
+            .retrieve_for_query(Some(&requested.aggregate_id))
+            .await?;
+        let all = store.retrieve_for_query(None).await?;
+
+        assert_eq!(accepted.version, 2);
+        assert_eq!(context.version, 2);
+        assert_eq!(
+            context.aggregate.accepted_at.as_deref(),
+            Some(mock::FIXEDDATE)
+        );
+        assert_eq!(queried.len(), 2);
+        assert!(queried
+            .iter()
+            .all(|e| e.aggregate_id == requested.aggregate_id));
+        assert_eq!(all.len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_conflict_is_not_other_errors() {
+        let err = TestStore::<Dispatch, DispatchEvent>::new(vec![])
+            .append(accepted(), &context("dispatch", 3), HashMap::new())
+            .await
+            .unwrap_err();
+
+        assert!(err.is_conflict());
+        assert_eq!(err.extension().unwrap()["expected_version"], "3");
+        assert_eq!(err.extension().unwrap()["current_version"], "0");
+        assert!(!Error::new("IO Isssues", Some("INTERNAL"), None).is_conflict());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_commands_do_not_duplicate_versions() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store: FileEventStore<Dispatch, DispatchEvent> = FileEventStore::new(&path);
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let requested = vec![DispatchEvent::Requested(Requested {
+            id: id.clone(),
+            client: mock::CLIENT.to_string(),
+            dispatcher: mock::DISPATCHER.to_string(),
+        })];
+        store
+            .append(requested, &context(&id, 0), HashMap::new())
+            .await?;
+
+        let executions = (0..8).map(|_| {
+            let cqrs = CQRS::new(store.clone(), vec![]);
+            let command = AcceptExisting(Accept {
+                id: id.clone(),
+                dispatcher: mock::DISPATCHER.to_string(),
+                _query: None,
+            });
+
+            async move { cqrs.execute(command, HashMap::new()).await }
+        });
+        let results = join_all(executions).await;
+
+        let mut versions: Vec<usize> = store
+            .retrieve(&id)
+            .await?
+            .iter()
+            .map(|e| e.version)
+            .collect();
+        mock::remove_store(&path);
+
+        let succeeded = results.iter().filter(|r| r.is_ok()).count();
+        assert!(results.iter().all(|r| match r {
+            Ok(_) => true,
+            Err(e) => e.is_conflict(),
+        }));
+
+        let total = versions.len();
+        versions.dedup();
+        assert_eq!(versions.len(), total);
+        assert_eq!(total, succeeded + 1);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod retry_dispatch_test {
+    use super::*;
+    use cqrs_eventsourcing::{FormatedResult, MetaData, RetryPolicy, TestStore};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+
+    /// Store which reports a version conflict for the first `conflicts` appends
+    #[derive(Clone)]
+    struct ConflictingStore {
+        inner: TestStore<Dispatch, DispatchEvent>,
+        conflicts: Arc<AtomicUsize>,
+    }
+
+    impl ConflictingStore {
+        fn new(conflicts: usize) -> ConflictingStore {
+            ConflictingStore {
+                inner: TestStore::new(vec![]),
+                conflicts: Arc::new(AtomicUsize::new(conflicts)),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Store<Dispatch, DispatchEvent> for ConflictingStore {
+        async fn assemble_aggregate(
+            &self,
+            id: Option<String>,
+        ) -> Result<AggregateContext<Dispatch>, Error> {
+            self.inner.assemble_aggregate(id).await
+        }
+
+        async fn append(
+            &self,
+            events: Vec<DispatchEvent>,
+            context: &AggregateContext<Dispatch>,
+            meta: MetaData,
+        ) -> FormatedResult<Dispatch, DispatchEvent> {
+            let remaining = self.conflicts.load(Ordering::SeqCst);
+            if remaining > 0 {
+                self.conflicts.store(remaining - 1, Ordering::SeqCst);
+                return Err(Error::conflict(
+                    &context.id,
+                    context.version,
+                    context.version + 1,
+                ));
+            }
+
+            self.inner.append(events, context, meta).await
+        }
+
+        async fn retrieve(&self, aggregate_id: &str) -> FormatedResult<Dispatch, DispatchEvent> {
+            self.inner.retrieve(aggregate_id).await
+        }
+
+        async fn read_all(
+            &self,
+            position: usize,
+            aggregate_type: Option<&str>,
+        ) -> FormatedResult<Dispatch, DispatchEvent> {
+            self.inner.read_all(position, aggregate_type).await
+        }
+
+        async fn retrieve_for_query(
+            &self,
+            aggregate_id: Option<&str>,
+        ) -> FormatedResult<Dispatch, DispatchEvent> {
+            self.inner.retrieve_for_query(aggregate_id).await
+        }
+    }
+
+    #[derive(Clone)]
+    struct CountedAccept {
+        id: String,
+        idempotent: bool,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl CountedAccept {
+        fn new(idempotent: bool) -> CountedAccept {
+            CountedAccept {
+                id: mock::DISPATCHID.to_string(),
+                idempotent,
+                calls: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl Command<Dispatch, DispatchEvent> for CountedAccept {
+        fn id(&self) -> Option<String> {
+            Some(self.id.clone())
+        }
+
+        async fn handle(
+            self,
+            _context: &AggregateContext<Dispatch>,
+        ) -> Result<Vec<DispatchEvent>, Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            Ok(vec![DispatchEvent::Accepted(Accepted {
+                dispatcher: mock::DISPATCHER.to_string(),
+                accepted_at: mock::FIXEDDATE.to_string(),
+            })])
+        }
+
+        fn retry_on_conflict(&self) -> bool {
+            self.idempotent
+        }
+    }
+
+    fn fast_policy(max_retries: usize) -> RetryPolicy {
+        RetryPolicy::new(max_retries, Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_append_succeeds() -> Result<(), Error> {
+        let store = ConflictingStore::new(2);
+        let cqrs = CQRS::new(store.clone(), vec![]).with_retry_policy(fast_policy(3));
+        let command = CountedAccept::new(true);
+
+        cqrs.execute(command.clone(), HashMap::new()).await?;
+
+        assert_eq!(command.calls(), 3);
+        assert_eq!(store.retrieve(mock::DISPATCHID).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_retries() {
+        let cqrs = CQRS::new(ConflictingStore::new(5), vec![]).with_retry_policy(fast_policy(2));
+        let command = CountedAccept::new(true);
+
+        let err = cqrs
+            .execute(command.clone(), HashMap::new())
+            .await
+            .unwrap_err();
+
+        assert!(err.is_conflict());
+        assert_eq!(command.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_policy_does_not_retry() {
+        let cqrs =
+            CQRS::new(ConflictingStore::new(1), vec![]).with_retry_policy(RetryPolicy::disabled());
+        let command = CountedAccept::new(true);
+
+        let err = cqrs
+            .execute(command.clone(), HashMap::new())
+            .await
+            .unwrap_err();
+
+        assert!(err.is_conflict());
+        assert_eq!(command.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_non_idempotent_command_is_not_retried() {
+        let cqrs = CQRS::new(ConflictingStore::new(1), vec![]);
+        let command = CountedAccept::new(false);
+
+        let err = cqrs
+            .execute(command.clone(), HashMap::new())
+            .await
+            .unwrap_err();
+
+        assert!(err.is_conflict());
+        assert_eq!(command.calls(), 1);
+    }
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            multiplier: 2,
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(10));
+        assert_eq!(policy.backoff(1), Duration::from_millis(20));
+        assert_eq!(policy.backoff(2), Duration::from_millis(40));
+        assert_eq!(policy.backoff(3), Duration::from_millis(50));
+        assert_eq!(policy.backoff(64), Duration::from_millis(50));
+    }
+}
+
+#[cfg(test)]
+mod memory_store_dispatch_test {
+    use super::*;
+    use cqrs_eventsourcing::InMemoryEventStore;
+
+    type MemoryStore = InMemoryEventStore<Dispatch, DispatchEvent>;
+
+    #[tokio::test]
+    async fn test_request_and_accept() -> Result<(), Error> {
+        let store = MemoryStore::new();
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        let context = store.assemble_aggregate(None).await?;
+        let id = context.id.clone();
+        store
+            .append(vec![mock::requested(&id)], &context, HashMap::new())
+            .await?;
+
+        let command = AcceptExisting(Accept {
+            id: id.clone(),
+            dispatcher: mock::DISPATCHER.to_string(),
+            _query: None,
+        });
+        cqrs.execute(command, HashMap::new()).await?;
+
+        let context = store.assemble_aggregate(Some(id.clone())).await?;
+        assert_eq!(context.version, 2);
+        assert_eq!(context.aggregate.dispatcher, mock::DISPATCHER);
+        assert_eq!(
+            context.aggregate.accepted_at.as_deref(),
+            Some(mock::FIXEDDATE)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clones_share_events() -> Result<(), Error> {
+        let store = MemoryStore::new();
+        let clone = store.clone();
+
+        let context = clone.assemble_aggregate(None).await?;
+        let id = context.id.clone();
+        clone
+            .append(vec![mock::requested(&id)], &context, HashMap::new())
+            .await?;
+
+        assert_eq!(store.retrieve(&id).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_for_query() -> Result<(), Error> {
+        let store = MemoryStore::new();
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let context = store.assemble_aggregate(None).await?;
+            ids.push(context.id.clone());
+            store
+                .append(vec![mock::requested(&context.id)], &context, HashMap::new())
+                .await?;
+        }
+
+        let all = store.retrieve_for_query(None).await?;
+        let all_ids: Vec<&str> = all.iter().map(|e| e.aggregate_id.as_str()).collect();
+        assert_eq!(all_ids, ids);
+
+        let one = store.retrieve_for_query(Some(&ids[1])).await?;
+        assert_eq!(one.len(), 1);
+        assert_eq!(one[0].aggregate_id, ids[1]);
+
+        let query = DispatchQuery::process(&store, None).await?;
+        assert_eq!(query.dispatches.len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_parallel_appends_keep_versions_unique() -> Result<(), Error> {
+        let store = MemoryStore::new();
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let store = store.clone();
+                let id = id.clone();
+
+                tokio::spawn(async move {
+                    let context = store.assemble_aggregate(Some(id.clone())).await?;
+                    store
+                        .append(vec![mock::requested(&id)], &context, HashMap::new())
+                        .await
+                })
+            })
+            .collect();
+
+        let mut succeeded = 0;
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(_) => succeeded += 1,
+                Err(e) => assert!(e.is_conflict()),
+            }
+        }
+
+        let versions: Vec<usize> = store
+            .retrieve(&id)
+            .await?
+            .iter()
+            .map(|e| e.version)
+            .collect();
+        assert_eq!(versions, (1..=succeeded).collect::<Vec<usize>>());
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod sqlite_store_dispatch_test {
+    use super::*;
+    use cqrs_eventsourcing::SqliteEventStore;
+
+    type SqliteStore = SqliteEventStore<Dispatch, DispatchEvent>;
+
+    #[tokio::test]
+    async fn test_request_and_accept() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = SqliteStore::new(&path)?;
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        let context = store.assemble_aggregate(None).await?;
+        let id = context.id.clone();
+        let mut meta = HashMap::new();
+        meta.insert("user".to_string(), mock::CLIENT.to_string());
+        store
+            .append(vec![mock::requested(&id)], &context, meta)
+            .await?;
+
+        let command = AcceptExisting(Accept {
+            id: id.clone(),
+            dispatcher: mock::DISPATCHER.to_string(),
+            _query: None,
+        });
+        cqrs.execute(command, HashMap::new()).await?;
+
+        // Reopen the file to read what was persisted
+        let reopened = SqliteStore::new(&path)?;
+        let events = reopened.retrieve(&id).await?;
+        let after_first = reopened.retrieve_from(&id, 1).await?;
+        mock::remove_store(&path);
+
+        assert_eq!(after_first.len(), 1);
+        assert_eq!(after_first[0].version, 2);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].payload, vec![mock::requested(&id)][0]);
+        assert_eq!(events[0].meta["user"], mock::CLIENT);
+        assert_eq!(
+            events.iter().map(|e| e.version).collect::<Vec<usize>>(),
+            vec![1, 2]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_for_query() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = SqliteStore::new(&path)?;
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let context = store.assemble_aggregate(None).await?;
+            ids.push(context.id.clone());
+            store
+                .append(vec![mock::requested(&context.id)], &context, HashMap::new())
+                .await?;
+        }
+
+        let all = store.retrieve_for_query(None).await?;
+        let one = store.retrieve_for_query(Some(&ids[2])).await?;
+        let query = DispatchQuery::process(&store, None).await?;
+        mock::remove_store(&path);
+
+        let all_ids: Vec<&str> = all.iter().map(|e| e.aggregate_id.as_str()).collect();
+        assert_eq!(all_ids, ids);
+        assert_eq!(one.len(), 1);
+        assert_eq!(one[0].aggregate_id, ids[2]);
+        assert_eq!(query.dispatches.len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_cqrs() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let cqrs = SqliteStore::create_cqrs(&path, vec![])?;
+
+        let command = Request {
+            client: mock::CLIENT.to_string(),
+            dispatcher: mock::DISPATCHER.to_string(),
+        };
+        let result = cqrs.execute(command, HashMap::new()).await;
+
+        let events = SqliteStore::new(&path)?.retrieve_for_query(None).await?;
+        mock::remove_store(&path);
+
+        result?;
+        assert_eq!(events.len(), 1);
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "postgres"))]
+mod postgres_store_dispatch_test {
+    use super::*;
+    use cqrs_eventsourcing::PostgresEventStore;
+
+    type PostgresStore = PostgresEventStore<Dispatch, DispatchEvent>;
+
+    #[tokio::test]
+    async fn test_request_and_accept() -> Result<(), Error> {
+        let store = PostgresStore::connect(&mock::postgres_params()).await?;
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        let context = store.assemble_aggregate(None).await?;
+        let id = context.id.clone();
+        let mut meta = HashMap::new();
+        meta.insert("user".to_string(), mock::CLIENT.to_string());
+        store
+            .append(vec![mock::requested(&id)], &context, meta)
+            .await?;
+
+        let command = AcceptExisting(Accept {
+            id: id.clone(),
+            dispatcher: mock::DISPATCHER.to_string(),
+            _query: None,
+        });
+        cqrs.execute(command, HashMap::new()).await?;
+
+        let events = store.retrieve(&id).await?;
+        let after_first = store.retrieve_from(&id, 1).await?;
+        assert_eq!(after_first.len(), 1);
+        assert_eq!(after_first[0].version, 2);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].payload, vec![mock::requested(&id)][0]);
+        assert_eq!(events[0].meta["user"], mock::CLIENT);
+        assert_eq!(
+            events.iter().map(|e| e.version).collect::<Vec<usize>>(),
+            vec![1, 2]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_for_query_in_global_order() -> Result<(), Error> {
+        let cqrs = PostgresStore::create_cqrs(&mock::postgres_params(), vec![]).await?;
+        let store = PostgresStore::connect(&mock::postgres_params()).await?;
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let context = store.assemble_aggregate(None).await?;
+            ids.push(context.id.clone());
+            store
+                .append(vec![mock::requested(&context.id)], &context, HashMap::new())
+                .await?;
+        }
+
+        let command = Request {
+            client: mock::CLIENT.to_string(),
+            dispatcher: mock::DISPATCHER.to_string(),
+        };
+        cqrs.execute(command, HashMap::new()).await?;
+
+        // The database is shared with other runs, only look at the events of this test
+        let ours: Vec<String> = store
+            .retrieve_for_query(None)
+            .await?
+            .into_iter()
+            .map(|e| e.aggregate_id)
+            .filter(|id| ids.contains(id))
+            .collect();
+        assert_eq!(ours, ids);
+
+        let one = store.retrieve_for_query(Some(&ids[1])).await?;
+        assert_eq!(one.len(), 1);
+        assert_eq!(one[0].aggregate_id, ids[1]);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod snapshot_dispatch_test {
+    use super::*;
+    use cqrs_eventsourcing::{
+        FileSnapshotStore, InMemoryEventStore, InMemorySnapshotStore, Snapshot, SnapshotPolicy,
+        SnapshotStore, SnapshottingStore, StoreWarning,
+    };
+    use std::sync::{Arc, Mutex};
+
+    type SnapshotStoreOf = SnapshottingStore<
+        Dispatch,
+        DispatchEvent,
+        InMemoryEventStore<Dispatch, DispatchEvent>,
+        InMemorySnapshotStore<Dispatch>,
+    >;
+
+    fn snapshotting(every: usize) -> SnapshotStoreOf {
+        SnapshottingStore::new(
+            InMemoryEventStore::new(),
+            InMemorySnapshotStore::new(),
+            SnapshotPolicy::every(every),
+        )
+    }
+
+    /// Snapshot store failing every load and save
+    #[derive(Clone)]
+    struct BrokenSnapshotStore;
+
+    #[async_trait]
+    impl SnapshotStore<Dispatch> for BrokenSnapshotStore {
+        async fn load(&self, _aggregate_id: &str) -> Result<Option<Snapshot<Dispatch>>, Error> {
+            Err(Error::new("snapshot unreadable", None, None))
+        }
+
+        async fn save(&self, _snapshot: Snapshot<Dispatch>) -> Result<(), Error> {
+            Err(Error::new("snapshot unwritable", None, None))
+        }
+    }
+
+    type BrokenStoreOf = SnapshottingStore<
+        Dispatch,
+        DispatchEvent,
+        InMemoryEventStore<Dispatch, DispatchEvent>,
+        BrokenSnapshotStore,
+    >;
+
+    fn broken_snapshotting() -> (BrokenStoreOf, Arc<Mutex<Vec<StoreWarning>>>) {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink = warnings.clone();
+        let store = SnapshottingStore::new(
+            InMemoryEventStore::new(),
+            BrokenSnapshotStore,
+            SnapshotPolicy::every(1),
+        )
+        .with_warning_handler(move |warning| sink.lock().unwrap().push(warning.clone()));
+
+        (store, warnings)
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = SnapshotPolicy::every(3);
+
+        assert!(!policy.should_snapshot(0, 2));
+        assert!(policy.should_snapshot(2, 3));
+        assert!(policy.should_snapshot(2, 4));
+        assert!(!policy.should_snapshot(3, 5));
+        assert!(policy.should_snapshot(0, 7));
+        assert!(!SnapshotPolicy::every(0).should_snapshot(0, 100));
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_are_taken_every_n_events() -> Result<(), Error> {
+        let store = snapshotting(2);
+        let id = uuid::Uuid::new_v4().to_string();
+
+        mock::append_one(&store, &id, mock::requested_by(&id, mock::CLIENT)).await?;
+        assert!(store.snapshots().load(&id).await?.is_none());
+
+        mock::append_one(&store, &id, mock::requested_by(&id, "second")).await?;
+        mock::append_one(&store, &id, mock::requested_by(&id, "third")).await?;
+
+        let snapshot = store.snapshots().load(&id).await?.unwrap();
+        assert_eq!(snapshot.version, 2);
+        assert_eq!(snapshot.aggregate.client, "second");
+
+        let context = store.assemble_aggregate(Some(id.clone())).await?;
+        assert_eq!(context.version, 3);
+        assert_eq!(context.aggregate.client, "third");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_only_events_after_snapshot_are_replayed() -> Result<(), Error> {
+        let store = snapshotting(0);
+        let id = uuid::Uuid::new_v4().to_string();
+
+        mock::append_one(&store, &id, mock::requested_by(&id, mock::CLIENT)).await?;
+        mock::append_one(
+            &store,
+            &id,
+            DispatchEvent::Accepted(Accepted {
+                dispatcher: mock::DISPATCHER.to_string(),
+                accepted_at: mock::FIXEDDATE.to_string(),
+            }),
+        )
+        .await?;
+
+        // Replaying version 1 would overwrite the client taken from the snapshot
+        let aggregate = Dispatch {
+            client: "from-snapshot".to_string(),
+            ..Default::default()
+        };
+        store
+            .snapshots()
+            .save(Snapshot::new(&id, 1, aggregate))
+            .await?;
+
+        let context = store.assemble_aggregate(Some(id.clone())).await?;
+        assert_eq!(context.version, 2);
+        assert_eq!(context.aggregate.client, "from-snapshot");
+        assert_eq!(
+            context.aggregate.accepted_at.as_deref(),
+            Some(mock::FIXEDDATE)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_snapshot_save_keeps_the_events() -> Result<(), Error> {
+        let (store, warnings) = broken_snapshotting();
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let events = mock::append_one(&store, &id, mock::requested_by(&id, mock::CLIENT)).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(store.store().retrieve(&id).await?.len(), 1);
+        assert!(matches!(
+            warnings.lock().unwrap().as_slice(),
+            [
+                StoreWarning::SnapshotNotLoaded { .. },
+                StoreWarning::SnapshotNotSaved { version: 1, .. }
+            ]
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_snapshot_load_replays_all_events() -> Result<(), Error> {
+        let (store, warnings) = broken_snapshotting();
+        let id = uuid::Uuid::new_v4().to_string();
+
+        mock::append_one(store.store(), &id, mock::requested_by(&id, mock::CLIENT)).await?;
+        mock::append_one(store.store(), &id, mock::requested_by(&id, "second")).await?;
+
+        let context = store.assemble_aggregate(Some(id.clone())).await?;
+        assert_eq!(context.version, 2);
+        assert_eq!(context.aggregate.client, "second");
+        assert!(matches!(
+            warnings.lock().unwrap().as_slice(),
+            [StoreWarning::SnapshotNotLoaded { aggregate_id, .. }] if *aggregate_id == id
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cqrs_with_snapshotting_store() -> Result<(), Error> {
+        let store = snapshotting(1);
+        let cqrs = CQRS::new(store.clone(), vec![]);
+        let id = uuid::Uuid::new_v4().to_string();
+
+        mock::append_one(&store, &id, mock::requested_by(&id, mock::CLIENT)).await?;
+
+        let command = AcceptExisting(Accept {
+            id: id.clone(),
+            dispatcher: mock::DISPATCHER.to_string(),
+            _query: None,
+        });
+        cqrs.execute(command, HashMap::new()).await?;
+
+        let snapshot = store.snapshots().load(&id).await?.unwrap();
+        assert_eq!(snapshot.version, 2);
+        assert_eq!(
+            snapshot.aggregate.accepted_at.as_deref(),
+            Some(mock::FIXEDDATE)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_snapshot_store() -> Result<(), Error> {
+        let dir = mock::temp_store_path();
+        let snapshots: FileSnapshotStore<Dispatch> = FileSnapshotStore::new(&dir);
+        let id = uuid::Uuid::new_v4().to_string();
+
+        assert!(snapshots.load(&id).await?.is_none());
+
+        let aggregate = Dispatch {
+            client: mock::CLIENT.to_string(),
+            ..Default::default()
+        };
+        snapshots.save(Snapshot::new(&id, 4, aggregate)).await?;
+        snapshots
+            .save(Snapshot::new(&id, 8, Dispatch::default()))
+            .await?;
+
+        let loaded = snapshots.clone().load(&id).await?;
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.version, 8);
+        assert_eq!(loaded.aggregate_type, "dispatch");
+        assert_eq!(loaded.aggregate.client, "");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod read_all_dispatch_test {
+    use super::*;
+    use cqrs_eventsourcing::{FormatedEvent, InMemoryEventStore};
+
+    /// Other tests may write to the same store, keep the events of the given aggregates
+    fn positions_of(events: &[FormatedEvent<Dispatch, DispatchEvent>], ids: &[&str]) -> Vec<usize> {
+        events
+            .iter()
+            .filter(|e| ids.contains(&e.aggregate_id.as_str()))
+            .map(|e| e.position)
+            .collect()
+    }
+
+    async fn assert_read_all<S: Store<Dispatch, DispatchEvent>>(store: S) -> Result<(), Error> {
+        let first = uuid::Uuid::new_v4().to_string();
+        let second = uuid::Uuid::new_v4().to_string();
+
+        let mut committed = mock::append_requested(&store, &first, 2).await?;
+        committed.extend(mock::append_requested(&store, &second, 1).await?);
+        committed.extend(mock::append_requested(&store, &first, 1).await?);
+        let positions: Vec<usize> = committed.iter().map(|e| e.position).collect();
+
+        // Positions are assigned in append order
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+        assert!(positions[0] > 0);
+
+        let ids = [first.as_str(), second.as_str()];
+
+        let all = store.read_all(positions[0], None).await?;
+        assert_eq!(positions_of(&all, &ids), positions);
+        assert!(all.windows(2).all(|w| w[0].position < w[1].position));
+
+        let tail = store.read_all(positions[2], Some("dispatch")).await?;
+        assert_eq!(positions_of(&tail, &ids), positions[2..].to_vec());
+
+        let other = store.read_all(positions[0], Some("other")).await?;
+        assert!(positions_of(&other, &ids).is_empty());
+
+        let after = store.read_all(positions[3] + 1, None).await?;
+        assert!(positions_of(&after, &ids).is_empty());
+
+        // Stored events keep their position
+        let stored = store.retrieve(&first).await?;
+        let read: Vec<usize> = stored.iter().map(|e| e.position).collect();
+        assert_eq!(read, vec![positions[0], positions[1], positions[3]]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let result = assert_read_all(FileEventStore::new(&path)).await;
+        mock::remove_store(&path);
+
+        result
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() -> Result<(), Error> {
+        assert_read_all(InMemoryEventStore::new()).await
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_store() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let result = assert_read_all(cqrs_eventsourcing::SqliteEventStore::new(&path)?).await;
+        mock::remove_store(&path);
+
+        result
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn test_postgres_store() -> Result<(), Error> {
+        let store =
+            cqrs_eventsourcing::PostgresEventStore::connect(&mock::postgres_params()).await?;
+        assert_read_all(store).await
+    }
+}
+
+#[cfg(test)]
+mod stream_dispatch_test {
+    use super::*;
+    use cqrs_eventsourcing::{FormatedEvent, InMemoryEventStore};
+    use futures::TryStreamExt;
+
+    /// Spans several pages of the SQL stores
+    const EVENTS: usize = 600;
+
+    async fn assert_stream<S: Store<Dispatch, DispatchEvent>>(store: S) -> Result<(), Error> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let other = uuid::Uuid::new_v4().to_string();
+
+        let context = store.assemble_aggregate(Some(id.clone())).await?;
+        let events = (0..EVENTS).map(|_| mock::requested(&id)).collect();
+        store.append(events, &context, HashMap::new()).await?;
+
+        let context = store.assemble_aggregate(Some(other.clone())).await?;
+        store
+            .append(vec![mock::requested(&other)], &context, HashMap::new())
+            .await?;
+
+        let streamed: Vec<_> = store.stream(&id).try_collect().await?;
+        let versions: Vec<usize> = streamed.iter().map(|e| e.version).collect();
+        assert_eq!(versions, (1..=EVENTS).collect::<Vec<_>>());
+        assert!(streamed.windows(2).all(|w| w[0].position < w[1].position));
+
+        let retrieved = store.retrieve(&id).await?;
+        let positions = |events: &[FormatedEvent<Dispatch, DispatchEvent>]| {
+            events.iter().map(|e| e.position).collect::<Vec<_>>()
+        };
+        assert_eq!(positions(&streamed), positions(&retrieved));
+
+        let queried: Vec<_> = store.stream_for_query(Some(&id)).try_collect().await?;
+        assert_eq!(queried.len(), EVENTS);
+
+        let tail: Vec<_> = store.stream_from(&id, EVENTS - 2).try_collect().await?;
+        let versions: Vec<usize> = tail.iter().map(|e| e.version).collect();
+        assert_eq!(versions, vec![EVENTS - 1, EVENTS]);
+        assert_eq!(positions(&tail), positions(&retrieved[EVENTS - 2..]));
+
+        let context = store.assemble_aggregate(Some(id)).await?;
+        assert_eq!(context.version, EVENTS);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let result = assert_stream(FileEventStore::new(&path)).await;
+        mock::remove_store(&path);
+
+        result
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() -> Result<(), Error> {
+        assert_stream(InMemoryEventStore::new()).await
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_store() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = cqrs_eventsourcing::SqliteEventStore::new(&path)?;
+        let result = assert_stream(store.clone()).await;
+
+        // Every event of the type, across pages
+        let all: Vec<_> = store.stream_for_query(None).try_collect().await?;
+        assert_eq!(all.len(), EVENTS + 1);
+        mock::remove_store(&path);
+
+        result
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn test_postgres_store() -> Result<(), Error> {
+        let store =
+            cqrs_eventsourcing::PostgresEventStore::connect(&mock::postgres_params()).await?;
+        assert_stream(store).await
+    }
+
+    #[tokio::test]
+    async fn test_query_processor_reads_stream() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let context = store.assemble_aggregate(Some(id.clone())).await?;
+        store
+            .append(vec![mock::requested(&id)], &context, HashMap::new())
+            .await?;
+
+        let query = DispatchQuery::process(&store, Some(&id)).await?;
+        mock::remove_store(&path);
+        assert!(query.is_assigned_to(mock::DISPATCHER));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod file_store_io_test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// Several megabytes of store file
+    const EVENTS: usize = 20_000;
+
+    /// Milliseconds of reading per tick the ticker must at least make, leaving room for timer granularity
+    const TICK_RATIO: usize = 25;
+
+    /// `tokio::test` runs on a single thread, a blocking read would starve the ticker
+    #[tokio::test]
+    async fn test_large_reads_do_not_block_runtime() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let events = (0..EVENTS)
+            .map(|_| {
+                DispatchEvent::Requested(Requested {
+                    id: id.clone(),
+                    client: mock::CLIENT.to_string(),
+                    dispatcher: mock::DISPATCHER.to_string(),
+                })
+            })
+            .collect();
+        let context = store.assemble_aggregate(Some(id.clone())).await?;
+        store.append(events, &context, HashMap::new()).await?;
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let ticker = {
+            let ticks = ticks.clone();
+            let done = done.clone();
+            tokio::spawn(async move {
+                while !done.load(Ordering::SeqCst) {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::delay_for(Duration::from_millis(1)).await;
+                }
+            })
+        };
+
+        let started = Instant::now();
+        let result = store.retrieve(&id).await;
+        let elapsed = started.elapsed();
+        let progress = ticks.load(Ordering::SeqCst);
+
+        done.store(true, Ordering::SeqCst);
+        ticker.await.unwrap();
+        mock::remove_store(&path);
+
+        assert_eq!(result?.len(), EVENTS);
+        // The ticker wakes at most once a millisecond, a blocking read leaves it at one tick at most
+        let expected = (elapsed.as_millis() as usize / TICK_RATIO).max(2);
+        assert!(
+            progress >= expected,
+            "ticker ran {} times in {:?}, expected at least {}",
+            progress,
+            elapsed,
+            expected
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod file_store_errors_test {
+    use super::*;
+    use cqrs_eventsourcing::{CorruptLinePolicy, StoreWarning};
+    use std::sync::{Arc, Mutex};
+
+    /// Handler collecting warnings of corrupt lines
+    fn line_warnings() -> (
+        Arc<Mutex<Vec<StoreWarning>>>,
+        impl Fn(&StoreWarning) + Send + Sync + 'static,
+    ) {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink = warnings.clone();
+
+        (warnings, move |warning: &StoreWarning| match warning {
+            StoreWarning::SkippedLine { .. } | StoreWarning::QuarantinedLine { .. } => {
+                sink.lock().unwrap().push(warning.clone())
+            }
+            _ => {}
+        })
+    }
+
+    /// Store with two events of `id` around a corrupt second line
+    async fn corrupt_store(path: &str, id: &str) -> Result<(), Error> {
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(path);
+        let context = store.assemble_aggregate(Some(id.to_string())).await?;
+        store
+            .append(
+                vec![mock::requested(id), mock::requested(id)],
+                &context,
+                HashMap::new(),
+            )
+            .await?;
+
+        let data = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = data.lines().collect();
+        std::fs::write(path, format!("{}\n{{not json\n{}\n", lines[0], lines[1])).unwrap();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_line_fails_with_path_and_line() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        corrupt_store(&path, &id).await?;
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let err = store.retrieve(&id).await.unwrap_err();
+        let appended = store
+            .append(
+                vec![mock::requested(&id)],
+                &Default::default(),
+                HashMap::new(),
+            )
+            .await;
+        mock::remove_store(&path);
+
+        assert!(err.is_corrupt());
+        let extension = err.extension().unwrap();
+        assert_eq!(extension["path"], path);
+        assert_eq!(extension["line"], "2");
+        assert!(appended.unwrap_err().is_corrupt());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_line_is_skipped() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        corrupt_store(&path, &id).await?;
+
+        let (warnings, handler) = line_warnings();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .with_corrupt_line_policy(CorruptLinePolicy::Skip)
+            .with_warning_handler(handler);
+        let events = store.retrieve(&id).await?;
+        let positions: Vec<usize> = events.iter().map(|e| e.position).collect();
+        assert_eq!(positions, vec![1, 3]);
+        assert!(matches!(
+            warnings.lock().unwrap().as_slice(),
+            [StoreWarning::SkippedLine { line: 2, .. }]
+        ));
+
+        // Positions stay line numbers after the skipped line
+        let context = store.assemble_aggregate(Some(id.clone())).await?;
+        assert_eq!(context.version, 2);
+        let appended = store
+            .append(vec![mock::requested(&id)], &context, HashMap::new())
+            .await?;
+        mock::remove_store(&path);
+
+        assert_eq!(appended[0].position, 4);
+        assert_eq!(appended[0].version, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_line_is_quarantined_once() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        corrupt_store(&path, &id).await?;
+
+        let (warnings, handler) = line_warnings();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .with_corrupt_line_policy(CorruptLinePolicy::Quarantine)
+            .with_warning_handler(handler);
+        // Reads of the whole log meet the line every time
+        assert_eq!(store.read_all(0, None).await?.len(), 2);
+        assert_eq!(store.read_all(0, None).await?.len(), 2);
+
+        // Another process knows the line from the quarantine file
+        let reopened = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .with_corrupt_line_policy(CorruptLinePolicy::Quarantine);
+        let retrieved = reopened.read_all(0, None).await;
+
+        // A different line at the same position is quarantined too
+        let data = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, data.replace("{not json", "{still not json")).unwrap();
+        let rewritten = reopened.read_all(0, None).await;
+
+        let quarantined = store.quarantined().await;
+        mock::remove_store(&path);
+
+        assert_eq!(retrieved?.len(), 2);
+        assert_eq!(rewritten?.len(), 2);
+        let quarantined = quarantined?;
+        let contents: Vec<&str> = quarantined.iter().map(|q| q.content.as_str()).collect();
+        assert_eq!(contents, vec!["{not json", "{still not json"]);
+        assert_eq!(quarantined[0].line, 2);
+        assert_eq!(warnings.lock().unwrap().len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unopenable_store_returns_error() {
+        let path = std::env::temp_dir()
+            .join(uuid::Uuid::new_v4().to_string())
+            .join("missing-dir.store");
+        let path = path.to_str().unwrap();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(path);
+
+        let err = store.retrieve("id").await.unwrap_err();
+        assert_eq!(err.code(), "INTERNAL");
+        assert_eq!(err.extension().unwrap()["path"], path);
+    }
+}
+
+#[cfg(test)]
+mod event_filter_dispatch_test {
+    use super::*;
+    use chrono::{Duration, Utc};
+    use cqrs_eventsourcing::{EventFilter, InMemoryEventStore};
+
+    fn accepted() -> DispatchEvent {
+        DispatchEvent::Accepted(Accepted {
+            dispatcher: mock::DISPATCHER.to_string(),
+            accepted_at: Utc::now().to_rfc2822(),
+        })
+    }
+
+    async fn append<S: Store<Dispatch, DispatchEvent>>(
+        store: &S,
+        id: &str,
+        event: DispatchEvent,
+        tenant: &str,
+    ) -> Result<usize, Error> {
+        let mut meta = HashMap::new();
+        meta.insert("tenant".to_string(), tenant.to_string());
+
+        let context = store.assemble_aggregate(Some(id.to_string())).await?;
+        let commited = store.append(vec![event], &context, meta).await?;
+
+        Ok(commited[0].position)
+    }
+
+    fn versions(events: &[FormatedEvent<Dispatch, DispatchEvent>]) -> Vec<(String, usize)> {
+        events
+            .iter()
+            .map(|e| (e.aggregate_id.clone(), e.version))
+            .collect()
+    }
+
+    /// `store` must be empty
+    async fn assert_filters<S: Store<Dispatch, DispatchEvent>>(store: S) -> Result<(), Error> {
+        let first = "first".to_string();
+        let second = "second".to_string();
+
+        append(&store, &first, mock::requested(&first), "a").await?;
+        let accepted_at = append(&store, &first, accepted(), "b").await?;
+        append(&store, &second, mock::requested(&second), "a").await?;
+
+        let one = store.retrieve_for_query(Some(&first)).await?;
+        assert_eq!(versions(&one), vec![(first.clone(), 1), (first.clone(), 2)]);
+        assert_eq!(store.retrieve_for_query(None).await?.len(), 3);
+
+        let by_name = EventFilter::new().event_name("Accepted");
+        let events = store.retrieve_filtered(&by_name).await?;
+        assert_eq!(versions(&events), vec![(first.clone(), 2)]);
+
+        let by_meta = EventFilter::new().meta("tenant", "a");
+        let events = store.retrieve_filtered(&by_meta).await?;
+        assert_eq!(
+            versions(&events),
+            vec![(first.clone(), 1), (second.clone(), 1)]
+        );
+
+        let combined = by_meta.aggregate_id(&second).aggregate_type("dispatch");
+        let events = store.retrieve_filtered(&combined).await?;
+        assert_eq!(versions(&events), vec![(second.clone(), 1)]);
+
+        let by_position = EventFilter::new()
+            .from_position(accepted_at)
+            .to_position(accepted_at);
+        let events = store.retrieve_filtered(&by_position).await?;
+        assert_eq!(versions(&events), vec![(first.clone(), 2)]);
+
+        let hour_ago = Utc::now() - Duration::hours(1);
+        let recent = EventFilter::new().created_from(hour_ago);
+        assert_eq!(store.retrieve_filtered(&recent).await?.len(), 3);
+        let old = EventFilter::new().created_before(hour_ago);
+        assert!(store.retrieve_filtered(&old).await?.is_empty());
+
+        let other = EventFilter::new().aggregate_type("other");
+        assert!(store.retrieve_filtered(&other).await?.is_empty());
+
+        let query: DispatchQueryData =
+            QueryProcessor::process_filtered(&store, &EventFilter::new().aggregate_id(&second))
+                .await?;
+        assert!(query.is_assigned_to(mock::DISPATCHER));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let result = assert_filters(FileEventStore::new(&path)).await;
+        mock::remove_store(&path);
+
+        result
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() -> Result<(), Error> {
+        assert_filters(InMemoryEventStore::new()).await
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_store() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let result = assert_filters(cqrs_eventsourcing::SqliteEventStore::new(&path)?).await;
+        mock::remove_store(&path);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod file_store_crash_test {
+    use super::*;
+    use cqrs_eventsourcing::Durability;
+
+    type FormatedEvents = Vec<FormatedEvent<Dispatch, DispatchEvent>>;
+
+    /// A batch of 2 events then a batch of 3, returns the file length after the first batch
+    async fn two_batches(path: &str, id: &str) -> Result<u64, Error> {
+        let store = FileEventStore::new(path).with_durability(Durability::Full);
+        mock::append_requested(&store, id, 2).await?;
+        let committed = std::fs::metadata(path).unwrap().len();
+        mock::append_requested(&store, id, 3).await?;
+
+        Ok(committed)
+    }
+
+    fn cut(path: &str, len: u64) {
+        let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(len).unwrap();
+    }
+
+    fn versions(events: &FormatedEvents) -> Vec<usize> {
+        events.iter().map(|e| e.version).collect()
+    }
+
+    #[tokio::test]
+    async fn test_torn_line_is_ignored_then_truncated() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let committed = two_batches(&path, &id).await?;
+
+        // Crash in the middle of the last line
+        let len = std::fs::metadata(&path).unwrap().len();
+        cut(&path, len - 10);
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        assert_eq!(versions(&store.retrieve(&id).await?), vec![1, 2]);
+        assert_eq!(store.read_all(0, None).await?.len(), 2);
+
+        let appended = mock::append_requested(&store, &id, 1).await?;
+        assert_eq!(appended[0].position, 3);
+        assert_eq!(appended[0].version, 3);
+
+        let events = store.retrieve(&id).await?;
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        mock::remove_store(&path);
+
+        assert_eq!(versions(&events), vec![1, 2, 3]);
+        assert_eq!(lines, 3);
+        assert!(committed > 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_torn_batch_at_line_boundary_is_ignored() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        two_batches(&path, &id).await?;
+
+        // Crash after 2 of the 3 lines of the second batch
+        let data = std::fs::read_to_string(&path).unwrap();
+        let end: usize = data.lines().take(4).map(|l| l.len() + 1).sum();
+        cut(&path, end as u64);
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let events = store.retrieve(&id).await?;
+        let context = store.assemble_aggregate(Some(id.clone())).await?;
+        mock::remove_store(&path);
+
+        assert_eq!(versions(&events), vec![1, 2]);
+        assert_eq!(context.version, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_truncates_torn_tail() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let committed = two_batches(&path, &id).await?;
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        cut(&path, len - 1);
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::open(&path).await?;
+        let truncated = std::fs::metadata(&path).unwrap().len();
+        let removed = store.recover().await?;
+        mock::remove_store(&path);
+
+        assert_eq!(truncated, committed);
+        assert_eq!(removed, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lines_without_batch_frame_are_read() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        two_batches(&path, &id).await?;
+
+        // Lines written before batches were framed
+        let data = std::fs::read_to_string(&path).unwrap();
+        let legacy: Vec<String> = data
+            .lines()
+            .map(|line| {
+                // Cut the fields out of the text, payloads are inlined byte for byte
+                let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                let frame = format!(
+                    ",\"batch_size\":{},\"batch_index\":{}",
+                    value["batch_size"], value["batch_index"]
+                );
+                assert!(line.contains(&frame));
+                line.replace(&frame, "")
+            })
+            .collect();
+        std::fs::write(&path, legacy.join("\n") + "\n").unwrap();
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .with_durability(Durability::Buffered);
+        let events = store.retrieve(&id).await?;
+        let appended = mock::append_requested(&store, &id, 1).await?;
+        mock::remove_store(&path);
+
+        assert_eq!(versions(&events), vec![1, 2, 3, 4, 5]);
+        assert_eq!(appended[0].position, 6);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod file_store_verify_test {
+    use super::*;
+    use cqrs_eventsourcing::{DuplicateVersion, VersionGap};
+
+    /// Three appends of one event each, one line per batch
+    async fn three_lines(path: &str, id: &str) -> Result<Vec<String>, Error> {
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(path);
+        for _ in 0..3 {
+            let context = store.assemble_aggregate(Some(id.to_string())).await?;
+            store
+                .append(vec![mock::requested(id)], &context, HashMap::new())
+                .await?;
+        }
+
+        let data = std::fs::read_to_string(path).unwrap();
+        Ok(data.lines().map(String::from).collect())
+    }
+
+    fn write_lines(path: &str, lines: &[String]) {
+        std::fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clean_log() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        three_lines(&path, &id).await?;
+
+        let report = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .verify()
+            .await;
+        mock::remove_store(&path);
+
+        let report = report?;
+        assert!(report.is_ok());
+        assert_eq!(report.records, 3);
+        assert_eq!(report.torn_bytes, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tampered_payload_fails_checksum() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut lines = three_lines(&path, &id).await?;
+
+        // Still valid JSON, only the checksum gives it away
+        lines[1] = lines[1].replace(mock::DISPATCHER, "someone-else");
+        write_lines(&path, &lines);
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let err = store.retrieve(&id).await.unwrap_err();
+        let report = store.verify().await;
+        mock::remove_store(&path);
+
+        assert!(err.is_corrupt());
+        assert!(err.extension().unwrap()["reason"].contains("checksum"));
+
+        let report = report?;
+        assert!(!report.is_ok());
+        assert_eq!(report.records, 2);
+        assert_eq!(report.corrupted.len(), 1);
+        assert_eq!(report.corrupted[0].line, 2);
+        assert_eq!(report.version_gaps.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version_gaps_and_duplicates() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let lines = three_lines(&path, &id).await?;
+
+        // Version 2 is lost and version 1 written twice
+        let tampered = vec![lines[0].clone(), lines[2].clone(), lines[0].clone()];
+        write_lines(&path, &tampered);
+
+        let report = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .verify()
+            .await;
+        mock::remove_store(&path);
+
+        let report = report?;
+        assert!(report.corrupted.is_empty());
+        assert_eq!(
+            report.version_gaps,
+            vec![VersionGap {
+                aggregate_type: "dispatch".to_string(),
+                aggregate_id: id.clone(),
+                line: 2,
+                expected: 2,
+                found: 3,
+            }]
+        );
+        assert_eq!(
+            report.duplicate_versions,
+            vec![DuplicateVersion {
+                aggregate_type: "dispatch".to_string(),
+                aggregate_id: id,
+                version: 1,
+                first_line: 1,
+                line: 3,
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_torn_tail_is_reported() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let lines = three_lines(&path, &id).await?;
+
+        let torn = &lines[2][..lines[2].len() / 2];
+        std::fs::write(&path, format!("{}\n{}\n{}", lines[0], lines[1], torn)).unwrap();
+
+        let report = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .verify()
+            .await;
+        mock::remove_store(&path);
+
+        let report = report?;
+        assert!(report.is_ok());
+        assert_eq!(report.records, 2);
+        assert_eq!(report.torn_bytes, torn.len() as u64);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod file_store_lock_test {
+    use super::*;
+    use fs2::FileExt;
+    use futures::future::join_all;
+    use std::time::Duration;
+
+    /// The lock is held through another file description, like another process would
+    #[tokio::test]
+    async fn test_held_lock_times_out_and_readers_proceed() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .with_lock_timeout(Duration::from_millis(50));
+        mock::append_requested(&store, &id, 1).await?;
+
+        let other = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        other.lock_exclusive().unwrap();
+
+        let locked = mock::append_requested(&store, &id, 1).await.unwrap_err();
+        let read = store.retrieve(&id).await?;
+
+        other.unlock().unwrap();
+        let version = mock::append_requested(&store, &id, 1).await;
+        mock::remove_store(&path);
+
+        assert!(locked.is_locked());
+        assert_eq!(locked.extension().unwrap()["path"], path);
+        assert_eq!(locked.extension().unwrap()["timeout_ms"], "50");
+        assert_eq!(read.len(), 1);
+        assert_eq!(version?[0].version, 2);
+
+        Ok(())
+    }
+
+    /// Stores that share no in-process lock still write one at a time, without the
+    /// file lock two of them could both write the same version
+    #[tokio::test(threaded_scheduler)]
+    async fn test_independent_stores_do_not_interleave() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let writers = (0..4).map(|_| {
+            let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+            let id = id.clone();
+            tokio::spawn(async move {
+                let mut appended = 0;
+                while appended < 5 {
+                    match mock::append_requested(&store, &id, 1).await {
+                        Ok(_) => appended += 1,
+                        Err(e) if e.is_conflict() => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            })
+        });
+        let results = join_all(writers).await;
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let report = store.verify().await;
+        let context = store.assemble_aggregate(Some(id)).await;
+        mock::remove_store(&path);
+
+        for result in results {
+            result.unwrap()?;
+        }
+        let report = report?;
+        assert!(report.is_ok());
+        assert_eq!(report.records, 20);
+        assert_eq!(context?.version, 20);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod file_store_index_test {
+    use super::*;
+
+    /// Two aggregates with interleaved batches
+    async fn interleaved(path: &str) -> Result<(String, String), Error> {
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(path);
+        let first = uuid::Uuid::new_v4().to_string();
+        let second = uuid::Uuid::new_v4().to_string();
+
+        mock::append_requested(&store, &first, 2).await?;
+        mock::append_requested(&store, &second, 1).await?;
+        mock::append_requested(&store, &first, 1).await?;
+
+        Ok((first, second))
+    }
+
+    fn positions(events: &[FormatedEvent<Dispatch, DispatchEvent>]) -> Vec<usize> {
+        events.iter().map(|e| e.position).collect()
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_reads_only_indexed_lines() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let (first, second) = interleaved(&path).await?;
+        let indexed = std::path::Path::new(&format!("{}.index", path)).exists();
+
+        // Same length, so the index still covers the log, but a scan would fail on it
+        let data = std::fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<String> = data.lines().map(String::from).collect();
+        lines[2] = "x".repeat(lines[2].len());
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let events = store.retrieve(&first).await;
+        let from = store.retrieve_from(&first, 1).await;
+        let scanned = store.read_all(0, None).await;
+        let corrupt = store.retrieve(&second).await;
+        mock::remove_store(&path);
+
+        assert!(indexed);
+        assert_eq!(positions(&events?), vec![1, 2, 4]);
+        assert_eq!(positions(&from?), vec![2, 4]);
+        assert!(scanned.unwrap_err().is_corrupt());
+        assert!(corrupt.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_index_is_rebuilt() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let (first, _) = interleaved(&path).await?;
+        let index_path = format!("{}.index", path);
+        std::fs::remove_file(&index_path).unwrap();
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let events = store.retrieve(&first).await;
+        mock::append_requested(&store, &first, 1).await?;
+        let rebuilt = std::fs::read_to_string(&index_path);
+
+        // A fresh store loads the saved index
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let reloaded = store.retrieve(&first).await;
+        mock::remove_store(&path);
+
+        assert_eq!(positions(&events?), vec![1, 2, 4]);
+        assert!(!rebuilt.unwrap().is_empty());
+        assert_eq!(positions(&reloaded?), vec![1, 2, 4, 5]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_index_is_rebuilt() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let (first, second) = interleaved(&path).await?;
+
+        // The log is replaced by a shorter one the index knows nothing about
+        let data = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = data.lines().collect();
+        std::fs::write(&path, format!("{}\n", lines[2])).unwrap();
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let first_events = store.retrieve(&first).await?;
+        let second_events = store.retrieve(&second).await?;
+        mock::append_requested(&store, &second, 1).await?;
+        let second_after = store.retrieve(&second).await?;
+        mock::remove_store(&path);
+
+        assert!(first_events.is_empty());
+        assert_eq!(positions(&second_events), vec![1]);
+        assert_eq!(positions(&second_after), vec![1, 2]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_follows_other_writers() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let (first, _) = interleaved(&path).await?;
+
+        let reader = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        assert_eq!(reader.retrieve(&first).await?.len(), 3);
+
+        // Another process appends behind the reader's index
+        let writer = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        mock::append_requested(&writer, &first, 2).await?;
+
+        let events = reader.retrieve(&first).await;
+        mock::remove_store(&path);
+
+        assert_eq!(positions(&events?), vec![1, 2, 4, 5, 6]);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod segmented_store_test {
+    use super::*;
+    use cqrs_eventsourcing::{
+        InMemorySnapshotStore, RolloverPolicy, SegmentedEventStore, Snapshot, SnapshotPolicy,
+        SnapshotStore, SnapshottingStore,
+    };
+    use futures::TryStreamExt;
+
+    type SegmentedStore = SegmentedEventStore<Dispatch, DispatchEvent>;
+
+    fn positions(events: &[FormatedEvent<Dispatch, DispatchEvent>]) -> Vec<usize> {
+        events.iter().map(|e| e.position).collect()
+    }
+
+    fn read_only(path: &str) -> bool {
+        std::fs::metadata(path).unwrap().permissions().readonly()
+    }
+
+    #[test]
+    fn test_policy() {
+        assert!(!RolloverPolicy::count(3).should_roll(1024, 2));
+        assert!(RolloverPolicy::count(3).should_roll(0, 3));
+        assert!(!RolloverPolicy::size(100).should_roll(99, 1000));
+        assert!(RolloverPolicy::size(100).should_roll(100, 1));
+    }
+
+    #[tokio::test]
+    async fn test_stream_from_spans_segments() -> Result<(), Error> {
+        let dir = mock::temp_store_path();
+        let store = SegmentedStore::new(&dir).with_rollover_policy(RolloverPolicy::count(1));
+        let id = uuid::Uuid::new_v4().to_string();
+
+        for i in 0..3 {
+            mock::append_one(
+                &store,
+                &id,
+                mock::requested_by(&id, &format!("client-{}", i)),
+            )
+            .await?;
+        }
+
+        let tail: Result<Vec<_>, Error> = store.stream_from(&id, 1).try_collect().await;
+        let manifest = store.manifest().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let tail = tail?;
+        assert_eq!(manifest?.segments.len(), 4);
+        assert_eq!(
+            tail.iter().map(|e| e.version).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(positions(&tail), vec![2, 3]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_segments_roll_over_by_count() -> Result<(), Error> {
+        let dir = mock::temp_store_path();
+        let store = SegmentedStore::new(&dir).with_rollover_policy(RolloverPolicy::count(3));
+        let first = uuid::Uuid::new_v4().to_string();
+        let second = uuid::Uuid::new_v4().to_string();
+
+        for i in 0..4 {
+            mock::append_one(
+                &store,
+                &first,
+                mock::requested_by(&first, &format!("first-{}", i)),
+            )
+            .await?;
+            mock::append_one(
+                &store,
+                &second,
+                mock::requested_by(&second, &format!("second-{}", i)),
+            )
+            .await?;
+        }
+
+        // A context assembled before the last append is stale across segments
+        let stale = store.assemble_aggregate(Some(first.clone())).await?;
+        mock::append_one(&store, &first, mock::requested_by(&first, "latest")).await?;
+        let conflict = store
+            .append(
+                vec![mock::requested_by(&first, "stale")],
+                &stale,
+                HashMap::new(),
+            )
+            .await;
+
+        let manifest = store.manifest().await?;
+        let sealed: Vec<bool> = manifest
+            .segments
+            .iter()
+            .map(|segment| read_only(&store.segment_path(segment)))
+            .collect();
+        let all = store.read_all(0, None).await;
+        let from = store.read_all(5, None).await;
+        let events = store.retrieve(&first).await;
+        let context = store.assemble_aggregate(Some(first.clone())).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(conflict.unwrap_err().is_conflict());
+        let layout: Vec<(usize, usize, bool)> = manifest
+            .segments
+            .iter()
+            .map(|segment| (segment.base, segment.lines, segment.sealed))
+            .collect();
+        assert_eq!(
+            layout,
+            vec![(0, 3, true), (3, 3, true), (6, 3, true), (9, 0, false)]
+        );
+        assert_eq!(sealed, vec![true, true, true, false]);
+        assert_eq!(positions(&all?), (1..=9).collect::<Vec<_>>());
+        assert_eq!(positions(&from?), (5..=9).collect::<Vec<_>>());
+
+        let events = events?;
+        assert_eq!(positions(&events), vec![1, 3, 5, 7, 9]);
+        let versions: Vec<usize> = events.iter().map(|e| e.version).collect();
+        assert_eq!(versions, vec![1, 2, 3, 4, 5]);
+
+        let context = context?;
+        assert_eq!(context.version, 5);
+        assert_eq!(context.aggregate.client, "latest");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_segments_roll_over_by_size() -> Result<(), Error> {
+        let dir = mock::temp_store_path();
+        let store = SegmentedStore::new(&dir).with_rollover_policy(RolloverPolicy::size(1));
+        let id = uuid::Uuid::new_v4().to_string();
+
+        for i in 0..3 {
+            mock::append_one(
+                &store,
+                &id,
+                mock::requested_by(&id, &format!("client-{}", i)),
+            )
+            .await?;
+        }
+        let manifest = store.manifest().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let bases: Vec<usize> = manifest?.segments.iter().map(|s| s.base).collect();
+        assert_eq!(bases, vec![0, 1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_segments_are_readable_by_file_store() -> Result<(), Error> {
+        let dir = mock::temp_store_path();
+        let store = SegmentedStore::new(&dir).with_rollover_policy(RolloverPolicy::count(2));
+        let id = uuid::Uuid::new_v4().to_string();
+
+        for i in 0..5 {
+            mock::append_one(
+                &store,
+                &id,
+                mock::requested_by(&id, &format!("client-{}", i)),
+            )
+            .await?;
+        }
+
+        // The manifest lists the segments in order, each one a plain log
+        let manifest: cqrs_eventsourcing::SegmentManifest =
+            serde_json::from_str(&std::fs::read_to_string(store.manifest_path()).unwrap()).unwrap();
+        let mut read = Vec::new();
+        for segment in manifest.segments.iter() {
+            let file_store =
+                FileEventStore::<Dispatch, DispatchEvent>::new(&store.segment_path(segment));
+            for event in file_store.read_all(0, None).await? {
+                read.push((segment.base + event.position, event.payload));
+            }
+        }
+        let all = store.read_all(0, None).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let all: Vec<(usize, DispatchEvent)> =
+            all?.into_iter().map(|e| (e.position, e.payload)).collect();
+        assert_eq!(read, all);
+        assert_eq!(read.len(), 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compaction_drops_events_covered_by_snapshots() -> Result<(), Error> {
+        let dir = mock::temp_store_path();
+        let store = SegmentedStore::new(&dir).with_rollover_policy(RolloverPolicy::count(2));
+        let snapshots = InMemorySnapshotStore::<Dispatch>::new();
+        let id = uuid::Uuid::new_v4().to_string();
+
+        for i in 1..=6 {
+            mock::append_one(&store, &id, mock::requested_by(&id, &format!("v{}", i))).await?;
+        }
+        let aggregate = Dispatch {
+            client: "v5".to_string(),
+            ..Default::default()
+        };
+        snapshots.save(Snapshot::new(&id, 5, aggregate)).await?;
+
+        let manifest = store.manifest().await?;
+        let size = |manifest: &cqrs_eventsourcing::SegmentManifest| -> u64 {
+            manifest
+                .segments
+                .iter()
+                .filter(|s| s.sealed)
+                .map(|s| std::fs::metadata(store.segment_path(s)).unwrap().len())
+                .sum()
+        };
+        let before = size(&manifest);
+
+        let dropped = store.compact(&snapshots).await;
+        let after = size(&manifest);
+        let sealed = read_only(&store.segment_path(&manifest.segments[0]));
+        let replayed = store.assemble_aggregate(Some(id.clone())).await;
+        let retrieved = store.retrieve(&id).await;
+        let read = store.read_all(0, None).await;
+        let remaining = store.retrieve_from(&id, 5).await;
+
+        // Rebuilt from the snapshot, appends carry on from the stream version
+        let snapshotting =
+            SnapshottingStore::new(store.clone(), snapshots.clone(), SnapshotPolicy::every(0));
+        let context = snapshotting.assemble_aggregate(Some(id.clone())).await;
+        mock::append_one(&snapshotting, &id, mock::requested_by(&id, "v7")).await?;
+        let latest = store.retrieve_from(&id, 6).await;
+        let read_after = store.read_all(7, None).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(dropped?, 3);
+        assert!(after < before);
+        assert!(sealed);
+
+        // Replays of dropped events fail instead of building the wrong state
+        assert!(replayed.err().unwrap().is_compacted());
+        assert!(retrieved.unwrap_err().is_compacted());
+        assert!(read.unwrap_err().is_compacted());
+        let remaining = remaining?;
+        assert_eq!(positions(&remaining), vec![6]);
+        assert_eq!(remaining[0].version, 6);
+
+        let context = context?;
+        assert_eq!(context.version, 6);
+        assert_eq!(context.aggregate.client, "v6");
+
+        let latest = latest?;
+        assert_eq!(positions(&latest), vec![7]);
+        assert_eq!(latest[0].version, 7);
+        assert_eq!(positions(&read_after?), vec![7]);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod serializer_test {
+    use super::*;
+    use cqrs_eventsourcing::Serializer;
+
+    fn records(path: &str) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_enabled_formats_round_trip() -> Result<(), Error> {
+        let event = mock::requested(mock::DISPATCHID);
+        let formats = [
+            Serializer::Json,
+            Serializer::Cbor,
+            Serializer::MessagePack,
+            Serializer::Bincode,
+        ];
+
+        for format in formats.iter().filter(|format| format.is_enabled()) {
+            let bytes = format.serialize(&event)?;
+            assert_eq!(format.deserialize::<DispatchEvent>(&bytes)?, event);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_payload_is_inlined() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        mock::append_one(&FileEventStore::new(&path), &id, mock::requested(&id)).await?;
+        let records = records(&path);
+        mock::remove_store(&path);
+
+        assert_eq!(records[0]["format"], "json");
+        assert_eq!(records[0]["payload"]["Requested"]["id"], id.as_str());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lines_written_before_formats_are_read() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let legacy = serde_json::json!({
+            "aggregate_id": id,
+            "aggregate_type": "dispatch",
+            "version": 1,
+            "payload": serde_json::to_string(&mock::requested(&id)).unwrap(),
+            "meta": {},
+            "created_at": mock::FIXEDDATE,
+        });
+        std::fs::write(&path, legacy.to_string() + "\n").unwrap();
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        mock::append_one(&store, &id, mock::requested(&id)).await?;
+        let events = store.retrieve(&id).await;
+        mock::remove_store(&path);
+
+        let payloads: Vec<DispatchEvent> = events?.into_iter().map(|e| e.payload).collect();
+        assert_eq!(payloads, vec![mock::requested(&id), mock::requested(&id)]);
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "cbor"))]
+    #[tokio::test]
+    async fn test_disabled_format_is_an_error() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = FileEventStore::new(&path).with_serializer(Serializer::Cbor);
+        let appended =
+            mock::append_one(&store, mock::DISPATCHID, mock::requested(mock::DISPATCHID)).await;
+        mock::remove_store(&path);
+
+        let error = appended.unwrap_err();
+        assert_eq!(error.extension().unwrap()["format"], "cbor");
+
+        Ok(())
+    }
+
+    #[cfg(any(feature = "cbor", feature = "msgpack", feature = "bincode"))]
+    #[tokio::test]
+    async fn test_binary_records_are_smaller() -> Result<(), Error> {
+        let line_size = |format: Serializer| async move {
+            let path = mock::temp_store_path();
+            let store = FileEventStore::new(&path).with_serializer(format);
+            let appended =
+                mock::append_one(&store, mock::DISPATCHID, mock::requested(mock::DISPATCHID)).await;
+            let size = std::fs::metadata(&path).map(|metadata| metadata.len());
+            mock::remove_store(&path);
+
+            appended.map(|_| size.unwrap())
+        };
+
+        let json = line_size(Serializer::Json).await?;
+        let formats = [
+            Serializer::Cbor,
+            Serializer::MessagePack,
+            Serializer::Bincode,
+        ];
+        for format in formats.iter().filter(|format| format.is_enabled()) {
+            let size = line_size(*format).await?;
+            assert!(
+                size < json,
+                "{:?} record of {} bytes, json {}",
+                format,
+                size,
+                json
+            );
+        }
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "cbor", feature = "msgpack", feature = "bincode"))]
+    #[tokio::test]
+    async fn test_log_mixes_formats() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let formats = [
+            Serializer::Json,
+            Serializer::Cbor,
+            Serializer::MessagePack,
+            Serializer::Bincode,
+        ];
+
+        let mut offsets = Vec::new();
+        for format in formats.iter() {
+            offsets.push(std::fs::metadata(&path).map_or(0, |metadata| metadata.len()) as usize);
+            mock::append_one(
+                &FileEventStore::new(&path).with_serializer(*format),
+                &id,
+                mock::requested(&id),
+            )
+            .await?;
+        }
+
+        // Any store reads every line whatever its own format
+        let events = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .retrieve(&id)
+            .await;
+        let log = std::fs::read(&path).unwrap();
+        mock::remove_store(&path);
+
+        let versions: Vec<usize> = events?.iter().map(|e| e.version).collect();
+        assert_eq!(versions, vec![1, 2, 3, 4]);
+        // JSON lines open with a brace, binary records with the tag of their format
+        let first_bytes: Vec<u8> = offsets.iter().map(|offset| log[*offset]).collect();
+        assert_eq!(first_bytes, vec![b'{', 1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "bincode")]
+    #[tokio::test]
+    async fn test_damaged_binary_records() -> Result<(), Error> {
+        use cqrs_eventsourcing::{CorruptLinePolicy, StoreWarning};
+        use std::sync::{Arc, Mutex};
+
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .with_serializer(Serializer::Bincode);
+        mock::append_one(&store, &id, mock::requested(&id)).await?;
+        let first = std::fs::metadata(&path).unwrap().len() as usize;
+        mock::append_one(&store, &id, mock::requested_by(&id, "second")).await?;
+
+        // A flipped payload byte fails the CRC, a cut record is a torn write
+        let mut log = std::fs::read(&path).unwrap();
+        log[first - 2] ^= 0xff;
+        let torn = log.len() - 5;
+        std::fs::write(&path, &log[..torn]).unwrap();
+
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink = warnings.clone();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .with_corrupt_line_policy(CorruptLinePolicy::Skip)
+            .with_warning_handler(move |warning| sink.lock().unwrap().push(warning.clone()));
+        let skipped = store.retrieve(&id).await;
+        let recovered = store.recover().await;
+        mock::remove_store(&path);
+
+        assert!(skipped?.is_empty());
+        assert_eq!(recovered?, (torn - first) as u64);
+        assert!(matches!(
+            warnings.lock().unwrap().as_slice(),
+            [
+                StoreWarning::IndexRebuilt { .. },
+                StoreWarning::SkippedLine { line: 1, reason, .. },
+                StoreWarning::TruncatedBatch { .. },
+            ] if reason.contains("checksum")
+        ));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod compression_test {
+    use super::*;
+    use cqrs_eventsourcing::{Compression, CompressionPolicy};
+
+    #[test]
+    fn test_policy() {
+        let policy = CompressionPolicy::above(Compression::Gzip, 100);
+
+        assert_eq!(policy.compression_for(99), None);
+        assert_eq!(policy.compression_for(100), Some(Compression::Gzip));
+        assert_eq!(CompressionPolicy::none().compression_for(1 << 20), None);
+    }
+
+    #[cfg(any(feature = "zstd", feature = "gzip"))]
+    #[tokio::test]
+    async fn test_large_payloads_are_compressed() -> Result<(), Error> {
+        let compression = match cfg!(feature = "zstd") {
+            true => Compression::Zstd,
+            false => Compression::Gzip,
+        };
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let large = mock::requested_by(&id, &"client ".repeat(500));
+        let small = mock::requested_by(&id, mock::CLIENT);
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .with_compression(CompressionPolicy::above(compression, 1024));
+        mock::append(&store, &id, vec![large.clone(), small.clone()]).await?;
+
+        // Records say how they are encoded, so any store reads them
+        let events = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .retrieve(&id)
+            .await;
+        let data = std::fs::read_to_string(&path).unwrap();
+        mock::remove_store(&path);
+
+        let records: Vec<serde_json::Value> = data
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records[0]["compression"], compression.name());
+        assert!(records[0]["payload"].is_string());
+        assert!(records[1].get("compression").is_none());
+        assert!(data.len() < 1024);
+
+        let payloads: Vec<DispatchEvent> = events?.into_iter().map(|e| e.payload).collect();
+        assert_eq!(payloads, vec![large, small]);
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[tokio::test]
+    async fn test_disabled_compression_is_an_error() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .with_compression(CompressionPolicy::above(Compression::Zstd, 0));
+        let appended = mock::append(
+            &store,
+            mock::DISPATCHID,
+            vec![mock::requested_by("id", "client")],
+        )
+        .await;
+        mock::remove_store(&path);
+
+        let error = appended.unwrap_err();
+        assert_eq!(error.extension().unwrap()["compression"], "zstd");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_payload_is_not_a_corrupt_line() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .with_corrupt_line_policy(cqrs_eventsourcing::CorruptLinePolicy::Skip);
+        mock::append(&store, &id, vec![mock::requested_by(&id, mock::CLIENT)]).await?;
+
+        // An intact line whose payload isn't zstd data, or zstd isn't compiled in
+        let data = std::fs::read_to_string(&path).unwrap();
+        let mut record: serde_json::Value = serde_json::from_str(data.trim_end()).unwrap();
+        let bytes = record["payload"].to_string().into_bytes();
+        record["compression"] = "zstd".into();
+        record["checksum"] = crc32fast::hash(&bytes).into();
+        record["payload"] = base64::encode(&bytes).into();
+        std::fs::write(&path, record.to_string() + "\n").unwrap();
+
+        let retrieved = store.retrieve(&id).await;
+        mock::remove_store(&path);
+
+        let error = retrieved.unwrap_err();
+        assert!(!error.is_corrupt());
+        assert_eq!(error.extension().unwrap()["compression"], "zstd");
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "sqlite", feature = "gzip"))]
+    #[tokio::test]
+    async fn test_sqlite_compresses_large_payloads() -> Result<(), Error> {
+        use cqrs_eventsourcing::SqliteEventStore;
+
+        let path = mock::temp_store_path();
+        let store = SqliteEventStore::<Dispatch, DispatchEvent>::new(&path)?
+            .with_compression(CompressionPolicy::above(Compression::Gzip, 1024));
+        let id = uuid::Uuid::new_v4().to_string();
+        let large = mock::requested_by(&id, &"client ".repeat(500));
+        let small = mock::requested_by(&id, mock::CLIENT);
+
+        mock::append(&store, &id, vec![large.clone(), small.clone()]).await?;
+        let context = store.assemble_aggregate(Some(id.clone())).await?;
+        let payloads: Vec<DispatchEvent> = store
+            .retrieve(&id)
+            .await?
+            .into_iter()
+            .map(|e| e.payload)
+            .collect();
+
+        // The codec is in its own column, payloads are never sniffed
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT compression FROM events WHERE aggregate_id = ?1 ORDER BY sequence")
+            .unwrap();
+        let codecs: Vec<Option<String>> = stmt
+            .query_map(rusqlite::params![id], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        drop(stmt);
+        drop(conn);
+        mock::remove_store(&path);
+
+        assert_eq!(payloads, vec![large, small]);
+        assert_eq!(codecs, vec![Some("gzip".to_string()), None]);
+        assert_eq!(context.version, 2);
+        assert_eq!(context.aggregate.client, mock::CLIENT);
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "postgres", feature = "gzip"))]
+    #[tokio::test]
+    async fn test_postgres_compresses_large_payloads() -> Result<(), Error> {
+        use cqrs_eventsourcing::PostgresEventStore;
+
+        let store =
+            PostgresEventStore::<Dispatch, DispatchEvent>::connect(&mock::postgres_params())
+                .await?
+                .with_compression(CompressionPolicy::above(Compression::Gzip, 1024));
+        let id = uuid::Uuid::new_v4().to_string();
+        let large = mock::requested_by(&id, &"client ".repeat(500));
+        let small = mock::requested_by(&id, mock::CLIENT);
+
+        mock::append(&store, &id, vec![large.clone(), small.clone()]).await?;
+        let payloads: Vec<DispatchEvent> = store
+            .retrieve(&id)
+            .await?
+            .into_iter()
+            .map(|e| e.payload)
+            .collect();
+
+        assert_eq!(payloads, vec![large, small]);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod upcaster_test {
+    use super::*;
+    use cqrs_eventsourcing::Upcasters;
+
+    // The same event at three schema versions: v2 renamed `client` to
+    // `customer`, v3 added `priority`
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+    enum TicketV1 {
+        Opened { client: String },
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+    enum TicketV2 {
+        Opened { customer: String },
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+    enum TicketV3 {
+        Opened { customer: String, priority: u8 },
+    }
+
+    impl DomainEvent<Dispatch> for TicketV1 {
+        fn apply(self, _: &mut Dispatch) {}
+
+        fn name() -> &'static str {
+            "Opened"
+        }
+    }
+
+    impl DomainEvent<Dispatch> for TicketV2 {
+        fn apply(self, _: &mut Dispatch) {}
+
+        fn name() -> &'static str {
+            "Opened"
+        }
+
+        fn schema_version() -> usize {
+            2
+        }
+    }
+
+    impl DomainEvent<Dispatch> for TicketV3 {
+        fn apply(self, aggregate: &mut Dispatch) {
+            let TicketV3::Opened { customer, .. } = self;
+            aggregate.client = customer;
+        }
+
+        fn name() -> &'static str {
+            "Opened"
+        }
+
+        fn schema_version() -> usize {
+            3
+        }
+    }
+
+    fn opened(payload: &mut serde_json::Value) -> &mut serde_json::Map<String, serde_json::Value> {
+        payload["Opened"].as_object_mut().unwrap()
+    }
+
+    fn upcasters() -> Upcasters {
+        Upcasters::new()
+            .register(1, |mut payload| {
+                let client = opened(&mut payload).remove("client").unwrap();
+                opened(&mut payload).insert("customer".to_string(), client);
+                payload
+            })
+            .register(2, |mut payload| {
+                opened(&mut payload).insert("priority".to_string(), 3.into());
+                payload
+            })
+    }
+
+    async fn append<E, S>(store: &S, id: &str, event: E) -> Result<(), Error>
+    where
+        E: DomainEvent<Dispatch>,
+        S: Store<Dispatch, E>,
+    {
+        let context = store.assemble_aggregate(Some(id.to_string())).await?;
+        store.append(vec![event], &context, HashMap::new()).await?;
+
+        Ok(())
+    }
+
+    fn v3(customer: &str, priority: u8) -> TicketV3 {
+        TicketV3::Opened {
+            customer: customer.to_string(),
+            priority,
+        }
+    }
+
+    #[test]
+    fn test_steps_are_chained() -> Result<(), Error> {
+        let v1 = serde_json::json!({ "Opened": { "client": "acme" } });
+
+        let from_v1 = upcasters().upcast(v1.clone(), 1, 3)?;
+        let from_v2 = upcasters().upcast(
+            serde_json::json!({ "Opened": { "customer": "acme" } }),
+            2,
+            3,
+        )?;
+        let missing = Upcasters::new()
+            .register(2, |payload| payload)
+            .upcast(v1.clone(), 1, 3);
+        let newer = upcasters().upcast(v1, 4, 3);
+
+        assert_eq!(
+            from_v1,
+            serde_json::json!({ "Opened": { "customer": "acme", "priority": 3 } })
+        );
+        assert_eq!(from_v1, from_v2);
+        assert_eq!(
+            missing.unwrap_err().extension().unwrap()["from_version"],
+            "1"
+        );
+        assert!(newer.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store_upcasts_old_records() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let opened_v1 = TicketV1::Opened {
+            client: "first".to_string(),
+        };
+        let opened_v2 = TicketV2::Opened {
+            customer: "second".to_string(),
+        };
+        append(&FileEventStore::new(&path), &id, opened_v1).await?;
+        // The v2 store already upcasts v1 records
+        let store_v2 = FileEventStore::new(&path).with_upcasters(upcasters());
+        append(&store_v2, &id, opened_v2).await?;
+
+        let store = FileEventStore::<Dispatch, TicketV3>::new(&path).with_upcasters(upcasters());
+        append(&store, &id, v3("third", 1)).await?;
+        let events = store.retrieve(&id).await;
+        let context = store.assemble_aggregate(Some(id.clone())).await;
+        // Missing upcasters fail the read whatever the corrupt line policy is
+        let without = FileEventStore::<Dispatch, TicketV3>::new(&path)
+            .with_corrupt_line_policy(cqrs_eventsourcing::CorruptLinePolicy::Skip)
+            .retrieve(&id)
+            .await;
+        mock::remove_store(&path);
+
+        let payloads: Vec<TicketV3> = events?.into_iter().map(|e| e.payload).collect();
+        assert_eq!(
+            payloads,
+            vec![v3("first", 3), v3("second", 3), v3("third", 1)]
+        );
+        assert_eq!(context?.aggregate.client, "third");
+        let error = without.unwrap_err();
+        assert!(!error.is_corrupt());
+        assert_eq!(error.extension().unwrap()["from_version"], "1");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "bincode")]
+    #[tokio::test]
+    async fn test_bincode_records_are_not_upcast() -> Result<(), Error> {
+        use cqrs_eventsourcing::Serializer;
+
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+        let opened_v2 = TicketV2::Opened {
+            customer: "second".to_string(),
+        };
+        let store_v2 = FileEventStore::new(&path).with_serializer(Serializer::Bincode);
+        append(&store_v2, &id, opened_v2).await?;
+
+        let events = FileEventStore::<Dispatch, TicketV3>::new(&path)
+            .with_upcasters(upcasters())
+            .retrieve(&id)
+            .await;
+        mock::remove_store(&path);
+
+        let error = events.unwrap_err();
+        assert!(!error.is_corrupt());
+        assert_eq!(error.extension().unwrap()["from_version"], "2");
+        assert!(error.extension().unwrap()["reason"].contains("bincode"));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_store_upcasts_old_rows() -> Result<(), Error> {
+        use cqrs_eventsourcing::SqliteEventStore;
+        use futures::TryStreamExt;
+
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let opened_v1 = TicketV1::Opened {
+            client: "first".to_string(),
+        };
+        let opened_v2 = TicketV2::Opened {
+            customer: "second".to_string(),
+        };
+        append(&SqliteEventStore::new(&path)?, &id, opened_v1).await?;
+        let store_v2 = SqliteEventStore::new(&path)?.with_upcasters(upcasters());
+        append(&store_v2, &id, opened_v2).await?;
+
+        let store = SqliteEventStore::<Dispatch, TicketV3>::new(&path)?.with_upcasters(upcasters());
+        let events = store.retrieve(&id).await;
+        let streamed: Result<Vec<_>, Error> = store.stream(&id).try_collect().await;
+        let _ = std::fs::remove_file(&path);
+
+        let payloads: Vec<TicketV3> = events?.into_iter().map(|e| e.payload).collect();
+        assert_eq!(payloads, vec![v3("first", 3), v3("second", 3)]);
+        assert_eq!(streamed?.len(), 2);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_table_without_schema_version_is_migrated() -> Result<(), Error> {
+        use cqrs_eventsourcing::SqliteEventStore;
+
+        let path = mock::temp_store_path();
+        let id = uuid::Uuid::new_v4().to_string();
+
+        // Table and row as written before schema versions
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE events (
+                sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+                aggregate_type TEXT NOT NULL,
+                aggregate_id TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                payload TEXT NOT NULL,
+                meta TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                UNIQUE (aggregate_type, aggregate_id, version)
+            );",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO events (aggregate_type, aggregate_id, version, payload, meta, created_at)
+             VALUES ('dispatch', ?1, 1, '{\"Opened\":{\"client\":\"first\"}}', '{}', ?2)",
+            rusqlite::params![id, mock::FIXEDDATE],
+        )
+        .unwrap();
+        drop(conn);
+
+        let store = SqliteEventStore::<Dispatch, TicketV3>::new(&path)?.with_upcasters(upcasters());
+        append(&store, &id, v3("second", 1)).await?;
+        let events = store.retrieve(&id).await;
+        let _ = std::fs::remove_file(&path);
+
+        let payloads: Vec<TicketV3> = events?.into_iter().map(|e| e.payload).collect();
+        assert_eq!(payloads, vec![v3("first", 3), v3("second", 1)]);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod trace_test {
+    use super::*;
+    use cqrs_eventsourcing::InMemoryEventStore;
+    use uuid::Uuid;
+
+    type MemoryStore = InMemoryEventStore<Dispatch, DispatchEvent>;
+
+    /// Accept issued in reaction to an event, carrying its trace
+    #[derive(Clone)]
+    struct ReactiveAccept {
+        id: String,
+        correlation_id: String,
+        causation_id: String,
+    }
+
+    #[async_trait]
+    impl Command<Dispatch, DispatchEvent> for ReactiveAccept {
+        fn id(&self) -> Option<String> {
+            Some(self.id.clone())
+        }
+
+        async fn handle(
+            self,
+            _context: &AggregateContext<Dispatch>,
+        ) -> Result<Vec<DispatchEvent>, Error> {
+            Ok(vec![DispatchEvent::Accepted(Accepted {
+                dispatcher: mock::DISPATCHER.to_string(),
+                accepted_at: mock::FIXEDDATE.to_string(),
+            })])
+        }
+
+        fn correlation_id(&self) -> Option<String> {
+            Some(self.correlation_id.clone())
+        }
+
+        fn causation_id(&self) -> Option<String> {
+            Some(self.causation_id.clone())
+        }
+    }
+
+    fn reaction_to(event: &FormatedEvent<Dispatch, DispatchEvent>) -> ReactiveAccept {
+        ReactiveAccept {
+            id: event.aggregate_id.clone(),
+            correlation_id: event.correlation_id.clone().unwrap(),
+            causation_id: event.event_id.clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_starts_and_continues_a_chain() -> Result<(), Error> {
+        let store = MemoryStore::new();
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        cqrs.execute(mock::request(), HashMap::new()).await?;
+        let requested = store.read_all(0, None).await?.remove(0);
+        cqrs.execute(reaction_to(&requested), HashMap::new())
+            .await?;
+        let accepted = store.retrieve(&requested.aggregate_id).await?.remove(1);
+
+        assert!(Uuid::parse_str(&requested.event_id).is_ok());
+        assert_ne!(requested.event_id, accepted.event_id);
+        assert!(requested.correlation_id.is_some());
+        assert_eq!(requested.correlation_id, requested.causation_id);
+        assert_eq!(accepted.correlation_id, requested.correlation_id);
+        assert_eq!(accepted.causation_id, Some(requested.event_id.clone()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commands_start_separate_chains() -> Result<(), Error> {
+        let store = MemoryStore::new();
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        cqrs.execute(mock::request(), HashMap::new()).await?;
+        cqrs.execute(mock::request(), HashMap::new()).await?;
+        let events = store.read_all(0, None).await?;
+
+        assert_eq!(events.len(), 2);
+        assert_ne!(events[0].correlation_id, events[1].correlation_id);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store_keeps_ids() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        cqrs.execute(mock::request(), HashMap::new()).await?;
+        let requested = store.read_all(0, None).await?.remove(0);
+        cqrs.execute(reaction_to(&requested), HashMap::new())
+            .await?;
+        let events = FileEventStore::<Dispatch, DispatchEvent>::new(&path)
+            .retrieve(&requested.aggregate_id)
+            .await;
+        mock::remove_store(&path);
+        let events = events?;
+
+        assert_eq!(events[0].event_id, requested.event_id);
+        assert_eq!(events[0].correlation_id, requested.correlation_id);
+        assert_eq!(events[1].correlation_id, requested.correlation_id);
+        assert_eq!(events[1].causation_id, Some(requested.event_id.clone()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_records_without_ids_get_stable_ones() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let context = store.assemble_aggregate(None).await?;
+        let events = vec![
+            DispatchEvent::Requested(Requested {
+                id: context.id.clone(),
+                client: mock::CLIENT.to_string(),
+                dispatcher: mock::DISPATCHER.to_string(),
+            }),
+            DispatchEvent::Accepted(Accepted {
+                dispatcher: mock::DISPATCHER.to_string(),
+                accepted_at: mock::FIXEDDATE.to_string(),
+            }),
+        ];
+        let appended = store.append(events, &context, HashMap::new()).await?;
+
+        // Lines as written before event ids
+        let lines: Vec<String> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let start = line.find("\"event_id\"").unwrap();
+                let end = start + line[start..].find(',').unwrap() + 1;
+                format!("{}{}", &line[..start], &line[end..])
+            })
+            .collect();
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let first = store.read_all(0, None).await;
+        let second = store.read_all(0, None).await;
+        mock::remove_store(&path);
+        let (first, second) = (first?, second?);
+
+        assert_eq!(first.len(), 2);
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert!(Uuid::parse_str(&a.event_id).is_ok());
+            assert_eq!(a.event_id, b.event_id);
+            assert_eq!(a.correlation_id, None);
+        }
+        assert_ne!(first[0].event_id, first[1].event_id);
+        assert_ne!(first[0].event_id, appended[0].event_id);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_store_keeps_ids() -> Result<(), Error> {
+        use cqrs_eventsourcing::SqliteEventStore;
+
+        let path = mock::temp_store_path();
+        let store = SqliteEventStore::<Dispatch, DispatchEvent>::new(&path)?;
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        cqrs.execute(mock::request(), HashMap::new()).await?;
+        let requested = store.read_all(0, None).await?.remove(0);
+        cqrs.execute(reaction_to(&requested), HashMap::new())
+            .await?;
+        let events = store.retrieve(&requested.aggregate_id).await;
+        let _ = std::fs::remove_file(&path);
+        let events = events?;
+
+        assert_eq!(events[0].event_id, requested.event_id);
+        assert_eq!(events[1].correlation_id, requested.correlation_id);
+        assert_eq!(events[1].causation_id, Some(requested.event_id.clone()));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn test_postgres_store_keeps_ids() -> Result<(), Error> {
+        use cqrs_eventsourcing::PostgresEventStore;
+
+        let store =
+            PostgresEventStore::<Dispatch, DispatchEvent>::connect(&mock::postgres_params())
+                .await?;
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        let command = ReactiveAccept {
+            id: Uuid::new_v4().to_string(),
+            correlation_id: Uuid::new_v4().to_string(),
+            causation_id: Uuid::new_v4().to_string(),
+        };
+        cqrs.execute(command.clone(), HashMap::new()).await?;
+        let events = store.retrieve(&command.id).await?;
+
+        assert_eq!(events.len(), 1);
+        assert!(Uuid::parse_str(&events[0].event_id).is_ok());
+        assert_eq!(events[0].correlation_id, Some(command.correlation_id));
+        assert_eq!(events[0].causation_id, Some(command.causation_id));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod meta_test {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use cqrs_eventsourcing::{EventFilter, EventMeta, InMemoryEventStore, TestStore};
+
+    /// Request against stores with typed metadata
+    #[derive(Clone)]
+    struct TypedRequest;
+
+    #[async_trait]
+    impl Command<Dispatch, DispatchEvent, EventMeta> for TypedRequest {
+        fn id(&self) -> Option<String> {
+            None
+        }
+
+        async fn handle(
+            self,
+            _context: &AggregateContext<Dispatch>,
+        ) -> Result<Vec<DispatchEvent>, Error> {
+            Ok(vec![DispatchEvent::Requested(Requested {
+                id: mock::DISPATCHID.to_string(),
+                client: mock::CLIENT.to_string(),
+                dispatcher: mock::DISPATCHER.to_string(),
+            })])
+        }
+    }
+
+    fn meta() -> EventMeta {
+        EventMeta::new()
+            .with_actor("dispatcher-7")
+            .with_tenant("acme")
+            .with_source("10.0.0.7")
+            .with_client_timestamp(Utc.with_ymd_and_hms(2021, 3, 11, 17, 39, 23).unwrap())
+            .with_extra("channel", "mobile")
+    }
+
+    #[test]
+    fn test_event_meta_serializes_extra_entries_inline() {
+        let json = serde_json::to_value(meta()).unwrap();
+
+        assert_eq!(json["actor"], "dispatcher-7");
+        assert_eq!(json["client_timestamp"], "2021-03-11T17:39:23Z");
+        assert_eq!(json["channel"], "mobile");
+        assert_eq!(serde_json::from_value::<EventMeta>(json).unwrap(), meta());
+        assert_eq!(serde_json::to_string(&EventMeta::new()).unwrap(), "{}");
+    }
+
+    #[tokio::test]
+    async fn test_file_store_round_trips_typed_meta() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = FileEventStore::<Dispatch, DispatchEvent, EventMeta>::new(&path);
+        let cqrs = CQRS::new(store, vec![]);
+
+        cqrs.execute(TypedRequest, meta()).await?;
+        let events = FileEventStore::<Dispatch, DispatchEvent, EventMeta>::new(&path)
+            .read_all(0, None)
+            .await;
+        mock::remove_store(&path);
+        let events = events?;
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].meta, meta());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_test_store_round_trips_typed_meta() -> Result<(), Error> {
+        let store = TestStore::<Dispatch, DispatchEvent, EventMeta>::new(vec![]);
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        cqrs.execute(TypedRequest, meta()).await?;
+        let events = store.read_all(0, None).await?;
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].meta, meta());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_filter_matches_typed_meta_fields() -> Result<(), Error> {
+        let store = InMemoryEventStore::<Dispatch, DispatchEvent, EventMeta>::new();
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        cqrs.execute(TypedRequest, meta()).await?;
+        cqrs.execute(TypedRequest, EventMeta::new().with_tenant("globex"))
+            .await?;
+
+        let acme = store
+            .retrieve_filtered(&EventFilter::new().meta("tenant", "acme"))
+            .await?;
+        let mobile = store
+            .retrieve_filtered(&EventFilter::new().meta("channel", "mobile"))
+            .await?;
+        let missing = store
+            .retrieve_filtered(&EventFilter::new().meta("actor", "nobody"))
+            .await?;
+
+        assert_eq!(acme.len(), 1);
+        assert_eq!(acme[0].meta, meta());
+        assert_eq!(mobile.len(), 1);
+        assert!(missing.is_empty());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_store_round_trips_typed_meta() -> Result<(), Error> {
+        use cqrs_eventsourcing::SqliteEventStore;
+
+        let path = mock::temp_store_path();
+        let store = SqliteEventStore::<Dispatch, DispatchEvent, EventMeta>::new(&path)?;
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        cqrs.execute(TypedRequest, meta()).await?;
+        let events = store.read_all(0, None).await;
+        let _ = std::fs::remove_file(&path);
+        let events = events?;
+
+        assert_eq!(events[0].meta, meta());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod execution_result_test {
+    use super::*;
+    use cqrs_eventsourcing::InMemoryEventStore;
+
+    type MemoryStore = InMemoryEventStore<Dispatch, DispatchEvent>;
+
+    #[tokio::test]
+    async fn test_result_carries_generated_id_and_version() -> Result<(), Error> {
+        let store = MemoryStore::new();
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        let result = cqrs.execute(mock::request(), HashMap::new()).await?;
+        let stored = store.retrieve(&result.aggregate_id).await?;
+
+        assert!(uuid::Uuid::parse_str(&result.aggregate_id).is_ok());
+        assert_eq!(result.version, 1);
+        assert!(result.is_created());
+        assert!(result.aggregate.is_none());
+        assert_eq!(result.events.len(), 1);
+        assert_eq!(result.events[0].event_id, stored[0].event_id);
+        assert_eq!(result.events[0].position, stored[0].position);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_result_carries_aggregate_state_when_asked() -> Result<(), Error> {
+        let store = MemoryStore::new();
+        let cqrs = CQRS::new(store, vec![]).with_aggregate_state(true);
+
+        let requested = cqrs.execute(mock::request(), HashMap::new()).await?;
+        let accept = AcceptExisting(Accept {
+            id: requested.aggregate_id.clone(),
+            dispatcher: mock::DISPATCHER.to_string(),
+            _query: None,
+        });
+        let accepted = cqrs.execute(accept, HashMap::new()).await?;
+
+        assert_eq!(accepted.aggregate_id, requested.aggregate_id);
+        assert_eq!(accepted.version, 2);
+        assert!(!accepted.is_created());
+        let aggregate = accepted.aggregate.unwrap();
+        assert_eq!(aggregate.client, mock::CLIENT);
+        assert_eq!(aggregate.accepted_at.as_deref(), Some(mock::FIXEDDATE));
+
+        Ok(())
+    }
+
+    /// Counts its events twice, once in a field left out of its serialized form
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct Tally {
+        count: usize,
+        #[serde(skip)]
+        applied: usize,
+    }
+
+    impl Aggregate for Tally {
+        fn aggregate_type() -> &'static str {
+            "tally"
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+    struct Counted;
+
+    impl DomainEvent<Tally> for Counted {
+        fn apply(self, tally: &mut Tally) {
+            tally.count += 1;
+            tally.applied += 1;
+        }
+
+        fn name() -> &'static str {
+            "Counted"
+        }
+    }
+
+    #[derive(Clone)]
+    struct Count(Option<String>);
+
+    #[async_trait]
+    impl Command<Tally, Counted> for Count {
+        fn id(&self) -> Option<String> {
+            self.0.clone()
+        }
+
+        async fn handle(self, _context: &AggregateContext<Tally>) -> Result<Vec<Counted>, Error> {
+            Ok(vec![Counted])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_result_state_is_the_handled_aggregate() -> Result<(), Error> {
+        let store = InMemoryEventStore::<Tally, Counted>::new();
+        let cqrs = CQRS::new(store, vec![]).with_aggregate_state(true);
+
+        let first = cqrs.execute(Count(None), HashMap::new()).await?;
+        let second = cqrs
+            .execute(Count(Some(first.aggregate_id)), HashMap::new())
+            .await?;
+
+        let tally = second.aggregate.unwrap();
+        assert_eq!(tally.count, 2);
+        assert_eq!(tally.applied, 2);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod command_bus_test {
+    use super::*;
+    use cqrs_eventsourcing::{
+        BusMiddleware, CommandBus, CommandInfo, CommandRoutes, DispatchHandler, DispatchResult,
+        ExecutionResult, InMemoryEventStore, MetaData,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct Counter {
+        count: usize,
+    }
+
+    impl Aggregate for Counter {
+        fn aggregate_type() -> &'static str {
+            "counter"
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+    struct Incremented;
+
+    impl DomainEvent<Counter> for Incremented {
+        fn apply(self, counter: &mut Counter) {
+            counter.count += 1;
+        }
+
+        fn name() -> &'static str {
+            "Incremented"
+        }
+    }
+
+    #[derive(Clone)]
+    struct Increment {
+        id: Option<String>,
+    }
+
+    #[async_trait]
+    impl Command<Counter, Incremented> for Increment {
+        fn id(&self) -> Option<String> {
+            self.id.clone()
+        }
+
+        async fn handle(
+            self,
+            _context: &AggregateContext<Counter>,
+        ) -> Result<Vec<Incremented>, Error> {
+            Ok(vec![Incremented])
+        }
+    }
+
+    /// Increments once every holder of the barrier is handling
+    #[derive(Clone)]
+    struct Rendezvous(Arc<tokio::sync::Barrier>);
+
+    #[async_trait]
+    impl Command<Counter, Incremented> for Rendezvous {
+        fn id(&self) -> Option<String> {
+            None
+        }
+
+        async fn handle(
+            self,
+            _context: &AggregateContext<Counter>,
+        ) -> Result<Vec<Incremented>, Error> {
+            self.0.wait().await;
+            Ok(vec![Incremented])
+        }
+    }
+
+    struct Counted(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl DispatchHandler for Counted {
+        async fn handle(&self, _result: &DispatchResult) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn bus(
+        dispatches: &InMemoryEventStore<Dispatch, DispatchEvent>,
+        counters: &InMemoryEventStore<Counter, Incremented>,
+    ) -> CommandBus {
+        CommandBus::new()
+            .register(
+                CommandRoutes::new(CQRS::new(dispatches.clone(), vec![]))
+                    .command::<Request>()
+                    .command::<AcceptExisting>(),
+            )
+            .register(
+                CommandRoutes::new(CQRS::new(counters.clone(), vec![])).command::<Increment>(),
+            )
+    }
+
+    #[tokio::test]
+    async fn test_commands_are_routed_by_type() -> Result<(), Error> {
+        let dispatches = InMemoryEventStore::new();
+        let counters = InMemoryEventStore::new();
+        let bus = bus(&dispatches, &counters);
+
+        let requested = bus.dispatch(mock::request(), HashMap::new()).await?;
+        let accepted = bus
+            .dispatch(
+                AcceptExisting(Accept {
+                    id: requested.aggregate_id.clone(),
+                    dispatcher: mock::DISPATCHER.to_string(),
+                    _query: None,
+                }),
+                HashMap::new(),
+            )
+            .await?;
+        let incremented = bus.dispatch(Increment { id: None }, HashMap::new()).await?;
+
+        assert_eq!(requested.aggregate_type, "dispatch");
+        assert!(requested.created);
+        assert_eq!(accepted.aggregate_id, requested.aggregate_id);
+        assert_eq!(accepted.version, 2);
+        assert!(!accepted.created);
+        assert_eq!(incremented.aggregate_type, "counter");
+        assert_eq!(dispatches.read_all(0, None).await?.len(), 2);
+        let counted = counters.retrieve(&incremented.aggregate_id).await?;
+        assert_eq!(counted[0].event_id, incremented.event_ids[0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_result_converts_back_to_the_typed_execution() -> Result<(), Error> {
+        let bus = bus(&InMemoryEventStore::new(), &InMemoryEventStore::new());
+
+        let result = bus.dispatch(Increment { id: None }, HashMap::new()).await?;
+        let result = result
+            .into_execution::<Dispatch, DispatchEvent, MetaData>()
+            .unwrap_err();
+        let execution: ExecutionResult<Counter, Incremented> = result.into_execution().unwrap();
+
+        assert_eq!(execution.version, 1);
+        assert_eq!(execution.events[0].payload, Incremented);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unrouted_command_is_an_error() {
+        let bus = CommandBus::new().register(
+            CommandRoutes::new(CQRS::new(
+                InMemoryEventStore::<Counter, Incremented>::new(),
+                vec![],
+            ))
+            .command::<Increment>(),
+        );
+
+        let err = bus
+            .dispatch(mock::request(), HashMap::new())
+            .await
+            .unwrap_err();
+
+        assert!(bus.is_routed::<Increment>());
+        assert!(!bus.is_routed::<Request>());
+        assert!(err.extension().unwrap()["command"].ends_with("Request"));
+    }
+
+    #[tokio::test]
+    async fn test_enrichers_and_handlers_apply_to_every_aggregate() -> Result<(), Error> {
+        let dispatches = InMemoryEventStore::new();
+        let counters = InMemoryEventStore::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let bus = bus(&dispatches, &counters)
+            .with_enricher(|meta| {
+                meta.insert("source".to_string(), "web".to_string());
+            })
+            .with_enricher(|meta| {
+                meta.entry("tenant".to_string())
+                    .or_insert_with(|| "default".to_string());
+            })
+            .with_handler(Counted(Arc::clone(&calls)));
+
+        let mut meta = HashMap::new();
+        meta.insert("tenant".to_string(), "acme".to_string());
+        bus.dispatch(mock::request(), meta).await?;
+        bus.dispatch(Increment { id: None }, HashMap::new()).await?;
+
+        let requested = dispatches.read_all(0, None).await?.remove(0);
+        let incremented = counters.read_all(0, None).await?.remove(0);
+        assert_eq!(requested.meta["source"], "web");
+        assert_eq!(requested.meta["tenant"], "acme");
+        assert_eq!(incremented.meta["source"], "web");
+        assert_eq!(incremented.meta["tenant"], "default");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    /// Records the hooks it ran and refuses to handle counters
+    #[derive(Clone, Default)]
+    struct Audit(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl BusMiddleware for Audit {
+        async fn before_command(
+            &self,
+            command: &CommandInfo,
+            meta: &mut MetaData,
+        ) -> Result<(), Error> {
+            meta.insert("audited".to_string(), "yes".to_string());
+            self.record(format!("before_command {}", command.aggregate_type));
+            Ok(())
+        }
+
+        async fn before_handle(
+            &self,
+            command: &CommandInfo,
+            _aggregate_id: &str,
+            version: usize,
+            _meta: &mut MetaData,
+        ) -> Result<(), Error> {
+            self.record(format!(
+                "before_handle {} {}",
+                command.aggregate_type, version
+            ));
+            match command.aggregate_type {
+                "counter" => Err(Error::new("Audit: counters are closed", None, None)),
+                _ => Ok(()),
+            }
+        }
+
+        async fn after_append(&self, command: &CommandInfo, event_ids: &[String]) {
+            self.record(format!(
+                "after_append {} {}",
+                command.aggregate_type,
+                event_ids.len()
+            ));
+        }
+
+        async fn on_error(&self, command: &CommandInfo, error: &Error) {
+            self.record(format!(
+                "on_error {} {}",
+                command.aggregate_type,
+                error.message()
+            ));
+        }
+    }
+
+    impl Audit {
+        fn record(&self, hook: String) {
+            self.0.lock().unwrap().push(hook);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bus_middlewares_wrap_every_route() -> Result<(), Error> {
+        let dispatches = InMemoryEventStore::new();
+        let counters = InMemoryEventStore::new();
+        let audit = Audit::default();
+        let bus = bus(&dispatches, &counters).with_middleware(audit.clone());
+
+        bus.dispatch(mock::request(), HashMap::new()).await?;
+        let refused = bus.dispatch(Increment { id: None }, HashMap::new()).await;
+
+        assert_eq!(refused.unwrap_err().message(), "Audit: counters are closed");
+        assert!(counters.read_all(0, None).await?.is_empty());
+        assert_eq!(
+            dispatches.read_all(0, None).await?[0].meta["audited"],
+            "yes"
+        );
+        assert_eq!(
+            *audit.0.lock().unwrap(),
+            vec![
+                "before_command dispatch",
+                "before_handle dispatch 0",
+                "after_append dispatch 1",
+                "before_command counter",
+                "before_handle counter 0",
+                "on_error counter Audit: counters are closed",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commands_to_one_cqrs_run_concurrently() -> Result<(), Error> {
+        let bus = CommandBus::<MetaData>::new().register(
+            CommandRoutes::new(CQRS::new(InMemoryEventStore::new(), vec![]))
+                .command::<Rendezvous>(),
+        );
+        let barrier = Arc::new(tokio::sync::Barrier::new(2));
+
+        let both = futures::future::join(
+            bus.dispatch(Rendezvous(Arc::clone(&barrier)), HashMap::new()),
+            bus.dispatch(Rendezvous(barrier), HashMap::new()),
+        );
+        let (first, second) = tokio::time::timeout(std::time::Duration::from_secs(5), both)
+            .await
+            .expect("the second command waited for the first");
+        assert_ne!(first?.aggregate_id, second?.aggregate_id);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bus_is_shared_across_tasks() -> Result<(), Error> {
+        let counters = InMemoryEventStore::new();
+        let bus = Arc::new(bus(&InMemoryEventStore::new(), &counters));
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let bus = Arc::clone(&bus);
+                let command = Increment {
+                    id: Some(id.clone()),
+                };
+                tokio::spawn(async move { bus.dispatch(command, HashMap::new()).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap()?;
+        }
+
+        let versions: Vec<usize> = counters
+            .retrieve(&id)
+            .await?
+            .iter()
+            .map(|e| e.version)
+            .collect();
+        assert_eq!(versions, vec![1, 2, 3, 4]);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod middleware_test {
+    use super::*;
+    use cqrs_eventsourcing::{
+        CommandInfo, FormatedEvents, InMemoryEventStore, MetaData, Middleware,
+    };
+    use std::sync::{Arc, Mutex};
+
+    type MemoryStore = InMemoryEventStore<Dispatch, DispatchEvent>;
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Records the hooks it sees
+    struct Recording {
+        name: &'static str,
+        log: Log,
+    }
+
+    impl Recording {
+        fn record(&self, hook: &str) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{}:{}", self.name, hook));
+        }
+    }
+
+    #[async_trait]
+    impl Middleware<Dispatch, DispatchEvent> for Recording {
+        async fn before_command(
+            &self,
+            _command: &CommandInfo,
+            _meta: &mut MetaData,
+        ) -> Result<(), Error> {
+            self.record("before_command");
+            Ok(())
+        }
+
+        async fn before_handle(
+            &self,
+            _command: &CommandInfo,
+            _context: &AggregateContext<Dispatch>,
+            _meta: &mut MetaData,
+        ) -> Result<(), Error> {
+            self.record("before_handle");
+            Ok(())
+        }
+
+        async fn after_handle(
+            &self,
+            _command: &CommandInfo,
+            _context: &AggregateContext<Dispatch>,
+            _events: &mut Vec<DispatchEvent>,
+            _meta: &mut MetaData,
+        ) -> Result<(), Error> {
+            self.record("after_handle");
+            Ok(())
+        }
+
+        async fn after_append(
+            &self,
+            _command: &CommandInfo,
+            _events: &FormatedEvents<Dispatch, DispatchEvent>,
+        ) {
+            self.record("after_append");
+        }
+
+        async fn on_error(&self, _command: &CommandInfo, _error: &Error) {
+            self.record("on_error");
+        }
+    }
+
+    /// Rejects commands without an actor, stamps the handled command on the metadata
+    struct Authorize;
+
+    #[async_trait]
+    impl Middleware<Dispatch, DispatchEvent> for Authorize {
+        async fn before_command(
+            &self,
+            _command: &CommandInfo,
+            meta: &mut MetaData,
+        ) -> Result<(), Error> {
+            match meta.contains_key("actor") {
+                true => Ok(()),
+                false => Err(Error::new("Not authorized", Some("USERINPUT"), None)),
+            }
+        }
+
+        async fn after_handle(
+            &self,
+            command: &CommandInfo,
+            _context: &AggregateContext<Dispatch>,
+            events: &mut Vec<DispatchEvent>,
+            meta: &mut MetaData,
+        ) -> Result<(), Error> {
+            meta.insert("command".to_string(), command.command.to_string());
+            meta.insert("events".to_string(), events.len().to_string());
+            Ok(())
+        }
+    }
+
+    fn recording(name: &'static str, log: &Log) -> Recording {
+        Recording {
+            name,
+            log: Arc::clone(log),
+        }
+    }
+
+    fn actor() -> MetaData {
+        let mut meta = HashMap::new();
+        meta.insert("actor".to_string(), "dispatcher-7".to_string());
+        meta
+    }
+
+    #[tokio::test]
+    async fn test_hooks_wrap_in_order() -> Result<(), Error> {
+        let log = Log::default();
+        let cqrs = CQRS::new(MemoryStore::new(), vec![])
+            .with_middleware(recording("outer", &log))
+            .with_middleware(recording("inner", &log));
+
+        cqrs.execute(mock::request(), HashMap::new()).await?;
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "outer:before_command",
+                "inner:before_command",
+                "outer:before_handle",
+                "inner:before_handle",
+                "inner:after_handle",
+                "outer:after_handle",
+                "inner:after_append",
+                "outer:after_append",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_short_circuits_the_command() -> Result<(), Error> {
+        let log = Log::default();
+        let store = MemoryStore::new();
+        let cqrs = CQRS::new(store.clone(), vec![])
+            .with_middleware(recording("outer", &log))
+            .with_middleware(Authorize)
+            .with_middleware(recording("inner", &log));
+
+        let err = cqrs
+            .execute(mock::request(), HashMap::new())
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.message(), "Not authorized");
+        assert!(store.read_all(0, None).await?.is_empty());
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["outer:before_command", "inner:on_error", "outer:on_error"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_middleware_modifies_stored_meta() -> Result<(), Error> {
+        let store = MemoryStore::new();
+        let cqrs = CQRS::new(store.clone(), vec![]).with_middleware(Authorize);
+
+        let result = cqrs.execute(mock::request(), actor()).await?;
+        let stored = store.retrieve(&result.aggregate_id).await?;
+
+        assert_eq!(stored[0].meta["actor"], "dispatcher-7");
+        assert!(stored[0].meta["command"].ends_with("Request"));
+        assert_eq!(stored[0].meta["events"], "1");
+        assert_eq!(result.events[0].meta, stored[0].meta);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod idempotency_test {
+    use super::*;
+    use cqrs_eventsourcing::{
+        IdempotencyKey, IdempotencyRecord, InMemoryEventStore, SegmentedEventStore, TestStore,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    type MemoryStore = InMemoryEventStore<Dispatch, DispatchEvent>;
+
+    /// Request carrying the key of the client's HTTP request
+    #[derive(Clone)]
+    struct KeyedRequest {
+        key: String,
+        handled: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Command<Dispatch, DispatchEvent> for KeyedRequest {
+        fn id(&self) -> Option<String> {
+            None
+        }
+
+        async fn handle(
+            self,
+            _context: &AggregateContext<Dispatch>,
+        ) -> Result<Vec<DispatchEvent>, Error> {
+            self.handled.fetch_add(1, Ordering::SeqCst);
+
+            Ok(vec![DispatchEvent::Requested(Requested {
+                id: mock::DISPATCHID.to_string(),
+                client: mock::CLIENT.to_string(),
+                dispatcher: mock::DISPATCHER.to_string(),
+            })])
+        }
+
+        fn idempotency_key(&self) -> Option<String> {
+            Some(self.key.clone())
+        }
+    }
+
+    fn keyed(key: &str, handled: &Arc<AtomicUsize>) -> KeyedRequest {
+        KeyedRequest {
+            key: key.to_string(),
+            handled: Arc::clone(handled),
+        }
+    }
+
+    /// Executes the same keyed request twice, checking only the first one was handled
+    async fn assert_deduplicated<S: Store<Dispatch, DispatchEvent>>(store: S) -> Result<(), Error> {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let cqrs = CQRS::new(store.clone(), vec![]).with_aggregate_state(true);
+
+        let first = cqrs
+            .execute(keyed("req-1", &handled), HashMap::new())
+            .await?;
+        let retry = cqrs
+            .execute(keyed("req-1", &handled), HashMap::new())
+            .await?;
+
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+        assert!(!first.replayed);
+        assert!(retry.replayed);
+        assert_eq!(retry.aggregate_id, first.aggregate_id);
+        assert_eq!(retry.version, 1);
+        assert_eq!(retry.events[0].event_id, first.events[0].event_id);
+        assert_eq!(retry.aggregate.unwrap().client, mock::CLIENT);
+        assert_eq!(store.read_all(0, None).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_deduplicates() -> Result<(), Error> {
+        assert_deduplicated(MemoryStore::new()).await
+    }
+
+    #[tokio::test]
+    async fn test_file_store_deduplicates() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let result = assert_deduplicated(FileEventStore::new(&path)).await;
+        mock::remove_store(&path);
+
+        result
+    }
+
+    #[tokio::test]
+    async fn test_segmented_store_deduplicates() -> Result<(), Error> {
+        let dir = mock::temp_store_path();
+        let result = assert_deduplicated(SegmentedEventStore::new(&dir)).await;
+        let _ = std::fs::remove_dir_all(&dir);
+
+        result
+    }
+
+    #[tokio::test]
+    async fn test_test_store_deduplicates() -> Result<(), Error> {
+        assert_deduplicated(TestStore::new(vec![])).await
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_store_deduplicates() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let result = assert_deduplicated(cqrs_eventsourcing::SqliteEventStore::new(&path)?).await;
+        let _ = std::fs::remove_file(&path);
+
+        result
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn test_postgres_store_deduplicates() -> Result<(), Error> {
+        let store =
+            cqrs_eventsourcing::PostgresEventStore::connect(&mock::postgres_params()).await?;
+        let handled = Arc::new(AtomicUsize::new(0));
+        let cqrs = CQRS::new(store.clone(), vec![]);
+        let key = uuid::Uuid::new_v4().to_string();
+
+        let first = cqrs.execute(keyed(&key, &handled), HashMap::new()).await?;
+        let retry = cqrs.execute(keyed(&key, &handled), HashMap::new()).await?;
+
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+        assert!(retry.replayed);
+        assert_eq!(retry.events[0].event_id, first.events[0].event_id);
+        assert_eq!(store.remembered(&key).await?.unwrap().version, 1);
+
+        Ok(())
+    }
+
+    /// Request whose handling waits for every holder of the barrier
+    #[derive(Clone)]
+    struct Gathered(KeyedRequest, Arc<tokio::sync::Barrier>);
+
+    #[async_trait]
+    impl Command<Dispatch, DispatchEvent> for Gathered {
+        fn id(&self) -> Option<String> {
+            None
+        }
+
+        async fn handle(
+            self,
+            context: &AggregateContext<Dispatch>,
+        ) -> Result<Vec<DispatchEvent>, Error> {
+            self.1.wait().await;
+            self.0.handle(context).await
+        }
+
+        fn idempotency_key(&self) -> Option<String> {
+            self.0.idempotency_key()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_retries_append_once() -> Result<(), Error> {
+        let store = MemoryStore::new();
+        let handled = Arc::new(AtomicUsize::new(0));
+        let cqrs = CQRS::new(store.clone(), vec![]);
+        let barrier = Arc::new(tokio::sync::Barrier::new(2));
+        let retry = || Gathered(keyed("req-1", &handled), Arc::clone(&barrier));
+
+        let (first, second) = futures::future::join(
+            cqrs.execute(retry(), HashMap::new()),
+            cqrs.execute(retry(), HashMap::new()),
+        )
+        .await;
+        let (first, second) = (first?, second?);
+
+        // Both were handled before either appended, only one got its events stored
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+        assert!(first.replayed != second.replayed);
+        assert_eq!(first.events[0].event_id, second.events[0].event_id);
+        assert_eq!(store.read_all(0, None).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store_ignores_keys_without_their_events() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let handled = Arc::new(AtomicUsize::new(0));
+
+        // Left by an append that stopped between the key and its events
+        let mut record = IdempotencyRecord::new(
+            "req-1",
+            "dispatch",
+            mock::DISPATCHID,
+            0,
+            1,
+            Duration::from_secs(60),
+        );
+        record.event_id = Some(uuid::Uuid::new_v4().to_string());
+        std::fs::write(
+            format!("{}.keys", path),
+            serde_json::to_string(&record).unwrap() + "\n",
+        )
+        .unwrap();
+
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        let remembered = store.remembered("req-1").await;
+        let result = CQRS::new(store.clone(), vec![])
+            .execute(keyed("req-1", &handled), HashMap::new())
+            .await;
+        let retry = CQRS::new(store.clone(), vec![])
+            .execute(keyed("req-1", &handled), HashMap::new())
+            .await;
+        mock::remove_store(&path);
+
+        assert_eq!(remembered?, None);
+        assert!(!result?.replayed);
+        assert!(retry?.replayed);
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store_keeps_no_key_of_conflicting_appends() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let store = FileEventStore::<Dispatch, DispatchEvent>::new(&path);
+        mock::append_one(&store, mock::DISPATCHID, mock::requested(mock::DISPATCHID)).await?;
+
+        let mut context = store
+            .assemble_aggregate(Some(mock::DISPATCHID.to_string()))
+            .await?;
+        context.idempotency = Some(IdempotencyKey::new("req-1", Duration::from_secs(60)));
+        context.version = 0;
+        let conflict = store
+            .append(
+                vec![mock::requested(mock::DISPATCHID)],
+                &context,
+                HashMap::new(),
+            )
+            .await;
+        let keys = std::fs::read_to_string(format!("{}.keys", path)).unwrap_or_default();
+
+        context.version = 1;
+        let retry = store
+            .append(
+                vec![mock::requested(mock::DISPATCHID)],
+                &context,
+                HashMap::new(),
+            )
+            .await;
+        mock::remove_store(&path);
+
+        assert!(conflict.unwrap_err().is_conflict());
+        assert_eq!(keys, "");
+        assert_eq!(retry?[0].version, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_store_keys_outlive_the_store() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let handled = Arc::new(AtomicUsize::new(0));
+
+        let first = FileEventStore::create_cqrs(&path, vec![])
+            .execute(keyed("req-1", &handled), HashMap::new())
+            .await?;
+        let retry = FileEventStore::create_cqrs(&path, vec![])
+            .execute(keyed("req-1", &handled), HashMap::new())
+            .await?;
+        let keys = std::fs::read_to_string(format!("{}.keys", path)).unwrap();
+        mock::remove_store(&path);
+
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+        assert!(retry.replayed);
+        assert_eq!(retry.aggregate_id, first.aggregate_id);
+        assert_eq!(keys.lines().count(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_other_keys_are_handled() -> Result<(), Error> {
+        let store = MemoryStore::new();
+        let handled = Arc::new(AtomicUsize::new(0));
+        let cqrs = CQRS::new(store.clone(), vec![]);
+
+        let first = cqrs
+            .execute(keyed("req-1", &handled), HashMap::new())
+            .await?;
+        let second = cqrs
+            .execute(keyed("req-2", &handled), HashMap::new())
+            .await?;
+
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+        assert!(!second.replayed);
+        assert_ne!(second.aggregate_id, first.aggregate_id);
+        assert_eq!(store.read_all(0, None).await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keys_expire() -> Result<(), Error> {
+        let path = mock::temp_store_path();
+        let handled = Arc::new(AtomicUsize::new(0));
+        let cqrs = FileEventStore::create_cqrs(&path, vec![])
+            .with_idempotency_window(Duration::from_millis(20));
+
+        cqrs.execute(keyed("req-1", &handled), HashMap::new())
+            .await?;
+        tokio::time::delay_for(Duration::from_millis(40)).await;
+        let retry = cqrs
+            .execute(keyed("req-1", &handled), HashMap::new())
+            .await?;
+        let keys = std::fs::read_to_string(format!("{}.keys", path)).unwrap();
+        mock::remove_store(&path);
+
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+        assert!(!retry.replayed);
+        // The expired key was dropped when the new one was written
+        assert_eq!(keys.lines().count(), 1);
 
         Ok(())
     }
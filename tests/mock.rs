@@ -1,5 +1,85 @@
+#![allow(clippy::redundant_static_lifetimes)]
+
+use cqrs_eventsourcing::{FormatedResult, Store};
+use std::collections::HashMap;
+
+use super::{Dispatch, DispatchEvent, Request, Requested};
+
 pub const FIXEDDATE: &'static str = "Thu, 11 Mar 2021 17:39:23 +0000";
 pub const DISPATCHID: &'static str = "ba2a54a4-367d-450c-8ef3-9b678d41ff1a";
 pub const CLIENT: &'static str = "ba2a54a4-367d-450c-8ef3-9b677d41ff1c";
 pub const DISPATCHER: &'static str = "89532cea-5dc6-4056-a2ae-1a049e9c09e";
 pub const FILESTORE: &'static str = "tests/eventstore.store";
+
+/// Unique store path in the system temp directory
+pub fn temp_store_path() -> String {
+    std::env::temp_dir()
+        .join(format!("cqrs-{}.store", uuid::Uuid::new_v4()))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Remove a store file along with its side files
+#[allow(dead_code)]
+pub fn remove_store(path: &str) {
+    for suffix in ["", ".index", ".quarantine", ".keys"].iter() {
+        let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+    }
+}
+
+/// Connection string of the local Postgres used by the `postgres` feature tests
+#[allow(dead_code)]
+pub fn postgres_params() -> String {
+    std::env::var("CQRS_POSTGRES_PARAMS")
+        .unwrap_or_else(|_| "host=localhost user=postgres".to_string())
+}
+
+/// `Requested` event of dispatch `id` by `CLIENT`
+pub fn requested(id: &str) -> DispatchEvent {
+    requested_by(id, CLIENT)
+}
+
+/// `Request` command of `CLIENT` for `DISPATCHER`
+pub fn request() -> Request {
+    Request {
+        client: CLIENT.to_string(),
+        dispatcher: DISPATCHER.to_string(),
+    }
+}
+
+/// `Requested` event of dispatch `id` by `client`
+pub fn requested_by(id: &str, client: &str) -> DispatchEvent {
+    DispatchEvent::Requested(Requested {
+        id: id.to_string(),
+        client: client.to_string(),
+        dispatcher: DISPATCHER.to_string(),
+    })
+}
+
+/// Append `events` to dispatch `id` at its current version
+pub async fn append<S: Store<Dispatch, DispatchEvent>>(
+    store: &S,
+    id: &str,
+    events: Vec<DispatchEvent>,
+) -> FormatedResult<Dispatch, DispatchEvent> {
+    let context = store.assemble_aggregate(Some(id.to_string())).await?;
+    store.append(events, &context, HashMap::new()).await
+}
+
+/// Append `event` to dispatch `id` at its current version
+pub async fn append_one<S: Store<Dispatch, DispatchEvent>>(
+    store: &S,
+    id: &str,
+    event: DispatchEvent,
+) -> FormatedResult<Dispatch, DispatchEvent> {
+    append(store, id, vec![event]).await
+}
+
+/// Append one batch of `count` `requested` events to dispatch `id`
+pub async fn append_requested<S: Store<Dispatch, DispatchEvent>>(
+    store: &S,
+    id: &str,
+    count: usize,
+) -> FormatedResult<Dispatch, DispatchEvent> {
+    append(store, id, (0..count).map(|_| requested(id)).collect()).await
+}